@@ -1,34 +1,57 @@
-use std::{fmt::Display, sync::mpsc::Receiver};
+use std::{collections::BTreeMap, fmt::Display, ops::Range};
 
-use crossterm::event::KeyCode;
+use chrono::{DateTime, Local, TimeDelta};
+
+use crossterm::event::{KeyCode, KeyModifiers};
 use ratatui::{
     layout::{Constraint, Flex, Layout, Rect},
-    style::{Style, Stylize},
-    text::Line,
-    widgets::{Block, Clear, List, ListDirection},
+    style::{Color, Style, Stylize},
+    text::{Line, Span},
+    widgets::{Bar, BarChart, BarGroup, Block, Clear, List, ListDirection, Paragraph, Row, Table},
     Frame,
 };
+use snafu::{prelude::*, Whatever};
 
 use crate::{
-    mode::ApplicationMode, portthread::{RxTx, SerialStateMessage}, serialtypes::control_char_to_string, DisplayMode
+    checksum::{Checksum, CrcParams, RxValidation, CHECKSUMS},
+    clipboard::{copy_to_clipboard, CopyFormat, COPY_FORMATS},
+    decode::{decode, Endianness, ENDIANNESSES, MAX_DECODE_LEN, MAX_VALUE_LEN, Q_FRACTION_BITS},
+    export::{default_export_path, export_to_file, ExportFormat, EXPORT_FORMATS},
+    filter::{HistoryFilter, TimeRange, DIRECTION_FILTERS},
+    highlight::{HighlightRule, Highlighter},
+    timing::{
+        reference_timestamp, DeltaReference, DeltaSettings, DeltaUnit, TimeBase,
+        TimestampSettings, TimestampTarget, DELTA_REFERENCES, DELTA_UNITS,
+    },
+    history::History, mode::ApplicationMode, portthread::{HistoryEntry, RxTx, SerialStateMessage},
+    protocol::{FieldNode, Protocol},
+    search::{search, SearchKind, SearchMatch, SearchQuery, SearchResult, SEARCH_KINDS},
+    serialtypes::{display_separator, format_byte_for_display},
+    stats::{StatsMetric, Summary, TimingStats, STATS_METRICS},
+    template::{find_template, FieldValue, StructTemplate}, DisplayMode
 };
 
 
-const TIME_INFORMATION_MODES: [TimeInformationMode; 2] = [
+const NO_SELECTION: &str = "No selection, select bytes with shift and the arrow keys";
+
+/// Maximum number of entries searched for a data entry, when moving the cursor.
+const MAX_CURSOR_SEARCH: usize = 1024;
+
+const TIME_INFORMATION_MODES: [TimeInformationMode; 4] = [
     TimeInformationMode::None,
-    // TimeInformationMode::Delta,
+    TimeInformationMode::Delta,
     TimeInformationMode::Absolute,
-    // TimeInformationMode::AbsoluteWithDelta,
+    TimeInformationMode::AbsoluteWithDelta,
 ];
 
 #[derive(Debug, Default, PartialEq, Clone, Copy)]
 pub enum TimeInformationMode
 {
     None,
-    // Delta,
+    Delta,
     #[default]
     Absolute,
-    // AbsoluteWithDelta,
+    AbsoluteWithDelta,
 }
 
 impl Display for TimeInformationMode {
@@ -36,62 +59,368 @@ impl Display for TimeInformationMode {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             TimeInformationMode::None => write!(f, "None"),
-            // TimeInformationMode::Delta => write!(f, "Delta"),
+            TimeInformationMode::Delta => write!(f, "Delta"),
             TimeInformationMode::Absolute => write!(f, "Absolute"),
-            // TimeInformationMode::AbsoluteWithDelta => write!(f, "Absolute with Delta"),
+            TimeInformationMode::AbsoluteWithDelta => write!(f, "Absolute with Delta"),
         }
     }
 }
 
-#[derive(Debug, Default, PartialEq)]
-pub enum Endianness {
-    Big,
-    #[default]
-    Little,
+/// What the text entered in the prompt is used for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PromptPurpose {
+    Search,
+    FilterPattern,
+    FilterTimeRange,
+    FilterLength,
+    HighlightPattern,
+    Annotation,
+    CustomCrc,
 }
-impl Display for Endianness {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Endianness::Big => write!(f, "Big"),
-            Endianness::Little => write!(f, "Little"),
+
+#[derive(Debug)]
+struct Prompt {
+    purpose: PromptPurpose,
+    text: String,
+}
+
+/// The bytes starting at the analyzer cursor, possibly spanning several entries.
+#[derive(Debug)]
+struct CursorRun {
+    /// The entry with the cursor and the offset of the cursor in it.
+    entry: HistoryEntry,
+    pos: usize,
+    bytes: Vec<u8>,
+    /// History index of every entry the bytes were taken from, and the range taken.
+    segments: Vec<(usize, Range<usize>)>,
+}
+
+impl CursorRun {
+    /// The range of the bytes of the entry with the given index, that are decoded as
+    /// numeric values, and whether the range starts with the cursor.
+    fn value_range(&self, index: usize) -> Option<(Range<usize>, bool)> {
+        let mut remaining = MAX_VALUE_LEN;
+        for (i, range) in &self.segments {
+            let len = range.len().min(remaining);
+            if *i == index {
+                return Some((range.start..range.start + len, *i == self.segments[0].0));
+            }
+            remaining -= len;
+            if remaining == 0 {
+                return None;
+            }
         }
+        None
     }
 }
 
+/// The template decoding the bytes in the template panel.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TemplateChoice {
+    /// The first template, whose header the entry at the cursor starts with,
+    /// decoded from the start of the entry.
+    Auto,
+    /// The template with the given index, decoded from the cursor.
+    Fixed(usize),
+}
+
+/// A checksum, its value and, if the trailing bytes match it, whether they are big endian.
+type ChecksumRow = (Checksum, u64, Option<bool>);
+
 #[derive(Debug)]
 pub struct AnalyzerMode {
     active: bool,
-    display_history: Vec<SerialStateMessage>,
+    display_history: History,
     scroll_offset: u32,
-    analyzer_cursor_line: usize,
+    /// History index of the entry with the analyzer cursor. Until the cursor is
+    /// moved, it is on the bottom most data entry shown.
+    analyzer_cursor_entry: Option<usize>,
     analyzer_cursor_pos: usize,
+    /// History index and offset of the byte, where the selection started. The
+    /// selection reaches from there to the cursor.
+    selection_anchor: Option<(usize, usize)>,
+    show_copy_popup: bool,
+    show_checksum_panel: bool,
+    /// The user defined CRC shown in the checksum panel.
+    custom_crc: Option<CrcParams>,
+    rx_validation: RxValidation,
+    /// Number of received frames validated and, of these, with a bad checksum.
+    checked_frames: u64,
+    bad_frames: u64,
+    /// Frame layouts from the .klemme file.
+    templates: Vec<StructTemplate>,
+    /// The template shown in the template panel, while it is open.
+    template_choice: Option<TemplateChoice>,
+    /// Protocol description, the frames are labelled with and decoded by.
+    protocol: Option<Protocol>,
+    show_message_panel: bool,
+    /// Selected bytes to be put into the send buffer of the interactive mode.
+    pending_send: Option<Vec<u8>>,
+    /// Number of entries that fit into the history, used to keep the cursor visible.
+    visible_rows: usize,
     analyzer_endianness: Endianness,
+    /// Number of fraction bits of the decoded fixed point values.
+    analyzer_fraction_bits: u32,
     active_display_mode: DisplayMode,
     active_time_display_mode: TimeInformationMode,
+    delta_reference: DeltaReference,
+    delta_unit: DeltaUnit,
+    delta_settings_edited: bool,
+    /// Reference timestamps of the shown entries. Looking them up may read older
+    /// entries from disk, so it is only done when an entry is scrolled into view
+    /// or the delta reference changes, not on every render.
+    reference_cache: BTreeMap<usize, ReferenceTimes>,
+    timestamps: TimestampSettings,
+    show_export_popup: bool,
+    status_message: String,
+    /// The text prompt, while it is open.
+    prompt: Option<Prompt>,
+    search_kind: SearchKind,
+    search_text: String,
+    search_result: Option<SearchResult>,
+    show_filter_popup: bool,
+    filter: HistoryFilter,
+    /// Indices of the entries that pass the filter, while a filter is active.
+    filtered_indices: Vec<usize>,
+    show_highlight_popup: bool,
+    highlight_rules: Vec<HighlightRule>,
+    highlighter: Highlighter,
+    /// Index of the rule selected in the rules editor.
+    selected_highlight_rule: usize,
+    highlight_rules_edited: bool,
+    /// Indices of all annotations in the history.
+    bookmarks: Vec<usize>,
+    /// Indices of all start markers in the history.
+    session_starts: Vec<usize>,
+    /// Annotation entered in the prompt, that has not been added to the history yet.
+    pending_annotation: Option<String>,
+    /// Timing statistics over the entries that pass the filter.
+    stats: TimingStats,
+    /// The metric shown in the statistics panel, while it is open.
+    stats_metric: Option<StatsMetric>,
+    stats_summary: Option<Summary>,
+    /// The selection the summary was computed over, if any.
+    stats_selection: Option<((usize, usize), (usize, usize))>,
+}
+
+/// The timestamps the time information of an entry is computed against.
+#[derive(Debug, Clone, Copy)]
+struct ReferenceTimes {
+    /// Of the reference entry of the delta.
+    delta: Option<DateTime<Local>>,
+    /// Of the first entry of the session.
+    session_start: Option<DateTime<Local>>,
 }
 
 impl AnalyzerMode {
-    pub fn new() -> AnalyzerMode {
+    pub fn new(history: History) -> AnalyzerMode {
+        let mut bookmarks = vec![];
+        let mut session_starts = vec![];
+        let mut stats = TimingStats::default();
+        for (i, msg) in history.iter() {
+            stats.add(&msg);
+            match msg {
+                SerialStateMessage::Annotation(_) => bookmarks.push(i),
+                SerialStateMessage::Started => session_starts.push(i),
+                _ => {}
+            }
+        }
         AnalyzerMode {
             active: false,
-            display_history: vec![],
+            display_history: history,
             scroll_offset: 0,
-            analyzer_cursor_line: 0,
+            analyzer_cursor_entry: None,
             analyzer_cursor_pos: 0,
+            selection_anchor: None,
+            show_copy_popup: false,
+            show_checksum_panel: false,
+            custom_crc: None,
+            rx_validation: RxValidation::default(),
+            checked_frames: 0,
+            bad_frames: 0,
+            templates: vec![],
+            template_choice: None,
+            protocol: None,
+            show_message_panel: false,
+            pending_send: None,
+            visible_rows: 0,
             analyzer_endianness: Endianness::Little,
+            analyzer_fraction_bits: Q_FRACTION_BITS[0],
             active_display_mode: DisplayMode::Hex,
             active_time_display_mode: TimeInformationMode::None,
+            delta_reference: DeltaReference::default(),
+            delta_unit: DeltaUnit::default(),
+            delta_settings_edited: false,
+            reference_cache: BTreeMap::new(),
+            timestamps: TimestampSettings::default(),
+            show_export_popup: false,
+            status_message: String::new(),
+            prompt: None,
+            search_kind: SearchKind::default(),
+            search_text: String::new(),
+            search_result: None,
+            show_filter_popup: false,
+            filter: HistoryFilter::default(),
+            filtered_indices: vec![],
+            show_highlight_popup: false,
+            highlight_rules: vec![],
+            highlighter: Highlighter::default(),
+            selected_highlight_rule: 0,
+            highlight_rules_edited: false,
+            bookmarks,
+            session_starts,
+            pending_annotation: None,
+            stats,
+            stats_metric: None,
+            stats_summary: None,
+            stats_selection: None,
         }
     }
 
     pub(crate) fn add_to_history(&mut self, arg: &str) {
         let msg = SerialStateMessage::ErrorEvent(arg.to_string());
+        self.add_message(msg);
+    }
+
+    pub(crate) fn history(&self) -> &History {
+        &self.display_history
+    }
+
+    pub(crate) fn set_status_message(&mut self, message: String) {
+        self.status_message = message;
+    }
+
+    /// Sets the height of the history area, including its border.
+    pub(crate) fn set_history_height(&mut self, height: u16) {
+        self.visible_rows = height.saturating_sub(2) as usize;
+        self.update_reference_cache(height as usize);
+    }
+
+    pub(crate) fn set_delta_settings(&mut self, delta: DeltaSettings) {
+        self.delta_reference = delta.reference;
+        self.delta_unit = delta.unit;
+        self.reference_cache.clear();
+    }
+
+    /// Returns the delta settings, if they were changed since the last call.
+    pub(crate) fn take_edited_delta_settings(&mut self) -> Option<DeltaSettings> {
+        if !std::mem::take(&mut self.delta_settings_edited) {
+            return None;
+        }
+        Some(DeltaSettings {
+            reference: self.delta_reference,
+            unit: self.delta_unit,
+        })
+    }
+
+    /// Returns true, if a popup is open that consumes all key events.
+    pub(crate) fn has_popup(&self) -> bool {
+        self.show_export_popup
+            || self.show_copy_popup
+            || self.show_filter_popup
+            || self.show_highlight_popup
+            || self.prompt.is_some()
+    }
+
+    pub(crate) fn set_highlight_rules(&mut self, rules: Vec<HighlightRule>) {
+        self.highlighter = Highlighter::new(&rules);
+        self.highlight_rules = rules;
+        self.selected_highlight_rule = 0;
+    }
+
+    /// Sets the templates of the template panel. Templates with errors are left out
+    /// and the first error is shown.
+    pub(crate) fn set_templates(&mut self, templates: Vec<StructTemplate>) {
+        self.templates = templates
+            .into_iter()
+            .filter(|template| match template.check() {
+                Ok(()) => true,
+                Err(e) => {
+                    self.status_message = e.to_string();
+                    false
+                }
+            })
+            .collect();
+        self.template_choice = None;
+    }
+
+    pub(crate) fn set_protocol(&mut self, protocol: Option<Protocol>) {
+        self.protocol = protocol;
+    }
+
+    /// Returns the highlight rules, if they were changed in the rules editor
+    /// since the last call.
+    pub(crate) fn take_edited_highlight_rules(&mut self) -> Option<Vec<HighlightRule>> {
+        if !std::mem::take(&mut self.highlight_rules_edited) {
+            return None;
+        }
+        Some(self.highlight_rules.clone())
+    }
+
+    /// Opens the prompt for an annotation. It is shown in every mode.
+    pub(crate) fn open_annotation_prompt(&mut self) {
+        self.open_prompt(PromptPurpose::Annotation);
+    }
+
+    /// Returns the text of an annotation entered since the last call.
+    pub(crate) fn take_annotation(&mut self) -> Option<String> {
+        self.pending_annotation.take()
+    }
+
+    /// Returns the bytes of the selection, if sending them was requested since the
+    /// last call.
+    pub(crate) fn take_selection_to_send(&mut self) -> Option<Vec<u8>> {
+        self.pending_send.take()
+    }
+
+    /// Appends a message received from the port thread to the history.
+    pub(crate) fn add_message(&mut self, msg: SerialStateMessage) {
+        match msg {
+            SerialStateMessage::Annotation(_) => self.bookmarks.push(self.display_history.len()),
+            SerialStateMessage::Started => self.session_starts.push(self.display_history.len()),
+            _ => {}
+        }
+        if !self.filter.is_active() || self.filter.matches(&msg) {
+            self.stats.add(&msg);
+        }
+        self.count_checksum(&msg);
+        if self.filter.is_active() && self.filter.matches(&msg) {
+            self.filtered_indices.push(self.display_history.len());
+        }
         self.display_history.push(msg);
     }
 }
 
 impl ApplicationMode for AnalyzerMode {
     fn handle_key_event(&mut self, key_event: crossterm::event::KeyEvent) {
+        if self.show_export_popup {
+            self.handle_export_popup_key(key_event.code);
+            return;
+        }
+        if self.show_copy_popup {
+            self.handle_copy_popup_key(key_event.code);
+            return;
+        }
+        if self.show_filter_popup {
+            self.handle_filter_popup_key(key_event.code);
+            return;
+        }
+        if self.show_highlight_popup {
+            self.handle_highlight_popup_key(key_event.code);
+            return;
+        }
+        if self.prompt.is_some() {
+            self.handle_prompt_key(key_event.code);
+            return;
+        }
+
+        if matches!(
+            key_event.code,
+            KeyCode::Left | KeyCode::Right | KeyCode::Up | KeyCode::Down
+        ) {
+            self.update_selection(key_event.modifiers.contains(KeyModifiers::SHIFT));
+        }
+
         match key_event.code {
             KeyCode::Left => self.cursor_left(),
             KeyCode::Right => self.cursor_right(),
@@ -100,6 +429,24 @@ impl ApplicationMode for AnalyzerMode {
             KeyCode::PageUp => self.scroll_up(),
             KeyCode::PageDown => self.scroll_down(),
             KeyCode::Char('e') => self.rotate_analyzer_endianness(),
+            KeyCode::Char('q') => self.rotate_fraction_bits(),
+            KeyCode::Char('x') => self.show_export_popup = true,
+            KeyCode::Char('y') => self.open_copy_popup(),
+            KeyCode::Char('p') => self.send_selection(),
+            KeyCode::Char('/') => self.open_prompt(PromptPurpose::Search),
+            KeyCode::Char('n') => self.goto_next_match(),
+            KeyCode::Char('N') => self.goto_previous_match(),
+            KeyCode::Char('f') => self.show_filter_popup = true,
+            KeyCode::Char('h') => self.show_highlight_popup = true,
+            KeyCode::Char('d') => self.rotate_delta_reference(),
+            KeyCode::Char('u') => self.rotate_delta_unit(),
+            KeyCode::Char('t') => self.rotate_stats_metric(),
+            KeyCode::Char('c') => self.show_checksum_panel = !self.show_checksum_panel,
+            KeyCode::Char('C') => self.open_prompt(PromptPurpose::CustomCrc),
+            KeyCode::Char('a') => self.rotate_template(),
+            KeyCode::Char('m') => self.toggle_message_panel(),
+            KeyCode::Char('b') => self.goto_next_bookmark(),
+            KeyCode::Char('B') => self.goto_previous_bookmark(),
             _ => {}
         }
     }
@@ -114,12 +461,24 @@ impl ApplicationMode for AnalyzerMode {
         } else {
             ratatui::style::Color::Gray
         };
-        //self.update_history_with_incoming_data();
-        let mut analyzer_data: Vec<u8> = Vec::new();
+        let cursor_run = self.cursor_run(MAX_DECODE_LEN);
         let num_rows = area.height as usize;
-        let items = self.build_list_items(&mut analyzer_data, num_rows);
+        let items = self.build_list_items(cursor_run.as_ref(), num_rows);
+        let mut title = "History".to_string();
+        if matches!(
+            self.active_time_display_mode,
+            TimeInformationMode::Delta | TimeInformationMode::AbsoluteWithDelta
+        ) {
+            title += &format!(" (delta to {} in {})", self.delta_reference, self.delta_unit);
+        }
+        if self.filter.is_active() {
+            title += &format!(" [{}]", self.filter.description());
+        }
+        if !self.status_message.is_empty() {
+            title += &format!(" - {}", self.status_message);
+        }
         let list = List::new(items)
-            .block(Block::bordered().title("History"))
+            .block(Block::bordered().title(title))
             .style(Style::new().fg(highlight_color))
             .highlight_style(Style::new().fg(ratatui::style::Color::Red))
             .highlight_symbol(">>")
@@ -127,7 +486,16 @@ impl ApplicationMode for AnalyzerMode {
             .direction(ListDirection::BottomToTop);
 
         buf.render_widget(list, area);
-        self.render_analyzer(area, buf, analyzer_data);
+        self.render_analyzer(area, buf, cursor_run);
+        self.render_export_popup(area, buf);
+        self.render_copy_popup(area, buf);
+        self.render_filter_popup(area, buf);
+        self.render_highlight_popup(area, buf);
+        self.render_stats_panel(area, buf);
+        self.render_checksum_panel(area, buf);
+        self.render_template_panel(area, buf);
+        self.render_message_panel(area, buf);
+        self.render_prompt(area, buf);
     }
 }
 
@@ -137,12 +505,29 @@ impl AnalyzerMode {
         self.scroll_offset = self.scroll_offset.saturating_add(1);
     }
 
+    /// Moves the analyzer cursor to the previous (older) data entry. The offset
+    /// is kept, as far as the entry is long enough.
     pub fn scroll_analyzer_cursor_up(&mut self) {
-        self.analyzer_cursor_line += 1;
+        let Some((index, pos)) = self.cursor() else {
+            return;
+        };
+        let previous = self
+            .view_position(index)
+            .checked_sub(1)
+            .and_then(|position| self.data_entry_from(position, true));
+        if let Some((previous, len)) = previous {
+            self.move_cursor_to(previous, pos.min(len - 1));
+        }
     }
 
+    /// Moves the analyzer cursor to the next (newer) data entry.
     pub fn scroll_analyzer_cursor_down(&mut self) {
-        self.analyzer_cursor_line = self.analyzer_cursor_line.saturating_sub(1);
+        let Some((index, pos)) = self.cursor() else {
+            return;
+        };
+        if let Some((next, len)) = self.data_entry_from(self.view_position(index) + 1, false) {
+            self.move_cursor_to(next, pos.min(len - 1));
+        }
     }
 
     /// Scroll down in the display history, moving the top line up by one.
@@ -150,26 +535,160 @@ impl AnalyzerMode {
         self.scroll_offset = self.scroll_offset.saturating_sub(1);
     }
 
-    /// Move the analyzer cursor one character to the left. This will move the highlighted
-    /// character in the analyzer window to the left by one position. If the cursor is
-    /// already at the left edge of the window, this function has no effect.
+    /// Move the analyzer cursor one byte to the left. At the start of an entry, the
+    /// cursor moves to the last byte of the previous data entry. At the start of the
+    /// oldest entry, this function has no effect.
     pub fn cursor_left(&mut self) {
-        self.analyzer_cursor_pos = self.analyzer_cursor_pos.saturating_sub(1);
+        let Some((index, pos)) = self.cursor() else {
+            return;
+        };
+        if pos > 0 {
+            self.move_cursor_to(index, pos - 1);
+            return;
+        }
+        let previous = self
+            .view_position(index)
+            .checked_sub(1)
+            .and_then(|position| self.data_entry_from(position, true));
+        if let Some((previous, len)) = previous {
+            self.move_cursor_to(previous, len - 1);
+        }
     }
 
-    /// Move the analyzer cursor one character to the right. This will move the highlighted
-    /// character in the analyzer window to the right by one position. If the cursor is
-    /// already at the right edge of the window, this function has no effect.
+    /// Move the analyzer cursor one byte to the right. At the end of an entry, the
+    /// cursor moves to the first byte of the next data entry. At the end of the
+    /// newest entry, this function has no effect.
     pub fn cursor_right(&mut self) {
-        self.analyzer_cursor_pos += 1;
+        let Some((index, pos)) = self.cursor() else {
+            return;
+        };
+        if pos + 1 < self.data_len(index) {
+            self.move_cursor_to(index, pos + 1);
+            return;
+        }
+        if let Some((next, _)) = self.data_entry_from(self.view_position(index) + 1, false) {
+            self.move_cursor_to(next, 0);
+        }
     }
 
-    pub fn rotate_analyzer_endianness(&mut self) {
-        if self.analyzer_endianness == Endianness::Big {
-            self.analyzer_endianness = Endianness::Little
+    fn data_len(&self, index: usize) -> usize {
+        match self.display_history.get(index) {
+            Some(SerialStateMessage::DataEvent(entry)) => entry.data.len(),
+            _ => 0,
+        }
+    }
+
+    /// Returns the history index and length of the first non empty data entry
+    /// shown at `position` or, searching towards older or newer entries, after it.
+    fn data_entry_from(&self, position: usize, older: bool) -> Option<(usize, usize)> {
+        let positions: Box<dyn Iterator<Item = usize>> = if older {
+            Box::new((0..=position.min(self.view_len().checked_sub(1)?)).rev())
         } else {
-            self.analyzer_endianness = Endianness::Big
+            Box::new(position..self.view_len())
+        };
+        positions
+            .take(MAX_CURSOR_SEARCH)
+            .map(|position| self.view_index(position))
+            .find_map(|index| {
+                let len = self.data_len(index);
+                (len > 0).then_some((index, len))
+            })
+    }
+
+    /// The history index of the entry with the cursor and the offset of the cursor
+    /// within the entry. The offset is clamped to the length of the entry. If the
+    /// entry was scrolled out of view, the cursor is back on the bottom most entry.
+    fn cursor(&self) -> Option<(usize, usize)> {
+        let shown = |index: usize| {
+            self.passes_filter(index) && self.is_visible(self.view_position(index))
+        };
+        let (index, len) = match self.analyzer_cursor_entry {
+            Some(index) if shown(index) && self.data_len(index) > 0 => {
+                (index, self.data_len(index))
+            }
+            _ => {
+                let bottom = self
+                    .view_len()
+                    .checked_sub(self.scroll_offset as usize + 1)?;
+                self.data_entry_from(bottom, true)?
+            }
+        };
+        Some((index, self.analyzer_cursor_pos.min(len - 1)))
+    }
+
+    fn is_visible(&self, position: usize) -> bool {
+        let Some(bottom) = self.view_len().checked_sub(self.scroll_offset as usize + 1) else {
+            return false;
+        };
+        // before the first render, the height of the history is unknown:
+        position <= bottom && (self.visible_rows == 0 || position + self.visible_rows > bottom)
+    }
+
+    /// Moves the cursor and scrolls the history, so that the cursor stays visible.
+    fn move_cursor_to(&mut self, index: usize, pos: usize) {
+        self.analyzer_cursor_entry = Some(index);
+        self.analyzer_cursor_pos = pos;
+
+        let position = self.view_position(index);
+        let rows = self.visible_rows.max(1);
+        let bottom = self.view_len().saturating_sub(self.scroll_offset as usize + 1);
+        if position > bottom {
+            self.scroll_offset = (self.view_len() - 1 - position) as u32;
+        } else if position + rows <= bottom {
+            self.scroll_offset = (self.view_len() - position - rows) as u32;
+        }
+    }
+
+    /// Returns the bytes starting at the cursor, continued by the following data
+    /// entries of the same direction, up to `max_len` bytes. Together with the
+    /// bytes, the entries and ranges they were taken from are returned.
+    fn cursor_run(&self, max_len: usize) -> Option<CursorRun> {
+        if !self.active {
+            return None;
         }
+        let (index, pos) = self.cursor()?;
+        let Some(SerialStateMessage::DataEvent(entry)) = self.display_history.get(index) else {
+            return None;
+        };
+        let mut run = CursorRun {
+            entry,
+            pos,
+            bytes: vec![],
+            segments: vec![],
+        };
+        // the run continues across entries in the history, even if they are hidden
+        // by the filter, as they belong to the same stream:
+        let mut next = index;
+        while run.bytes.len() < max_len {
+            let Some(SerialStateMessage::DataEvent(entry)) = self.display_history.get(next) else {
+                break;
+            };
+            if entry.rx_tx != run.entry.rx_tx {
+                break;
+            }
+            let start = if next == index { pos } else { 0 };
+            let end = entry.data.len().min(start + max_len - run.bytes.len());
+            run.bytes.extend_from_slice(&entry.data[start..end]);
+            run.segments.push((next, start..end));
+            next += 1;
+        }
+        Some(run)
+    }
+
+    pub fn rotate_analyzer_endianness(&mut self) {
+        let position = ENDIANNESSES
+            .iter()
+            .position(|e| *e == self.analyzer_endianness)
+            .unwrap_or(0);
+        self.analyzer_endianness = ENDIANNESSES[(position + 1) % ENDIANNESSES.len()];
+    }
+
+    pub fn rotate_fraction_bits(&mut self) {
+        let position = Q_FRACTION_BITS
+            .iter()
+            .position(|bits| *bits == self.analyzer_fraction_bits)
+            .unwrap_or(0);
+        self.analyzer_fraction_bits = Q_FRACTION_BITS[(position + 1) % Q_FRACTION_BITS.len()];
     }
 
     pub fn rotate_time_display_mode(&mut self) {
@@ -182,263 +701,1557 @@ impl AnalyzerMode {
         self.active_time_display_mode = TIME_INFORMATION_MODES[selectd_index];
     }
 
+    pub fn rotate_delta_reference(&mut self) {
+        let mut selected_index = DELTA_REFERENCES
+            .iter()
+            .position(|x| *x == self.delta_reference)
+            .unwrap_or(0);
+        selected_index += 1;
+        selected_index %= DELTA_REFERENCES.len();
+        self.delta_reference = DELTA_REFERENCES[selected_index];
+        self.delta_settings_edited = true;
+        self.reference_cache.clear();
+    }
+
+    pub fn rotate_delta_unit(&mut self) {
+        let mut selected_index = DELTA_UNITS
+            .iter()
+            .position(|x| *x == self.delta_unit)
+            .unwrap_or(0);
+        selected_index += 1;
+        selected_index %= DELTA_UNITS.len();
+        self.delta_unit = DELTA_UNITS[selected_index];
+        self.delta_settings_edited = true;
+    }
+
     pub fn update_data(
         &mut self,
-        data_source: &Receiver<SerialStateMessage>,
         display_mode: DisplayMode,
+        timestamps: &TimestampSettings,
+        rx_validation: RxValidation,
     ) {
-        self.update_history_with_incoming_data(data_source);
         self.active_display_mode = display_mode;
+        if *timestamps != self.timestamps {
+            self.timestamps = timestamps.clone();
+        }
+        if rx_validation != self.rx_validation {
+            self.set_rx_validation(rx_validation);
+        }
+        // the statistics of a selection do not change with new data:
+        let changed = self.stats.take_changed() && self.stats_selection.is_none();
+        if changed || (self.stats_metric.is_some() && self.selection() != self.stats_selection) {
+            self.update_stats_summary();
+        }
     }
 
-    pub fn clear_history(&mut self) {
-        self.display_history.clear();
-        self.display_history.shrink_to_fit();
+    /// Validates the checksum of a received frame and counts it.
+    fn count_checksum(&mut self, msg: &SerialStateMessage) {
+        if let Some(valid) = validate_rx(&self.rx_validation, msg) {
+            self.checked_frames += 1;
+            if !valid {
+                self.bad_frames += 1;
+            }
+        }
     }
 
-    fn popup_area(area: Rect, percent_x: u16, percent_y: u16) -> Rect {
-        let vertical = Layout::vertical([Constraint::Percentage(percent_y)]).flex(Flex::Start);
-        let horizontal = Layout::horizontal([Constraint::Percentage(percent_x)]).flex(Flex::End);
-        let [area] = vertical.areas(area);
-        let [area] = horizontal.areas(area);
-        area
+    /// Changes the validation of received frames and recounts the bad frames of
+    /// the whole history.
+    fn set_rx_validation(&mut self, rx_validation: RxValidation) {
+        self.rx_validation = rx_validation;
+        (self.checked_frames, self.bad_frames) = self
+            .display_history
+            .iter()
+            .filter_map(|(_, msg)| validate_rx(&rx_validation, &msg))
+            .fold((0, 0), |(checked, bad), valid| (checked + 1, bad + u64::from(!valid)));
+        if self.filter.bad_checksum.is_some() {
+            self.filter.bad_checksum = rx_validation.checksum.map(|_| rx_validation);
+            self.apply_filter();
+        }
     }
 
-    fn update_history_with_incoming_data(&mut self, data_source: &Receiver<SerialStateMessage>) {
-        // copy state events to display history:
-        while let Ok(x) = data_source.try_recv() {
-            self.display_history.push(x);
-        }
+    /// The number of received frames validated and, of these, with a bad checksum.
+    /// `None` if received frames are not validated.
+    pub(crate) fn checksum_counts(&self) -> Option<(u64, u64)> {
+        self.rx_validation
+            .checksum
+            .map(|_| (self.checked_frames, self.bad_frames))
     }
 
-    fn format_data_for_display(&self, data: &[u8]) -> String {
-        match self.active_display_mode {
-            DisplayMode::Hex => data.iter().map(|x| format!("{:02X} ", x)).collect(),
-            DisplayMode::Ascii => {
-                // replace control bytes by their name:
-                let data = data
-                    .iter()
-                    .map(|x| {
-                        if !(*x as char).is_control() {
-                            return format!("{}", (*x) as char);
-                        }
-                        let chr = control_char_to_string(*x);
-                        format!("{}", chr)
-                    })
-                    .collect::<Vec<String>>()
-                    .join("");
-                data
-            }
-            DisplayMode::Decimal => data
-                .iter()
-                .map(|x| x.to_string())
-                .collect::<Vec<String>>()
-                .join(" "),
-            DisplayMode::MixedHex => {
-                // all bytes, that are printable characters are printed as such, otherwise hex
-                data.iter()
-                    .map(|x| {
-                        if (*x as char).is_ascii() && !(*x as char).is_control() {
-                            return format!("{}", (*x) as char);
-                        }
-                        format!("{:02X}", *x)
-                    })
-                    .collect::<Vec<String>>()
-                    .join(" ")
-            }
-            DisplayMode::MixedDec => data
-                .iter()
-                .map(|x| {
-                    if (*x as char).is_ascii() && !(*x as char).is_control() {
-                        return format!("{}", (*x) as char);
-                    }
-                    format!("{}", *x)
-                })
-                .collect::<Vec<String>>()
-                .join(" "),
+    fn toggle_bad_checksum_filter(&mut self) {
+        if self.filter.bad_checksum.is_some() {
+            self.filter.bad_checksum = None;
+        } else if self.rx_validation.checksum.is_some() {
+            self.filter.bad_checksum = Some(self.rx_validation);
+        } else {
+            self.status_message = "Checksum validation is off, see the settings".to_string();
+            return;
         }
+        self.apply_filter();
     }
 
-    fn select_time_format_string(&self) -> String {
-        match self.active_time_display_mode {
-            TimeInformationMode::Absolute => "%H:%M:%S%.3f ".to_string(),
-            // TimeInformationMode::Delta => "%H:%M:%S%.3f ".to_string(),
-            TimeInformationMode::None => "".to_string(),
-            // TimeInformationMode::AbsoluteWithDelta => format!("%H:%M:%S%.3f[+{} ms] ", delta_to_prev),
+    /// Sets the user defined CRC of the checksum panel. An empty input removes it.
+    fn set_custom_crc(&mut self, text: &str) {
+        self.show_checksum_panel = true;
+        if text.trim().is_empty() {
+            self.custom_crc = None;
+            return;
+        }
+        match CrcParams::parse(text) {
+            Ok(params) => self.custom_crc = Some(params),
+            Err(e) => self.status_message = e.to_string(),
         }
-        .to_string()
     }
 
-    fn build_list_items(&self, analyzer_data: &mut Vec<u8>, max_num_rows: usize) -> Vec<Line<'_>> {
-        let mut line_index = 0;
-
-        let items: Vec<Line> = self
-            .display_history
+    /// The checksums of the selection, or of the entry at the cursor without the
+    /// trailing checksum, and whether the trailing bytes match them.
+    fn checksum_rows(&self) -> Option<(String, Vec<ChecksumRow>)> {
+        let checksums = CHECKSUMS
             .iter()
-            .rev()
-            .skip(self.scroll_offset as usize)
-            .take(max_num_rows)
-            .map(|x| {
-                let result = match x {
-                    SerialStateMessage::DataEvent(x) => {
-                        let bytes = self.format_data_for_display(&x.data);
-
-                        let mut pre_cursor = String::from(bytes.clone());
-                        let mut cursor = String::from("");
-                        let mut post_cursor = String::from("");
-                        let mut highlight_string = String::from("");
-                        let mut cursor_color = ratatui::style::Color::Black;
-                        let mut post_cursor_color = ratatui::style::Color::Black;
-
-                        if self.active_display_mode == DisplayMode::Hex
-                            && line_index == self.analyzer_cursor_line
-                            && self.active
-                        {
-                            // the cursor pos is always a multiple of 3:
-                            let pos = self.analyzer_cursor_pos * 3;
-                            if pos <= bytes.len() - 3 {
-                                pre_cursor = String::from(&bytes[0..pos]);
-                                cursor = String::from(&bytes[pos..pos + 2]);
-                                let highlight_len = if (bytes.len() - pos - 2) > 24 {
-                                    24
-                                } else {
-                                    bytes.len() - pos - 2
-                                };
-                                highlight_string =
-                                    String::from(&bytes[pos + 2..pos + 2 + highlight_len]);
-                                post_cursor = String::from(&bytes[pos + 2 + highlight_len..]);
-                            }
-                            cursor_color = ratatui::style::Color::Blue;
-                            post_cursor_color = ratatui::style::Color::DarkGray;
-                            *analyzer_data = x.data.to_vec();
-                        }
+            .copied()
+            .chain(self.custom_crc.map(Checksum::Custom));
 
-                        let time_string = x.timestamp.format(&self.select_time_format_string()).to_string();
+        if let Some(selected) = self.selected_bytes() {
+            // the bytes following the selection in its last entry:
+            let (_, end) = self.selection()?;
+            let trailer = match self.display_history.get(end.0) {
+                Some(SerialStateMessage::DataEvent(entry)) => entry.data[end.1 + 1..].to_vec(),
+                _ => vec![],
+            };
+            let rows = checksums
+                .map(|c| (c, c.compute(&selected), c.matches(&selected, &trailer)))
+                .collect();
+            return Some((format!("{} selected bytes", selected.len()), rows));
+        }
 
-                        let ln = Line::from(vec![
-                            time_string.fg(ratatui::style::Color::Gray),
-                            x.rx_tx.to_string().fg(if x.rx_tx == RxTx::Tx {
-                                ratatui::style::Color::Green
-                            } else {
-                                ratatui::style::Color::Red
-                            }),
-                            format!(":").fg(ratatui::style::Color::Gray),
-                            format!("{}", pre_cursor).fg(ratatui::style::Color::Gray),
-                            format!("{}", cursor)
-                                .fg(ratatui::style::Color::Gray)
-                                .bg(cursor_color),
-                            format!("{}", highlight_string)
-                                .fg(ratatui::style::Color::Gray)
-                                .bg(post_cursor_color),
-                            format!("{}", post_cursor).fg(ratatui::style::Color::Gray),
-                        ]);
-                        line_index += 1;
-                        ln
-                    }
-                    SerialStateMessage::ErrorEvent(x) => Line::raw(x),
-                    SerialStateMessage::Started => {
-                        Line::from(vec!["--- Started ---".fg(ratatui::style::Color::Green)])
-                    }
-                    SerialStateMessage::Stopped => {
-                        Line::from(vec!["--- Stopped ---".fg(ratatui::style::Color::LightRed)])
-                    }
+        let (index, _) = self.cursor()?;
+        let Some(SerialStateMessage::DataEvent(entry)) = self.display_history.get(index) else {
+            return None;
+        };
+        let data = &entry.data;
+        let rows = checksums
+            .filter(|c| c.len() < data.len())
+            .map(|c| {
+                let (payload, trailer) = data.split_at(data.len() - c.len());
+                (c, c.compute(payload), c.matches(payload, trailer))
+            })
+            .collect();
+        Some(("entry at the cursor".to_string(), rows))
+    }
+
+    fn render_checksum_panel(&self, area: Rect, buf: &mut Frame<'_>) {
+        if !self.active || !self.show_checksum_panel {
+            return;
+        }
+        let Some((title, rows)) = self.checksum_rows() else {
+            return;
+        };
+
+        let lines: Vec<Line> = rows
+            .iter()
+            .map(|(checksum, value, matched)| {
+                let value = format!("0x{:0width$X}", value, width = checksum.len() * 2);
+                let matched = match matched {
+                    Some(_) if checksum.len() == 1 => "match".fg(Color::LightGreen),
+                    Some(true) => "match (BE)".fg(Color::LightGreen),
+                    Some(false) => "match (LE)".fg(Color::LightGreen),
+                    None => "".into(),
                 };
-                return result;
+                Line::from(vec![
+                    format!("{:<16}{:<20}", checksum.to_string(), value).fg(Color::Gray),
+                    matched,
+                ])
             })
             .collect();
-        items
+
+        let [_, area] = Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)])
+            .areas(area);
+        let [_, area] = Layout::vertical([Constraint::Min(0), Constraint::Length(lines.len() as u16 + 2)])
+            .areas(area);
+        let block = Block::bordered().title(Line::from(vec![
+            format!("Checksums of the {} ", title).fg(Color::Gray),
+            "C".fg(Color::Red),
+            " custom CRC".fg(Color::Gray),
+        ]));
+        buf.render_widget(Clear, area);
+        buf.render_widget(Paragraph::new(lines).block(block), area);
     }
 
-    /// Renders the analyzer window. This window will appear if the display mode is hex
-    /// and the mode is analyzer. The window will contain the byte, u16, i16, u32, i32, f32, u64, i64, and f64
-    /// values of the byte at the cursor position.
-    fn render_analyzer(&self, area: Rect, buf: &mut Frame<'_>, analyzer_data: Vec<u8>) {
-        if !self.active {
+    /// Opens the template panel with the template matching the entry at the cursor,
+    /// then switches through all templates. After the last template the panel is closed.
+    fn rotate_template(&mut self) {
+        if self.templates.is_empty() {
+            self.status_message = "No templates defined in the .klemme file".to_string();
             return;
         }
+        self.template_choice = match self.template_choice {
+            None => Some(TemplateChoice::Auto),
+            Some(TemplateChoice::Auto) => Some(TemplateChoice::Fixed(0)),
+            Some(TemplateChoice::Fixed(i)) if i + 1 < self.templates.len() => {
+                Some(TemplateChoice::Fixed(i + 1))
+            }
+            Some(TemplateChoice::Fixed(_)) => None,
+        };
+        self.show_message_panel = false;
+    }
 
-        if self.active_display_mode != DisplayMode::Hex {
+    /// Shows or hides the fields of the message at the cursor. The template panel
+    /// takes the same place, so it is closed.
+    fn toggle_message_panel(&mut self) {
+        if self.protocol.is_none() {
+            self.status_message = "No protocol file loaded, see --protocol".to_string();
             return;
         }
+        self.show_message_panel = !self.show_message_panel;
+        self.template_choice = None;
+    }
 
-        if self.analyzer_cursor_pos >= analyzer_data.len() {
+    fn render_message_panel(&self, area: Rect, buf: &mut Frame<'_>) {
+        if !self.active || !self.show_message_panel {
             return;
         }
+        let Some(protocol) = &self.protocol else {
+            return;
+        };
+        let Some((index, _)) = self.cursor() else {
+            return;
+        };
+        let Some(SerialStateMessage::DataEvent(entry)) = self.display_history.get(index) else {
+            return;
+        };
+
+        let (title, rows) = match protocol.decode(&entry.data, &entry.rx_tx) {
+            Some(frame) => {
+                let mut rows = vec![];
+                field_tree(&frame.fields, "", &mut rows);
+                let truncated = if frame.truncated { ", truncated" } else { "" };
+                (format!("{}: {}{}", protocol.name, frame.message, truncated), rows)
+            }
+            None => (format!("{}: unknown message", protocol.name), vec![]),
+        };
+        let name_width = rows.iter().map(|(name, _, _)| name.chars().count()).max().unwrap_or(0).max(5);
+        let height = rows.len() as u16 + 3;
+        let rows: Vec<Row> = rows
+            .into_iter()
+            .map(|(name, bytes, value)| Row::new(vec![name, bytes, value]))
+            .collect();
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Length(name_width as u16),
+                Constraint::Length(7),
+                Constraint::Fill(1),
+            ],
+        )
+        .header(Row::new(vec!["Field", "Bytes", "Value"]).fg(Color::White))
+        .style(Style::new().fg(Color::Gray));
 
-        let mut items: Vec<String> = vec![];
-        // Use the cursor position to obtain the analyzer data: 1 byte, 2 byte, 4 bytes
-        let one_byte = analyzer_data[self.analyzer_cursor_pos];
-        items.push(format!("binary (MSB first): {:08b}", one_byte));
-        items.push(format!("u8: {}", one_byte));
+        let [_, area] = Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)])
+            .areas(area);
+        let [area, _] = Layout::vertical([Constraint::Length(height), Constraint::Min(0)]).areas(area);
+        let block = Block::bordered().title(Line::from(vec![
+            format!("Message {} ", title).fg(Color::Gray),
+            "m".fg(Color::Red),
+            " close".fg(Color::Gray),
+        ]));
+        buf.render_widget(Clear, area);
+        buf.render_widget(table.block(block), area);
+    }
 
-        if (self.analyzer_cursor_pos as i32) <= (analyzer_data.len() as i32 - 2) {
-            let two_bytes =
-                analyzer_data[self.analyzer_cursor_pos..=self.analyzer_cursor_pos + 1].to_vec();
-            let two_as_u16: u16;
-            let two_as_i16: i16;
-            if self.analyzer_endianness == Endianness::Big {
-                two_as_u16 = u16::from_be_bytes(two_bytes.clone().try_into().unwrap());
-                two_as_i16 = i16::from_be_bytes(two_bytes.clone().try_into().unwrap());
-            } else {
-                two_as_u16 = u16::from_le_bytes(two_bytes.clone().try_into().unwrap());
-                two_as_i16 = i16::from_le_bytes(two_bytes.clone().try_into().unwrap());
+    /// The fields decoded by the chosen template and the title of the panel.
+    fn template_rows(&self) -> Option<(String, Vec<FieldValue>)> {
+        match self.template_choice? {
+            TemplateChoice::Auto => {
+                let (index, _) = self.cursor()?;
+                let Some(SerialStateMessage::DataEvent(entry)) = self.display_history.get(index) else {
+                    return None;
+                };
+                match find_template(&self.templates, &entry.data) {
+                    Some(template) => Some((
+                        format!("{} (header match)", template.name),
+                        template.decode(&entry.data),
+                    )),
+                    None => Some(("no header matches the entry".to_string(), vec![])),
+                }
+            }
+            TemplateChoice::Fixed(i) => {
+                let template = self.templates.get(i)?;
+                let run = self.cursor_run(template.len())?;
+                Some((format!("{} at the cursor", template.name), template.decode(&run.bytes)))
             }
-            items.push(format!("u16: {}", two_as_u16));
-            items.push(format!("i16: {}", two_as_i16));
         }
+    }
 
-        if (self.analyzer_cursor_pos as i32) <= (analyzer_data.len() as i32 - 4) {
-            let four_bytes =
-                analyzer_data[self.analyzer_cursor_pos..=self.analyzer_cursor_pos + 3].to_vec();
-            let four_as_u32: u32;
-            let four_as_i32: i32;
-            let four_as_f32: f32;
+    fn render_template_panel(&self, area: Rect, buf: &mut Frame<'_>) {
+        if !self.active {
+            return;
+        }
+        let Some((title, fields)) = self.template_rows() else {
+            return;
+        };
 
-            if self.analyzer_endianness == Endianness::Big {
-                four_as_u32 = u32::from_be_bytes(four_bytes.clone().try_into().unwrap());
-                four_as_i32 = i32::from_be_bytes(four_bytes.clone().try_into().unwrap());
-                four_as_f32 = f32::from_be_bytes(four_bytes.clone().try_into().unwrap());
-            } else {
-                four_as_u32 = u32::from_le_bytes(four_bytes.clone().try_into().unwrap());
-                four_as_i32 = i32::from_le_bytes(four_bytes.clone().try_into().unwrap());
-                four_as_f32 = f32::from_le_bytes(four_bytes.clone().try_into().unwrap());
+        let rows: Vec<Row> = fields
+            .iter()
+            .map(|field| {
+                Row::new(vec![
+                    field.name.clone(),
+                    field.position.clone(),
+                    field.raw.clone(),
+                    field.value.clone(),
+                ])
+            })
+            .collect();
+        let name_width = fields.iter().map(|f| f.name.chars().count()).max().unwrap_or(0).max(5);
+        let raw_width = fields.iter().map(|f| f.raw.len()).max().unwrap_or(0).clamp(3, 12);
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Length(name_width as u16),
+                Constraint::Length(6),
+                Constraint::Length(raw_width as u16),
+                Constraint::Fill(1),
+            ],
+        )
+        .header(Row::new(vec!["Field", "Bytes", "Raw", "Value"]).fg(Color::White))
+        .style(Style::new().fg(Color::Gray));
+
+        let [_, area] = Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)])
+            .areas(area);
+        let [area, _] = Layout::vertical([Constraint::Length(fields.len() as u16 + 3), Constraint::Min(0)])
+            .areas(area);
+        let block = Block::bordered().title(Line::from(vec![
+            format!("Template {} ", title).fg(Color::Gray),
+            "a".fg(Color::Red),
+            " next".fg(Color::Gray),
+        ]));
+        buf.render_widget(Clear, area);
+        buf.render_widget(table.block(block), area);
+    }
+
+    /// Opens the statistics panel or switches to the next metric, after the
+    /// last metric the panel is closed.
+    fn rotate_stats_metric(&mut self) {
+        self.stats_metric = match self.stats_metric {
+            None => Some(STATS_METRICS[0]),
+            Some(metric) => {
+                let selected_index = STATS_METRICS.iter().position(|x| *x == metric).unwrap_or(0);
+                STATS_METRICS.get(selected_index + 1).copied()
             }
+        };
+        self.update_stats_summary();
+    }
+
+    /// Sorting all values is expensive, so it is only done while the panel is open.
+    /// While there is a selection, only the selected entries are taken into account.
+    fn update_stats_summary(&mut self) {
+        self.stats_selection = self.stats_metric.and(self.selection());
+        self.stats_summary = self.stats_metric.and_then(|metric| match self.stats_selection {
+            Some((start, end)) => self.range_stats(start.0, end.0).summarize(metric),
+            None => self.stats.summarize(metric),
+        });
+    }
 
-            items.push(format!("u32: {}", four_as_u32));
-            items.push(format!("i32: {}", four_as_i32));
-            items.push(format!("f32: {}", four_as_f32));
+    /// Timing statistics over the shown entries from `first` to `last`.
+    fn range_stats(&self, first: usize, last: usize) -> TimingStats {
+        let mut stats = TimingStats::default();
+        for position in self.view_position(first)..self.view_position(last + 1) {
+            if let Some(msg) = self.display_history.get(self.view_index(position)) {
+                stats.add(&msg);
+            }
         }
+        stats
+    }
 
-        if (self.analyzer_cursor_pos as i32) <= (analyzer_data.len() as i32 - 8) {
-            let eight_bytes =
-                analyzer_data[self.analyzer_cursor_pos..=self.analyzer_cursor_pos + 7].to_vec();
-            let eight_as_u64 = u64::from_le_bytes(eight_bytes.clone().try_into().unwrap());
-            let eight_as_i64 = i64::from_le_bytes(eight_bytes.clone().try_into().unwrap());
-            let eight_as_f64 = f64::from_le_bytes(eight_bytes.clone().try_into().unwrap());
-            items.push(format!("u64: {}", eight_as_u64));
-            items.push(format!("i64: {}", eight_as_i64));
-            items.push(format!("f64: {}", eight_as_f64));
+    fn handle_export_popup_key(&mut self, key: KeyCode) {
+        self.show_export_popup = false;
+        if let KeyCode::Char(c) = key {
+            if let Some(format) = EXPORT_FORMATS.iter().find(|f| f.key() == c) {
+                self.export_history(*format);
+            }
         }
+    }
 
-        let headline = Line::from(vec![
-            "Analyzer,".fg(ratatui::style::Color::Gray),
-            format!(" {} ", self.analyzer_endianness).fg(ratatui::style::Color::Gray),
-            "e".fg(ratatui::style::Color::Red),
-            "ndian".fg(ratatui::style::Color::Gray),
-        ]);
+    /// Exports the whole history to a file in the CWD.
+    fn export_history(&mut self, format: ExportFormat) {
+        let path = default_export_path(format);
+        self.status_message = match export_to_file(
+            &path,
+            self.exported_messages(),
+            format,
+            &self.timestamps,
+        ) {
+            Ok(_) => format!("Exported to {}", path.display()),
+            Err(e) => e.to_string(),
+        };
+    }
 
-        let list = List::new(items)
-            .block(Block::bordered().title(headline))
-            .style(Style::new().fg(ratatui::style::Color::Gray))
-            .highlight_style(Style::new().fg(ratatui::style::Color::Red))
-            .highlight_symbol(">>")
-            .repeat_highlight_symbol(true)
-            .direction(ListDirection::TopToBottom);
+    /// The entries shown with the current filter or, if there is a selection,
+    /// the selected part of them.
+    fn exported_messages(&self) -> impl Iterator<Item = SerialStateMessage> + '_ {
+        let positions = match self.selection() {
+            Some((start, end)) => self.view_position(start.0)..self.view_position(end.0 + 1),
+            None => 0..self.view_len(),
+        };
+        positions.filter_map(|position| {
+            let index = self.view_index(position);
+            let mut msg = self.display_history.get(index)?;
+            if let SerialStateMessage::DataEvent(entry) = &mut msg {
+                if let Some(range) = self.selected_range(index, entry.data.len()) {
+                    entry.data = entry.data[range].to_vec();
+                }
+            }
+            Some(msg)
+        })
+    }
 
-        //let block = Block::bordered().title("Analyzer");
-        let area = Self::popup_area(area, 40, 40);
-        buf.render_widget(Clear, area); //this clears out the background
-                                        //buf.render_widget(block, area);
-        buf.render_widget(list, area);
+    fn render_export_popup(&self, area: Rect, buf: &mut Frame<'_>) {
+        if !self.active || !self.show_export_popup {
+            return;
+        }
+
+        let items: Vec<Line> = EXPORT_FORMATS
+            .iter()
+            .map(|f| {
+                Line::from(vec![
+                    f.key().to_string().fg(ratatui::style::Color::Red),
+                    format!(" - {}", f).fg(ratatui::style::Color::Gray),
+                ])
+            })
+            .collect();
+
+        let list = List::new(items)
+            .block(Block::bordered().title("Export history"))
+            .style(Style::new().fg(ratatui::style::Color::Gray));
+
+        let area = Self::popup_area(area, 30, 40);
+        buf.render_widget(Clear, area);
+        buf.render_widget(list, area);
+    }
+
+    /// Starts a selection at the cursor, if the cursor is moved with shift held,
+    /// and ends it, if the cursor is moved without.
+    fn update_selection(&mut self, extend: bool) {
+        if !extend {
+            self.selection_anchor = None;
+        } else if self.selection_anchor.is_none() {
+            self.selection_anchor = self.cursor();
+        }
+    }
+
+    /// The first and the last selected byte, as history index and offset.
+    fn selection(&self) -> Option<((usize, usize), (usize, usize))> {
+        let anchor = self.selection_anchor?;
+        let cursor = self.cursor()?;
+        Some((anchor.min(cursor), anchor.max(cursor)))
+    }
+
+    /// The selected range of the bytes of the entry with the given index.
+    fn selected_range(&self, index: usize, len: usize) -> Option<Range<usize>> {
+        let (start, end) = self.selection()?;
+        if index < start.0 || index > end.0 {
+            return None;
+        }
+        let first = if index == start.0 { start.1 } else { 0 };
+        let last = if index == end.0 { end.1 + 1 } else { len };
+        Some(first.min(len)..last.min(len))
+    }
+
+    /// The selected bytes of all data entries, that are shown.
+    fn selected_bytes(&self) -> Option<Vec<u8>> {
+        let (start, end) = self.selection()?;
+        let mut bytes = vec![];
+        for index in (start.0..=end.0).filter(|index| self.passes_filter(*index)) {
+            if let Some(SerialStateMessage::DataEvent(entry)) = self.display_history.get(index) {
+                if let Some(range) = self.selected_range(index, entry.data.len()) {
+                    bytes.extend_from_slice(&entry.data[range]);
+                }
+            }
+        }
+        Some(bytes)
+    }
+
+    fn open_copy_popup(&mut self) {
+        if self.selection().is_none() {
+            self.status_message = NO_SELECTION.to_string();
+            return;
+        }
+        self.show_copy_popup = true;
+    }
+
+    fn handle_copy_popup_key(&mut self, key: KeyCode) {
+        self.show_copy_popup = false;
+        if let KeyCode::Char(c) = key {
+            if let Some(format) = COPY_FORMATS.iter().find(|f| f.key() == c) {
+                self.copy_selection(*format);
+            }
+        }
+    }
+
+    /// Copies the selection to the system clipboard in the given format.
+    fn copy_selection(&mut self, format: CopyFormat) {
+        let Some(bytes) = self.selected_bytes() else {
+            return;
+        };
+        self.status_message = match copy_to_clipboard(&format.format(&bytes)) {
+            Ok(_) => format!("Copied {} bytes as {}", bytes.len(), format),
+            Err(e) => e.to_string(),
+        };
+    }
+
+    /// Requests the selection to be put into the send buffer, see `take_selection_to_send`.
+    fn send_selection(&mut self) {
+        let Some(bytes) = self.selected_bytes() else {
+            self.status_message = NO_SELECTION.to_string();
+            return;
+        };
+        self.status_message = format!("{} bytes put into the send buffer", bytes.len());
+        self.pending_send = Some(bytes);
+    }
+
+    fn render_copy_popup(&self, area: Rect, buf: &mut Frame<'_>) {
+        if !self.active || !self.show_copy_popup {
+            return;
+        }
+
+        let items: Vec<Line> = COPY_FORMATS
+            .iter()
+            .map(|f| {
+                Line::from(vec![
+                    f.key().to_string().fg(ratatui::style::Color::Red),
+                    format!(" - {}", f).fg(ratatui::style::Color::Gray),
+                ])
+            })
+            .collect();
+
+        let list = List::new(items)
+            .block(Block::bordered().title("Copy selection"))
+            .style(Style::new().fg(ratatui::style::Color::Gray));
+
+        let area = Self::popup_area(area, 30, 40);
+        buf.render_widget(Clear, area);
+        buf.render_widget(list, area);
+    }
+
+    pub fn clear_history(&mut self) {
+        self.display_history.clear();
+        self.analyzer_cursor_entry = None;
+        self.analyzer_cursor_pos = 0;
+        self.selection_anchor = None;
+        self.filtered_indices.clear();
+        self.bookmarks.clear();
+        self.session_starts.clear();
+        self.reference_cache.clear();
+        self.search_result = None;
+        self.checked_frames = 0;
+        self.bad_frames = 0;
+        self.stats = TimingStats::default();
+        self.update_stats_summary();
+    }
+
+    /// Index of the entry shown at the bottom of the history.
+    fn bottom_index(&self) -> Option<usize> {
+        let position = self
+            .view_len()
+            .checked_sub(self.scroll_offset as usize + 1)?;
+        Some(self.view_index(position))
+    }
+
+    /// Jumps to the next annotation below the bottom of the history.
+    fn goto_next_bookmark(&mut self) {
+        let bottom = self.bottom_index();
+        let next = self
+            .bookmarks
+            .iter()
+            .position(|b| bottom.is_none_or(|bottom| *b > bottom));
+        self.jump_to_bookmark(next);
+    }
+
+    /// Jumps to the previous annotation above the bottom of the history.
+    fn goto_previous_bookmark(&mut self) {
+        let bottom = self.bottom_index();
+        let previous = self
+            .bookmarks
+            .iter()
+            .rposition(|b| bottom.is_none_or(|bottom| *b < bottom));
+        self.jump_to_bookmark(previous);
+    }
+
+    /// Scrolls the bookmark with the given number to the bottom of the history.
+    fn jump_to_bookmark(&mut self, bookmark: Option<usize>) {
+        let Some(number) = bookmark else {
+            self.status_message = "No more bookmarks".to_string();
+            return;
+        };
+        let entry = self.bookmarks[number];
+        self.scroll_offset = self
+            .view_len()
+            .saturating_sub(self.view_position(entry) + 1) as u32;
+        let text = match self.display_history.get(entry) {
+            Some(SerialStateMessage::Annotation(note)) => note.text,
+            _ => String::new(),
+        };
+        self.status_message = format!(
+            "Bookmark {}/{}: {}",
+            number + 1,
+            self.bookmarks.len(),
+            text
+        );
+    }
+
+    /// Number of entries shown, i.e. the entries that pass the filter.
+    fn view_len(&self) -> usize {
+        if self.filter.is_active() {
+            self.filtered_indices.len()
+        } else {
+            self.display_history.len()
+        }
+    }
+
+    /// Index in the history of the entry at the given position of the view.
+    fn view_index(&self, position: usize) -> usize {
+        if self.filter.is_active() {
+            self.filtered_indices[position]
+        } else {
+            position
+        }
+    }
+
+    /// Position in the view of the given entry. Entries hidden by the filter
+    /// map to the position of the next shown entry.
+    fn view_position(&self, index: usize) -> usize {
+        if self.filter.is_active() {
+            self.filtered_indices.partition_point(|i| *i < index)
+        } else {
+            index
+        }
+    }
+
+    /// Whether the entry with the given history index is shown in the view.
+    fn passes_filter(&self, index: usize) -> bool {
+        !self.filter.is_active() || self.filtered_indices.binary_search(&index).is_ok()
+    }
+
+    /// Recomputes the entries that pass the filter. The history itself is not
+    /// modified, so clearing the filter shows all entries again.
+    fn apply_filter(&mut self) {
+        self.filtered_indices.clear();
+        self.stats = TimingStats::default();
+        for (i, msg) in self.display_history.iter() {
+            if !self.filter.matches(&msg) {
+                continue;
+            }
+            self.stats.add(&msg);
+            if self.filter.is_active() {
+                self.filtered_indices.push(i);
+            }
+        }
+        self.update_stats_summary();
+        self.scroll_offset = 0;
+        self.analyzer_cursor_entry = None;
+        self.selection_anchor = None;
+    }
+
+    fn handle_filter_popup_key(&mut self, key: KeyCode) {
+        match key {
+            KeyCode::Char('d') => {
+                let mut selected_index = DIRECTION_FILTERS
+                    .iter()
+                    .position(|x| *x == self.filter.direction)
+                    .unwrap_or(0);
+                selected_index += 1;
+                selected_index %= DIRECTION_FILTERS.len();
+                self.filter.direction = DIRECTION_FILTERS[selected_index].clone();
+                self.apply_filter();
+            }
+            KeyCode::Char('m') => {
+                self.filter.markers_only = !self.filter.markers_only;
+                self.apply_filter();
+            }
+            KeyCode::Char('b') => self.toggle_bad_checksum_filter(),
+            KeyCode::Char('c') => {
+                self.filter = HistoryFilter::default();
+                self.apply_filter();
+            }
+            KeyCode::Char('p') => self.open_prompt(PromptPurpose::FilterPattern),
+            KeyCode::Char('t') => self.open_prompt(PromptPurpose::FilterTimeRange),
+            KeyCode::Char('l') => self.open_prompt(PromptPurpose::FilterLength),
+            _ => self.show_filter_popup = false,
+        }
+    }
+
+    fn handle_highlight_popup_key(&mut self, key: KeyCode) {
+        let selected = self.selected_highlight_rule;
+        match key {
+            KeyCode::Up => self.selected_highlight_rule = selected.saturating_sub(1),
+            KeyCode::Down => {
+                self.selected_highlight_rule =
+                    (selected + 1).min(self.highlight_rules.len().saturating_sub(1))
+            }
+            KeyCode::Char('a') => self.open_prompt(PromptPurpose::HighlightPattern),
+            KeyCode::Char('d') if selected < self.highlight_rules.len() => {
+                self.highlight_rules.remove(selected);
+                self.selected_highlight_rule = selected.saturating_sub(1);
+                self.highlight_rules_changed();
+            }
+            KeyCode::Char(c @ ('f' | 'b' | 'm' | 'l')) => {
+                let Some(rule) = self.highlight_rules.get_mut(selected) else {
+                    return;
+                };
+                match c {
+                    'f' => rule.rotate_fg(),
+                    'b' => rule.rotate_bg(),
+                    'm' => rule.rotate_modifier(),
+                    _ => rule.whole_line = !rule.whole_line,
+                }
+                self.highlight_rules_changed();
+            }
+            _ => self.show_highlight_popup = false,
+        }
+    }
+
+    fn highlight_rules_changed(&mut self) {
+        self.highlighter = Highlighter::new(&self.highlight_rules);
+        self.highlight_rules_edited = true;
+    }
+
+    fn open_prompt(&mut self, purpose: PromptPurpose) {
+        self.show_filter_popup = false;
+        self.show_highlight_popup = false;
+        self.prompt = Some(Prompt {
+            purpose,
+            text: String::new(),
+        });
+    }
+
+    fn handle_prompt_key(&mut self, key: KeyCode) {
+        let Some(prompt) = self.prompt.as_mut() else {
+            return;
+        };
+        match key {
+            KeyCode::Char(c) => prompt.text.push(c),
+            KeyCode::Backspace => {
+                prompt.text.pop();
+            }
+            KeyCode::Tab => self.rotate_search_kind(),
+            KeyCode::Enter => {
+                if let Some(prompt) = self.prompt.take() {
+                    self.apply_prompt(prompt);
+                }
+            }
+            KeyCode::Esc => self.prompt = None,
+            _ => {}
+        }
+    }
+
+    fn apply_prompt(&mut self, prompt: Prompt) {
+        let text = prompt.text;
+        if prompt.purpose == PromptPurpose::Search {
+            self.run_search(text);
+            return;
+        }
+        if prompt.purpose == PromptPurpose::HighlightPattern {
+            self.add_highlight_rule(text);
+            return;
+        }
+        if prompt.purpose == PromptPurpose::Annotation {
+            if !text.trim().is_empty() {
+                self.pending_annotation = Some(text);
+            }
+            return;
+        }
+        if prompt.purpose == PromptPurpose::CustomCrc {
+            self.set_custom_crc(&text);
+            return;
+        }
+
+        match self.filter_from_prompt(prompt.purpose, text) {
+            Ok(filter) => {
+                self.filter = filter;
+                self.apply_filter();
+            }
+            Err(e) => self.status_message = e.to_string(),
+        }
+    }
+
+    /// Adds a rule highlighting the given pattern and returns to the rules editor.
+    fn add_highlight_rule(&mut self, text: String) {
+        self.show_highlight_popup = true;
+        if let Err(e) = SearchQuery::parse(self.search_kind, &text) {
+            self.status_message = e.to_string();
+            return;
+        }
+        self.highlight_rules
+            .push(HighlightRule::new(self.search_kind, text));
+        self.selected_highlight_rule = self.highlight_rules.len() - 1;
+        self.highlight_rules_changed();
+    }
+
+    /// Returns the current filter, with the criterion the prompt was opened for
+    /// replaced by the input. An empty input removes the criterion.
+    fn filter_from_prompt(
+        &self,
+        purpose: PromptPurpose,
+        text: String,
+    ) -> Result<HistoryFilter, Whatever> {
+        let mut filter = self.filter.clone();
+        let empty = text.trim().is_empty();
+        match purpose {
+            PromptPurpose::FilterPattern if empty => filter.pattern = None,
+            PromptPurpose::FilterPattern => {
+                let query = SearchQuery::parse(self.search_kind, &text)?;
+                filter.pattern = Some((text, query));
+            }
+            PromptPurpose::FilterTimeRange if empty => filter.time_range = None,
+            PromptPurpose::FilterTimeRange => {
+                let range = TimeRange::parse(&text)?;
+                filter.time_range = Some((text, range));
+            }
+            PromptPurpose::FilterLength if empty => filter.min_len = None,
+            PromptPurpose::FilterLength => {
+                let len = text
+                    .trim()
+                    .parse()
+                    .ok()
+                    .with_whatever_context(|| format!("Invalid length '{}'", text))?;
+                filter.min_len = Some(len);
+            }
+            PromptPurpose::Search
+            | PromptPurpose::HighlightPattern
+            | PromptPurpose::Annotation
+            | PromptPurpose::CustomCrc => {}
+        }
+        Ok(filter)
+    }
+
+    fn rotate_search_kind(&mut self) {
+        let mut selected_index = SEARCH_KINDS
+            .iter()
+            .position(|x| *x == self.search_kind)
+            .unwrap_or(0);
+        selected_index += 1;
+        selected_index %= SEARCH_KINDS.len();
+        self.search_kind = SEARCH_KINDS[selected_index];
+    }
+
+    /// Searches the whole history and jumps to the newest match.
+    fn run_search(&mut self, text: String) {
+        self.search_result = None;
+        let query = match SearchQuery::parse(self.search_kind, &text) {
+            Ok(query) => query,
+            Err(e) => {
+                self.status_message = e.to_string();
+                return;
+            }
+        };
+        self.search_text = text;
+        let mut result = search(&self.display_history, &query);
+        if result.is_empty() {
+            self.status_message = format!("No matches for '{}'", self.search_text);
+            return;
+        }
+        result.select_last();
+        self.search_result = Some(result);
+        self.select_shown_match(SearchResult::select_previous);
+        self.jump_to_current_match();
+    }
+
+    fn goto_next_match(&mut self) {
+        if let Some(result) = self.search_result.as_mut() {
+            result.select_next();
+        }
+        self.select_shown_match(SearchResult::select_next);
+        self.jump_to_current_match();
+    }
+
+    fn goto_previous_match(&mut self) {
+        if let Some(result) = self.search_result.as_mut() {
+            result.select_previous();
+        }
+        self.select_shown_match(SearchResult::select_previous);
+        self.jump_to_current_match();
+    }
+
+    /// Where the cursor goes for the given match: the start of its first segment
+    /// in an entry that passes the filter, `None` if the filter hides the match.
+    fn match_start_in_view(&self, m: &SearchMatch) -> Option<(usize, usize)> {
+        m.segments
+            .iter()
+            .find(|(entry, _)| self.passes_filter(*entry))
+            .map(|(entry, range)| (*entry, range.start))
+    }
+
+    /// Steps from the current match with `step`, until a match is reached that
+    /// is not hidden by the filter. Stays put, if the filter hides all matches.
+    fn select_shown_match(&mut self, step: fn(&mut SearchResult)) {
+        let Some(mut result) = self.search_result.take() else {
+            return;
+        };
+        for _ in 0..result.len() {
+            match result.current() {
+                Some(m) if self.match_start_in_view(m).is_none() => step(&mut result),
+                _ => break,
+            }
+        }
+        self.search_result = Some(result);
+    }
+
+    /// Scrolls the entry with the current match to the bottom of the history
+    /// and puts the cursor on its first byte.
+    fn jump_to_current_match(&mut self) {
+        let Some(result) = &self.search_result else {
+            self.status_message = "No active search, press / to search".to_string();
+            return;
+        };
+        let hidden = result
+            .iter()
+            .filter(|m| self.match_start_in_view(m).is_none())
+            .count();
+        let Some((entry, offset)) = result.current().and_then(|m| self.match_start_in_view(m))
+        else {
+            self.status_message = format!(
+                "Search '{}': all {} matches hidden by filter",
+                self.search_text, hidden
+            );
+            return;
+        };
+        self.scroll_offset = self
+            .view_len()
+            .saturating_sub(self.view_position(entry) + 1) as u32;
+        self.analyzer_cursor_entry = Some(entry);
+        self.analyzer_cursor_pos = offset;
+        self.status_message = format!(
+            "Search '{}': {}/{}",
+            self.search_text,
+            result.current_index() + 1,
+            result.len()
+        );
+        if hidden > 0 {
+            self.status_message += &format!(", {} hidden by filter", hidden);
+        }
+    }
+
+    fn render_stats_panel(&self, area: Rect, buf: &mut Frame<'_>) {
+        let Some(metric) = self.stats_metric else {
+            return;
+        };
+        if !self.active {
+            return;
+        }
+
+        let format_value = |value: i64| {
+            if metric.is_time() {
+                let delta = self.delta_unit.format(TimeDelta::microseconds(value));
+                delta.trim_start_matches('+').to_string()
+            } else {
+                format!("{}B", value)
+            }
+        };
+
+        let [_, area] = Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)])
+            .areas(area);
+        let [area, _] = Layout::vertical([Constraint::Length(16), Constraint::Min(0)]).areas(area);
+        let block = Block::bordered().title(Line::from(vec![
+            format!(
+                "Statistics: {}{} ",
+                metric,
+                if self.stats_selection.is_some() { " (selection)" } else { "" }
+            )
+            .fg(Color::Gray),
+            "t".fg(Color::Red),
+            " next".fg(Color::Gray),
+        ]));
+        buf.render_widget(Clear, area);
+        let inner = block.inner(area);
+        buf.render_widget(block, area);
+
+        let Some(summary) = &self.stats_summary else {
+            buf.render_widget(Paragraph::new("No data").fg(Color::Gray), inner);
+            return;
+        };
+        let [text_area, chart_area] =
+            Layout::vertical([Constraint::Length(3), Constraint::Min(0)]).areas(inner);
+        let text = vec![
+            Line::from(format!(
+                "n: {} min: {} max: {} mean: {}",
+                summary.count,
+                format_value(summary.min),
+                format_value(summary.max),
+                format_value(summary.mean.round() as i64)
+            )),
+            Line::from(format!(
+                "p50: {} p90: {} p99: {}",
+                format_value(summary.p50),
+                format_value(summary.p90),
+                format_value(summary.p99)
+            )),
+        ];
+        buf.render_widget(Paragraph::new(text).fg(Color::Gray), text_area);
+
+        let bars: Vec<Bar> = summary
+            .histogram
+            .iter()
+            .map(|(from, count)| {
+                Bar::default()
+                    .value(*count)
+                    .text_value(count.to_string())
+                    .label(Line::from(format!(">={}", format_value(*from))))
+            })
+            .collect();
+        let chart = BarChart::default()
+            .data(BarGroup::default().bars(&bars))
+            .bar_gap(0)
+            .direction(ratatui::layout::Direction::Horizontal)
+            .bar_style(Style::new().fg(Color::Cyan))
+            .value_style(Style::new().fg(Color::Black).bg(Color::Cyan))
+            .label_style(Style::new().fg(Color::Gray));
+        buf.render_widget(chart, chart_area);
+    }
+
+    fn render_filter_popup(&self, area: Rect, buf: &mut Frame<'_>) {
+        if !self.active || !self.show_filter_popup {
+            return;
+        }
+
+        let unset = || "-".to_string();
+        let entries = [
+            (
+                'd',
+                "Direction",
+                self.filter.direction.as_ref().map_or_else(unset, |d| d.to_string()),
+            ),
+            (
+                'p',
+                "Pattern",
+                self.filter.pattern.as_ref().map_or_else(unset, |(text, _)| text.clone()),
+            ),
+            (
+                't',
+                "Time range",
+                self.filter.time_range.as_ref().map_or_else(unset, |(text, _)| text.clone()),
+            ),
+            (
+                'm',
+                "Markers only",
+                if self.filter.markers_only { "on" } else { "off" }.to_string(),
+            ),
+            (
+                'l',
+                "Longer than",
+                self.filter.min_len.map_or_else(unset, |len| format!("{} bytes", len)),
+            ),
+            (
+                'b',
+                "Bad checksum only",
+                self.filter.bad_checksum.map_or_else(unset, |v| v.to_string()),
+            ),
+        ];
+        let mut items: Vec<Line> = entries
+            .into_iter()
+            .map(|(key, name, value)| {
+                Line::from(vec![
+                    key.to_string().fg(Color::Red),
+                    format!(" - {}: {}", name, value).fg(Color::Gray),
+                ])
+            })
+            .collect();
+        items.push(Line::from(vec![
+            "c".fg(Color::Red),
+            " - Clear all".fg(Color::Gray),
+        ]));
+
+        let list = List::new(items)
+            .block(Block::bordered().title("Filter history"))
+            .style(Style::new().fg(Color::Gray));
+
+        let area = Self::popup_area(area, 30, 40);
+        buf.render_widget(Clear, area);
+        buf.render_widget(list, area);
+    }
+
+    fn render_highlight_popup(&self, area: Rect, buf: &mut Frame<'_>) {
+        if !self.active || !self.show_highlight_popup {
+            return;
+        }
+
+        let mut items: Vec<Line> = self
+            .highlight_rules
+            .iter()
+            .enumerate()
+            .map(|(i, rule)| {
+                let marker = if i == self.selected_highlight_rule { ">>" } else { "  " };
+                Line::from(vec![
+                    marker.fg(Color::Red),
+                    format!("{} ", rule).fg(Color::Gray),
+                    Span::styled("sample", Style::new().fg(Color::Gray).patch(rule.style())),
+                ])
+            })
+            .collect();
+        if items.is_empty() {
+            items.push(Line::from("No rules".fg(Color::Gray)));
+        }
+        let keys = [
+            ("a", "dd "),
+            ("d", "elete "),
+            ("f", "g "),
+            ("b", "g "),
+            ("m", "odifier "),
+            ("l", "ine"),
+        ];
+        let title = Line::from(
+            std::iter::once("Highlight rules: ".fg(Color::Gray))
+                .chain(keys.iter().flat_map(|(key, rest)| {
+                    [key.fg(Color::Red), rest.fg(Color::Gray)]
+                }))
+                .collect::<Vec<_>>(),
+        );
+
+        let list = List::new(items)
+            .block(Block::bordered().title(title))
+            .style(Style::new().fg(Color::Gray));
+
+        let area = Self::popup_area(area, 60, 40);
+        buf.render_widget(Clear, area);
+        buf.render_widget(list, area);
+    }
+
+    fn render_prompt(&self, area: Rect, buf: &mut Frame<'_>) {
+        let Some(prompt) = &self.prompt else {
+            return;
+        };
+        if area.height < 3 {
+            return;
+        }
+
+        let area = Rect {
+            y: area.bottom() - 3,
+            height: 3,
+            ..area
+        };
+        let title = match prompt.purpose {
+            PromptPurpose::Search => format!(
+                "Search {}{} (Tab: kind, Enter: search)",
+                self.search_kind,
+                self.search_kind.hint()
+            ),
+            PromptPurpose::FilterPattern => {
+                format!(
+                    "Filter pattern {}{} (Tab: kind, Enter: apply)",
+                    self.search_kind,
+                    self.search_kind.hint()
+                )
+            }
+            PromptPurpose::FilterTimeRange => {
+                "Filter time range, e.g. 10:00-10:05:30 (Enter: apply)".to_string()
+            }
+            PromptPurpose::FilterLength => {
+                "Filter entries longer than N bytes (Enter: apply)".to_string()
+            }
+            PromptPurpose::HighlightPattern => {
+                format!(
+                    "Highlight {}{} (Tab: kind, Enter: add)",
+                    self.search_kind,
+                    self.search_kind.hint()
+                )
+            }
+            PromptPurpose::Annotation => "Annotation (Enter: add)".to_string(),
+            PromptPurpose::CustomCrc => {
+                "CRC, e.g. width=16 poly=0x1021 init=0xffff refin=false refout=false xorout=0".to_string()
+            }
+        };
+        let prefix = if prompt.purpose == PromptPurpose::Search { "/" } else { "" };
+        let paragraph = Paragraph::new(format!("{}{}_", prefix, prompt.text))
+            .block(Block::bordered().title(title))
+            .style(Style::new().fg(Color::Gray));
+        buf.render_widget(Clear, area);
+        buf.render_widget(paragraph, area);
+    }
+
+    fn popup_area(area: Rect, percent_x: u16, percent_y: u16) -> Rect {
+        let vertical = Layout::vertical([Constraint::Percentage(percent_y)]).flex(Flex::Start);
+        let horizontal = Layout::horizontal([Constraint::Percentage(percent_x)]).flex(Flex::End);
+        let [area] = vertical.areas(area);
+        let [area] = horizontal.areas(area);
+        area
+    }
+
+    /// Looks up the reference timestamps of the entry at `index`.
+    fn lookup_reference_times(&self, index: usize) -> ReferenceTimes {
+        let session = self.session_starts.partition_point(|s| *s < index);
+        let session_start = session.checked_sub(1).map_or(0, |s| self.session_starts[s]);
+        let lookup = |reference| {
+            reference_timestamp(&self.display_history, index, reference, session_start)
+        };
+        ReferenceTimes {
+            delta: lookup(self.delta_reference),
+            session_start: lookup(DeltaReference::SessionStart),
+        }
+    }
+
+    /// Looks up the reference timestamps of the entries shown in the given number
+    /// of rows, that are not cached yet, and drops the ones scrolled out of view.
+    fn update_reference_cache(&mut self, rows: usize) {
+        let last = self.view_len().saturating_sub(self.scroll_offset as usize);
+        let first = last.saturating_sub(rows);
+        let shown: Vec<usize> = (first..last).map(|position| self.view_index(position)).collect();
+        self.reference_cache
+            .retain(|index, _| shown.binary_search(index).is_ok());
+        for index in shown {
+            if !self.reference_cache.contains_key(&index) {
+                let times = self.lookup_reference_times(index);
+                self.reference_cache.insert(index, times);
+            }
+        }
+    }
+
+    /// The time information shown in front of the entry at `index`.
+    fn time_information(&self, index: usize, timestamp: DateTime<Local>) -> String {
+        let times = self
+            .reference_cache
+            .get(&index)
+            .copied()
+            .unwrap_or_else(|| self.lookup_reference_times(index));
+        let absolute = || {
+            let base_time = match self.timestamps.base {
+                TimeBase::WallClock => None,
+                TimeBase::SessionStart => times.session_start,
+                TimeBase::Bookmark => {
+                    let bookmark = self.bookmarks.partition_point(|b| *b <= index);
+                    bookmark
+                        .checked_sub(1)
+                        .and_then(|b| self.display_history.get(self.bookmarks[b]))
+                        .and_then(|msg| msg.timestamp())
+                }
+            };
+            self.timestamps
+                .format(timestamp, base_time, TimestampTarget::Screen)
+        };
+        let delta = || {
+            times.delta.map_or("-".to_string(), |reference| {
+                self.delta_unit.format(timestamp - reference)
+            })
+        };
+        match self.active_time_display_mode {
+            TimeInformationMode::None => "".to_string(),
+            TimeInformationMode::Delta => format!("{} ", delta()),
+            TimeInformationMode::Absolute => format!("{} ", absolute()),
+            TimeInformationMode::AbsoluteWithDelta => format!("{}[{}] ", absolute(), delta()),
+        }
+    }
+
+    fn build_list_items(&self, cursor_run: Option<&CursorRun>, max_num_rows: usize) -> Vec<Line<'_>> {
+        // only fetch the visible entries, older entries may have to be read from disk:
+        let last = self.view_len().saturating_sub(self.scroll_offset as usize);
+        let first = last.saturating_sub(max_num_rows);
+
+        let items: Vec<Line> = (first..last)
+            .rev()
+            .map(|position| self.view_index(position))
+            .filter_map(|i| self.display_history.get(i).map(|x| (i, x)))
+            .map(|(index, x)| {
+                let result = match x {
+                    SerialStateMessage::DataEvent(x) => {
+                        let cursor_range = cursor_run.and_then(|run| run.value_range(index));
+                        let data_spans = self.build_data_spans(index, &x.data, cursor_range);
+
+                        let time_string = self.time_information(index, x.timestamp);
+
+                        let mut spans = vec![
+                            time_string.fg(ratatui::style::Color::Gray),
+                            x.rx_tx.to_string().fg(if x.rx_tx == RxTx::Tx {
+                                ratatui::style::Color::Green
+                            } else {
+                                ratatui::style::Color::Red
+                            }),
+                            ":".fg(ratatui::style::Color::Gray),
+                        ];
+                        if let Some(frame) = self.protocol.as_ref().and_then(|p| p.decode(&x.data, &x.rx_tx)) {
+                            spans.push(format!("[{}] ", frame.message).fg(Color::Magenta));
+                        }
+                        if x.rx_tx == RxTx::Rx {
+                            match self.rx_validation.validate(&x.data) {
+                                Some(true) => spans.push("✓ ".fg(Color::LightGreen)),
+                                Some(false) => {
+                                    spans.push("✗".fg(Color::White).bg(Color::Red));
+                                    spans.push(" ".into());
+                                }
+                                None => {}
+                            }
+                        }
+                        spans.extend(data_spans);
+                        Line::from(spans)
+                    }
+                    SerialStateMessage::ErrorEvent(x) => Line::raw(x),
+                    SerialStateMessage::Annotation(note) => Line::from(vec![
+                        self.time_information(index, note.timestamp)
+                            .fg(ratatui::style::Color::Gray),
+                        format!("*** {} ***", note.text).fg(ratatui::style::Color::Cyan),
+                    ]),
+                    SerialStateMessage::Started => {
+                        Line::from(vec!["--- Started ---".fg(ratatui::style::Color::Green)])
+                    }
+                    SerialStateMessage::Stopped => {
+                        Line::from(vec!["--- Stopped ---".fg(ratatui::style::Color::LightRed)])
+                    }
+                };
+                result
+            })
+            .collect();
+        items
+    }
+
+    /// Builds the spans showing the data of the entry with the given index. Search
+    /// matches are highlighted, as are the bytes decoded by the analyzer, given by
+    /// `cursor_range`. If its flag is set, the range starts with the cursor.
+    fn build_data_spans(
+        &self,
+        index: usize,
+        data: &[u8],
+        cursor_range: Option<(Range<usize>, bool)>,
+    ) -> Vec<Span<'static>> {
+        let base = Style::new().fg(Color::Gray);
+        let mut styles = self.highlighter.styles(data, base);
+
+        if let Some(result) = &self.search_result {
+            for (range, current) in result.highlights(index) {
+                let style = if current {
+                    Style::new().fg(Color::Black).bg(Color::LightGreen)
+                } else {
+                    Style::new().fg(Color::Black).bg(Color::Yellow)
+                };
+                styles[range.start.min(data.len())..range.end.min(data.len())].fill(style);
+            }
+        }
+
+        if let Some(range) = self.selected_range(index, data.len()) {
+            styles[range].fill(Style::new().fg(Color::Black).bg(Color::Cyan));
+        }
+
+        if let Some((range, has_cursor)) = cursor_range {
+            for style in styles[range.start.min(data.len())..range.end.min(data.len())].iter_mut() {
+                if *style == base {
+                    *style = base.bg(Color::DarkGray);
+                }
+            }
+            if has_cursor && range.start < data.len() {
+                styles[range.start] = base.bg(Color::Blue);
+            }
+        }
+
+        let separator = display_separator(self.active_display_mode);
+        let mut spans: Vec<Span<'static>> = vec![];
+        for (i, byte) in data.iter().enumerate() {
+            push_span(&mut spans, format_byte_for_display(*byte, self.active_display_mode), styles[i]);
+            if let Some(next) = styles.get(i + 1) {
+                // only fill the gap, if both neighbours are styled alike:
+                let style = if *next == styles[i] { styles[i] } else { base };
+                push_span(&mut spans, separator.to_string(), style);
+            }
+        }
+        spans
+    }
+
+    /// The arrival time of the byte at the cursor, relative to the start of the entry,
+    /// and the gap to the byte before it.
+    fn byte_timing(&self, entry: &HistoryEntry, pos: usize) -> Vec<String> {
+        let Some(arrival) = entry.byte_time(pos) else {
+            return vec![];
+        };
+        let estimated = if entry.char_time_ns > 0 { " (est.)" } else { "" };
+        let mut items = vec![format!("arrival: {}{}", self.delta_unit.format(arrival), estimated)];
+        let previous = pos.checked_sub(1).and_then(|pos| entry.byte_time(pos));
+        if let Some(previous) = previous {
+            let gap = arrival - previous;
+            let mut line = format!("gap: {}", self.delta_unit.format(gap));
+            if entry.char_time_ns > 0 {
+                // back to back characters are one character time apart:
+                let chars = gap.num_nanoseconds().unwrap_or(0) as f64 / entry.char_time_ns as f64;
+                line += &format!(" ({:.1} chars silence)", (chars - 1.0).max(0.0));
+            }
+            items.push(line);
+        }
+        items
+    }
+
+    /// Renders the analyzer window. This window will appear in analyzer mode, in every
+    /// display mode, as the cursor addresses bytes rather than characters on screen.
+    /// The window will contain the values of the bytes starting at the cursor position
+    /// in all formats supported by `decode`. Values continue into the following entries
+    /// of the same direction.
+    fn render_analyzer(&self, area: Rect, buf: &mut Frame<'_>, cursor_run: Option<CursorRun>) {
+        let Some(run) = cursor_run else {
+            return;
+        };
+        let analyzer_data = &run.bytes;
+        if analyzer_data.is_empty() {
+            return;
+        }
+
+        let mut items = decode(analyzer_data, self.analyzer_endianness, self.analyzer_fraction_bits);
+        if run.segments.len() > 1 {
+            items.push(format!("spans {} entries", run.segments.len()));
+        }
+        if let Some(selected) = self.selected_bytes() {
+            items.push(format!("selection: {} bytes (y: copy, p: send)", selected.len()));
+        }
+        items.extend(self.byte_timing(&run.entry, run.pos));
+        let item_count = items.len();
+
+        let headline = Line::from(vec![
+            format!(
+                "Analyzer {}/{},",
+                run.pos + 1,
+                run.entry.data.len()
+            )
+            .fg(ratatui::style::Color::Gray),
+            format!(" {} ", self.analyzer_endianness).fg(ratatui::style::Color::Gray),
+            "e".fg(ratatui::style::Color::Red),
+            "ndian".fg(ratatui::style::Color::Gray),
+        ]);
+
+        let list = List::new(items)
+            .block(Block::bordered().title(headline))
+            .style(Style::new().fg(ratatui::style::Color::Gray))
+            .highlight_style(Style::new().fg(ratatui::style::Color::Red))
+            .highlight_symbol(">>")
+            .repeat_highlight_symbol(true)
+            .direction(ListDirection::TopToBottom);
+
+        //let block = Block::bordered().title("Analyzer");
+        let area = Self::popup_area(area, 40, 100);
+        let area = Rect {
+            height: area.height.min(item_count as u16 + 2),
+            ..area
+        };
+        buf.render_widget(Clear, area); //this clears out the background
+                                        //buf.render_widget(block, area);
+        buf.render_widget(list, area);
+    }
+}
+
+/// Appends the rows of the field tree of the nodes: the name, indented by the tree
+/// lines, the bytes and the value.
+fn field_tree(nodes: &[FieldNode], indent: &str, rows: &mut Vec<(String, String, String)>) {
+    for (i, node) in nodes.iter().enumerate() {
+        let last = i + 1 == nodes.len();
+        let bytes = match node.range.len() {
+            0 => String::new(),
+            1 => node.range.start.to_string(),
+            _ => format!("{}-{}", node.range.start, node.range.end - 1),
+        };
+        rows.push((
+            format!("{}{}{}", indent, if last { "└ " } else { "├ " }, node.name),
+            bytes,
+            node.value.clone(),
+        ));
+        let indent = format!("{}{}", indent, if last { "  " } else { "│ " });
+        field_tree(&node.children, &indent, rows);
+    }
+}
+
+/// Validates the checksum of received frames, see `RxValidation::validate`.
+fn validate_rx(validation: &RxValidation, msg: &SerialStateMessage) -> Option<bool> {
+    match msg {
+        SerialStateMessage::DataEvent(entry) if entry.rx_tx == RxTx::Rx => {
+            validation.validate(&entry.data)
+        }
+        _ => None,
+    }
+}
+
+/// Appends the text to the last span, if it has the same style, so that a line
+/// does not consist of one span per byte.
+fn push_span(spans: &mut Vec<Span<'static>>, text: String, style: Style) {
+    match spans.last_mut() {
+        Some(last) if last.style == style => last.content.to_mut().push_str(&text),
+        _ => spans.push(Span::styled(text, style)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{export::write_export, portthread::HistoryEntry};
+
+    use super::*;
+
+    fn data_event(rx_tx: RxTx, data: &[u8]) -> SerialStateMessage {
+        SerialStateMessage::DataEvent(HistoryEntry {
+            rx_tx,
+            data: data.to_vec(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_export_with_filter() {
+        let mut analyzer = AnalyzerMode::new(History::default());
+        analyzer.add_message(data_event(RxTx::Rx, &[0x01, 0x02]));
+        analyzer.add_message(data_event(RxTx::Tx, &[0x03]));
+        analyzer.add_message(data_event(RxTx::Rx, &[0x04]));
+        analyzer.filter.direction = Some(RxTx::Tx);
+        analyzer.apply_filter();
+
+        let mut output = vec![];
+        write_export(
+            &mut output,
+            analyzer.exported_messages(),
+            ExportFormat::Json,
+            &TimestampSettings::default(),
+        )
+        .unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&output).unwrap();
+        let entries = value.as_array().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0]["direction"], "TX");
+        assert_eq!(entries[0]["hex"], "03");
+    }
+
+    #[test]
+    fn test_stats_over_selection() {
+        let mut analyzer = AnalyzerMode::new(History::default());
+        analyzer.add_message(data_event(RxTx::Rx, &[0x01, 0x02]));
+        analyzer.add_message(data_event(RxTx::Tx, &[0x03]));
+        analyzer.add_message(data_event(RxTx::Rx, &[0x04, 0x05, 0x06]));
+        analyzer.rotate_stats_metric();
+        analyzer.rotate_stats_metric();
+        analyzer.rotate_stats_metric();
+        assert_eq!(analyzer.stats_summary.as_ref().unwrap().count, 3);
+
+        analyzer.selection_anchor = Some((1, 0));
+        analyzer.move_cursor_to(2, 1);
+        analyzer.update_data(
+            DisplayMode::Hex,
+            &TimestampSettings::default(),
+            RxValidation::default(),
+        );
+        let summary = analyzer.stats_summary.as_ref().unwrap();
+        assert_eq!((summary.count, summary.min, summary.max), (2, 1, 3));
+    }
+
+    #[test]
+    fn test_search_skips_matches_hidden_by_filter() {
+        let mut analyzer = AnalyzerMode::new(History::default());
+        analyzer.add_message(data_event(RxTx::Rx, b"ok"));
+        analyzer.add_message(data_event(RxTx::Tx, b"ok"));
+        analyzer.add_message(data_event(RxTx::Rx, b"ok"));
+        analyzer.add_message(data_event(RxTx::Tx, b"ok"));
+        analyzer.filter.direction = Some(RxTx::Rx);
+        analyzer.apply_filter();
+
+        analyzer.run_search("ok".to_string());
+        assert_eq!(analyzer.analyzer_cursor_entry, Some(2));
+        assert_eq!(analyzer.status_message, "Search 'ok': 3/4, 2 hidden by filter");
+
+        analyzer.goto_previous_match();
+        assert_eq!(analyzer.analyzer_cursor_entry, Some(0));
+        analyzer.goto_previous_match();
+        assert_eq!(analyzer.analyzer_cursor_entry, Some(2));
+        analyzer.goto_next_match();
+        assert_eq!(analyzer.analyzer_cursor_entry, Some(0));
+
+        analyzer.filter.direction = None;
+        analyzer.filter.min_len = Some(2);
+        analyzer.apply_filter();
+        analyzer.goto_next_match();
+        assert_eq!(analyzer.analyzer_cursor_entry, None);
+        assert_eq!(analyzer.status_message, "Search 'ok': all 4 matches hidden by filter");
     }
 }
@@ -0,0 +1,358 @@
+use std::{
+    fmt::Display,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::PathBuf,
+};
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use snafu::{prelude::*, Whatever};
+
+use crate::{
+    portthread::{RxTx, SerialStateMessage},
+    serialtypes::format_data_for_display,
+    timing::{TimestampFormatter, TimestampSettings, TimestampTarget},
+    DisplayMode,
+};
+
+pub const LOG_FORMATS: [LogFormat; 3] = [LogFormat::Raw, LogFormat::Text, LogFormat::JsonLines];
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum LogFormat {
+    /// Only the received bytes, exactly as they came in.
+    Raw,
+    /// One timestamped line per entry, formatted with the active display mode.
+    #[default]
+    Text,
+    /// One JSON object per line, containing timestamp, direction and hex data.
+    JsonLines,
+}
+
+impl Display for LogFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LogFormat::Raw => write!(f, "Raw"),
+            LogFormat::Text => write!(f, "Text"),
+            LogFormat::JsonLines => write!(f, "JSON"),
+        }
+    }
+}
+
+/// Capture log settings as stored in the .klemme file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CaptureLogSettings {
+    pub enabled: bool,
+    pub format: LogFormat,
+    /// Name of the log file. `{port}`, `{date}` and `{time}` are replaced by
+    /// the port name and the date/time the log was started.
+    pub file_template: String,
+    /// Once a log file exceeds this number of bytes, logging continues in a new
+    /// file. 0 disables rotation.
+    pub max_file_size: u64,
+}
+
+impl Default for CaptureLogSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            format: LogFormat::Text,
+            file_template: "klemme_{port}_{date}_{time}.log".to_string(),
+            max_file_size: 0,
+        }
+    }
+}
+
+/// Renders the file name template for the given port and start time.
+fn render_file_template(template: &str, port: &str, time: &DateTime<Local>) -> String {
+    // port names may be paths, which would not make for a valid file name:
+    let port = port.replace(['/', '\\', ':'], "_");
+    template
+        .replace("{port}", &port)
+        .replace("{date}", &time.format("%Y%m%d").to_string())
+        .replace("{time}", &time.format("%H%M%S").to_string())
+}
+
+/// Writes every `SerialStateMessage` to a log file as it arrives.
+#[derive(Debug, Default)]
+pub struct CaptureLog {
+    settings: CaptureLogSettings,
+    file: Option<File>,
+    base_path: PathBuf,
+    rotation: usize,
+    /// Format of the records in the current file.
+    file_format: Option<LogFormat>,
+    written: u64,
+    timestamps: TimestampFormatter,
+}
+
+impl CaptureLog {
+    pub fn new(settings: CaptureLogSettings) -> Self {
+        Self {
+            settings,
+            ..Default::default()
+        }
+    }
+
+    /// Applies new settings. The current log file is closed, so the next message
+    /// will start a new file with the new settings. If the file name does not
+    /// change, a changed format continues in the next rotated file, so a file
+    /// never mixes formats.
+    pub fn apply_settings(&mut self, settings: CaptureLogSettings) {
+        if settings != self.settings {
+            self.file = None;
+        }
+        self.settings = settings;
+    }
+
+    fn rotated_path(&self) -> PathBuf {
+        if self.rotation == 0 {
+            return self.base_path.clone();
+        }
+        let mut path = self.base_path.clone().into_os_string();
+        path.push(format!(".{}", self.rotation));
+        PathBuf::from(path)
+    }
+
+    fn open_file(&mut self) -> Result<(), Whatever> {
+        let path = self.rotated_path();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_whatever_context(|_| format!("Failed to open log file {}", path.display()))?;
+        self.written = file.metadata().map(|m| m.len()).unwrap_or(0);
+        self.file = Some(file);
+        Ok(())
+    }
+
+    /// Logs a single message. Does nothing, if logging is disabled.
+    pub fn log(
+        &mut self,
+        msg: &SerialStateMessage,
+        port: &str,
+        display_mode: DisplayMode,
+        timestamps: &TimestampSettings,
+    ) -> Result<(), Whatever> {
+        // the time bases are tracked while logging is disabled too, so they are
+        // correct once it is enabled:
+        self.timestamps.set_settings(timestamps);
+        self.timestamps.observe(msg);
+        if !self.settings.enabled {
+            self.file = None;
+            return Ok(());
+        }
+
+        let record = format_log_record(msg, self.settings.format, display_mode, &self.timestamps);
+        if record.is_empty() {
+            return Ok(());
+        }
+
+        if self.file.is_none() {
+            let name = render_file_template(&self.settings.file_template, port, &Local::now());
+            let path = PathBuf::from(name);
+            if path != self.base_path {
+                self.base_path = path;
+                self.rotation = 0;
+            } else if self.file_format != Some(self.settings.format) {
+                self.rotation += 1;
+            }
+            self.file_format = Some(self.settings.format);
+            self.open_file()?;
+        }
+
+        if self.settings.max_file_size > 0
+            && self.written > 0
+            && self.written + record.len() as u64 > self.settings.max_file_size
+        {
+            self.rotation += 1;
+            self.open_file()?;
+        }
+
+        if let Some(file) = &mut self.file {
+            file.write_all(&record)
+                .whatever_context("Failed to write log file")?;
+            self.written += record.len() as u64;
+        }
+        Ok(())
+    }
+}
+
+/// Converts a message to the bytes that are written to the log.
+fn format_log_record(
+    msg: &SerialStateMessage,
+    format: LogFormat,
+    display_mode: DisplayMode,
+    timestamps: &TimestampFormatter,
+) -> Vec<u8> {
+    let now = Local::now();
+    let text_time = |timestamp| timestamps.format(timestamp, TimestampTarget::File);
+    let json_time = |timestamp| timestamps.format(timestamp, TimestampTarget::Json);
+    match format {
+        LogFormat::Raw => match msg {
+            SerialStateMessage::DataEvent(entry) if entry.rx_tx == RxTx::Rx => entry.data.clone(),
+            _ => vec![],
+        },
+        LogFormat::Text => {
+            let line = match msg {
+                SerialStateMessage::DataEvent(entry) => format!(
+                    "{} {}:{}",
+                    text_time(entry.timestamp),
+                    entry.rx_tx,
+                    format_data_for_display(&entry.data, display_mode)
+                ),
+                SerialStateMessage::ErrorEvent(err) => {
+                    format!("{} {}", text_time(now), err)
+                }
+                SerialStateMessage::Started => {
+                    format!("{} --- Started ---", text_time(now))
+                }
+                SerialStateMessage::Stopped => {
+                    format!("{} --- Stopped ---", text_time(now))
+                }
+                SerialStateMessage::Annotation(note) => format!(
+                    "{} *** {} ***",
+                    text_time(note.timestamp),
+                    note.text
+                ),
+            };
+            (line + "\n").into_bytes()
+        }
+        LogFormat::JsonLines => {
+            let value = match msg {
+                SerialStateMessage::DataEvent(entry) => serde_json::json!({
+                    "timestamp": json_time(entry.timestamp),
+                    "direction": entry.rx_tx.to_string(),
+                    "data": entry.data.iter().map(|x| format!("{:02X}", x)).collect::<String>(),
+                }),
+                SerialStateMessage::ErrorEvent(err) => serde_json::json!({
+                    "timestamp": json_time(now),
+                    "event": "error",
+                    "message": err,
+                }),
+                SerialStateMessage::Started => serde_json::json!({
+                    "timestamp": json_time(now),
+                    "event": "started",
+                }),
+                SerialStateMessage::Stopped => serde_json::json!({
+                    "timestamp": json_time(now),
+                    "event": "stopped",
+                }),
+                SerialStateMessage::Annotation(note) => serde_json::json!({
+                    "timestamp": json_time(note.timestamp),
+                    "event": "annotation",
+                    "message": note.text,
+                }),
+            };
+            (value.to_string() + "\n").into_bytes()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::portthread::HistoryEntry;
+
+    use super::*;
+
+    fn rx_message(data: &[u8]) -> SerialStateMessage {
+        SerialStateMessage::DataEvent(HistoryEntry {
+            rx_tx: RxTx::Rx,
+            data: data.to_vec(),
+            ..Default::default()
+        })
+    }
+
+    fn record(msg: &SerialStateMessage, format: LogFormat) -> Vec<u8> {
+        format_log_record(msg, format, DisplayMode::Hex, &TimestampFormatter::default())
+    }
+
+    #[test]
+    fn test_render_file_template() {
+        let time = DateTime::parse_from_rfc3339("2024-03-05T07:08:09+00:00")
+            .unwrap()
+            .with_timezone(&Local);
+        let name = render_file_template("{port}_{date}_{time}.log", "/dev/ttyUSB0", &time);
+        assert_eq!(
+            name,
+            format!("_dev_ttyUSB0_{}_{}.log", time.format("%Y%m%d"), time.format("%H%M%S"))
+        );
+    }
+
+    #[test]
+    fn test_raw_format_only_logs_rx() {
+        let tx = SerialStateMessage::DataEvent(HistoryEntry {
+            rx_tx: RxTx::Tx,
+            data: vec![0x01],
+            ..Default::default()
+        });
+        assert!(record(&tx, LogFormat::Raw).is_empty());
+        assert_eq!(record(&rx_message(&[0xAB, 0xCD]), LogFormat::Raw), vec![0xAB, 0xCD]);
+    }
+
+    #[test]
+    fn test_json_lines_format() {
+        let record = record(&rx_message(&[0x0A, 0xFF]), LogFormat::JsonLines);
+        let value: serde_json::Value = serde_json::from_slice(&record).unwrap();
+        assert_eq!(value["direction"], "RX");
+        assert_eq!(value["data"], "0AFF");
+    }
+
+    #[test]
+    fn test_log_rotation() {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("klemme_capturelog_test_{}", std::process::id()));
+        let settings = CaptureLogSettings {
+            enabled: true,
+            format: LogFormat::Raw,
+            file_template: dir.to_str().unwrap().to_string(),
+            max_file_size: 4,
+        };
+        let mut log = CaptureLog::new(settings);
+        let timestamps = TimestampSettings::default();
+        log.log(&rx_message(&[1, 2, 3]), "port", DisplayMode::Hex, &timestamps).unwrap();
+        log.log(&rx_message(&[4, 5, 6]), "port", DisplayMode::Hex, &timestamps).unwrap();
+        let rotated = log.rotated_path();
+
+        assert_eq!(std::fs::read(&dir).unwrap(), vec![1, 2, 3]);
+        assert_eq!(std::fs::read(&rotated).unwrap(), vec![4, 5, 6]);
+        let _ = std::fs::remove_file(&dir);
+        let _ = std::fs::remove_file(&rotated);
+    }
+
+    #[test]
+    fn test_format_switch_applies_to_running_log() {
+        let mut path = std::env::temp_dir();
+        path.push(format!("klemme_capturelog_switch_{}", std::process::id()));
+        let mut settings = CaptureLogSettings {
+            enabled: true,
+            format: LogFormat::Raw,
+            file_template: path.to_str().unwrap().to_string(),
+            max_file_size: 0,
+        };
+        let mut log = CaptureLog::new(settings.clone());
+        let timestamps = TimestampSettings::default();
+        log.log(&rx_message(&[0x41]), "port", DisplayMode::Hex, &timestamps).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), vec![0x41]);
+
+        settings.format = LogFormat::JsonLines;
+        log.apply_settings(settings.clone());
+        log.log(&rx_message(&[0x42]), "port", DisplayMode::Hex, &timestamps).unwrap();
+        let rotated = |n: usize| PathBuf::from(format!("{}.{}", path.display(), n));
+        assert_eq!(std::fs::read(&path).unwrap(), vec![0x41]);
+        let written = std::fs::read(rotated(1)).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&written).unwrap();
+        assert_eq!(value["data"], "42");
+
+        settings.format = LogFormat::Raw;
+        log.apply_settings(settings);
+        log.log(&rx_message(&[0x43]), "port", DisplayMode::Hex, &timestamps).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), vec![0x41]);
+        assert_eq!(std::fs::read(rotated(1)).unwrap(), written);
+        assert_eq!(std::fs::read(rotated(2)).unwrap(), vec![0x43]);
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(rotated(1));
+        let _ = std::fs::remove_file(rotated(2));
+    }
+}
@@ -0,0 +1,469 @@
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+use snafu::{prelude::*, Whatever};
+
+/// The checksums shown by the checksum panel, besides the user defined CRC.
+pub const CHECKSUMS: [Checksum; 11] = [
+    Checksum::Xor8,
+    Checksum::Sum8,
+    Checksum::Sum16,
+    Checksum::TwosComplement8,
+    Checksum::Fletcher16,
+    Checksum::Crc8,
+    Checksum::Crc16Modbus,
+    Checksum::Crc16Ccitt,
+    Checksum::Crc16Xmodem,
+    Checksum::Crc32,
+    Checksum::Crc32C,
+];
+
+/// Parameters of a CRC in the notation of the "Catalogue of parametrised CRC
+/// algorithms", e.g. CRC-16/MODBUS is `width=16 poly=0x8005 init=0xffff refin=true
+/// refout=true xorout=0`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "UncheckedCrcParams")]
+pub struct CrcParams {
+    pub width: u8,
+    pub poly: u64,
+    pub init: u64,
+    pub refin: bool,
+    pub refout: bool,
+    pub xorout: u64,
+}
+
+/// `CrcParams` as read from a settings file, before the width is checked.
+#[derive(Deserialize)]
+struct UncheckedCrcParams {
+    width: u8,
+    poly: u64,
+    init: u64,
+    refin: bool,
+    refout: bool,
+    xorout: u64,
+}
+
+impl TryFrom<UncheckedCrcParams> for CrcParams {
+    type Error = String;
+
+    fn try_from(params: UncheckedCrcParams) -> Result<Self, Self::Error> {
+        if !(1..=64).contains(&params.width) {
+            return Err(format!(
+                "The CRC width must be between 1 and 64, not {}",
+                params.width
+            ));
+        }
+        Ok(CrcParams {
+            width: params.width,
+            poly: params.poly,
+            init: params.init,
+            refin: params.refin,
+            refout: params.refout,
+            xorout: params.xorout,
+        })
+    }
+}
+
+impl CrcParams {
+    /// Parses parameters like `width=16 poly=0x1021 init=0xffff`. Values may be
+    /// decimal or hex, parameters left out are 0 or false.
+    pub fn parse(input: &str) -> Result<CrcParams, Whatever> {
+        let mut params = CrcParams {
+            width: 0,
+            poly: 0,
+            init: 0,
+            refin: false,
+            refout: false,
+            xorout: 0,
+        };
+        for item in input.split_whitespace() {
+            let Some((key, value)) = item.split_once('=') else {
+                whatever!("CRC parameter '{}' must have the form key=value", item);
+            };
+            let number = || -> Result<u64, Whatever> {
+                let parsed = match value.strip_prefix("0x").or(value.strip_prefix("0X")) {
+                    Some(hex) => u64::from_str_radix(hex, 16),
+                    None => value.parse(),
+                };
+                parsed.with_whatever_context(|_| format!("Invalid number '{}'", value))
+            };
+            let flag = || -> Result<bool, Whatever> {
+                value
+                    .parse()
+                    .with_whatever_context(|_| format!("Invalid flag '{}'", value))
+            };
+            match key {
+                "width" => {
+                    params.width = u8::try_from(number()?)
+                        .ok()
+                        .filter(|width| (1..=64).contains(width))
+                        .with_whatever_context(|| {
+                            format!("The CRC width must be between 1 and 64, not {}", value)
+                        })?
+                }
+                "poly" => params.poly = number()?,
+                "init" => params.init = number()?,
+                "refin" => params.refin = flag()?,
+                "refout" => params.refout = flag()?,
+                "xorout" => params.xorout = number()?,
+                _ => whatever!("Unknown CRC parameter '{}'", key),
+            }
+        }
+        if !(1..=64).contains(&params.width) {
+            whatever!("The CRC width must be between 1 and 64");
+        }
+        Ok(params)
+    }
+
+    fn mask(&self) -> u64 {
+        u64::MAX >> (64 - self.width)
+    }
+
+    /// Computes the CRC bit by bit, which is fast enough for frames typed or
+    /// selected by hand.
+    pub fn compute(&self, bytes: &[u8]) -> u64 {
+        let top = 1u64 << (self.width - 1);
+        let mut crc = self.init & self.mask();
+        for byte in bytes {
+            let byte = if self.refin {
+                byte.reverse_bits()
+            } else {
+                *byte
+            };
+            for bit in (0..8).rev() {
+                let input = (byte >> bit) & 1 == 1;
+                let feedback = (crc & top != 0) != input;
+                crc = (crc << 1) & self.mask();
+                if feedback {
+                    crc ^= self.poly & self.mask();
+                }
+            }
+        }
+        if self.refout {
+            crc = crc.reverse_bits() >> (64 - self.width);
+        }
+        (crc ^ self.xorout) & self.mask()
+    }
+}
+
+impl Display for CrcParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "width={} poly={:#x} init={:#x} refin={} refout={} xorout={:#x}",
+            self.width, self.poly, self.init, self.refin, self.refout, self.xorout
+        )
+    }
+}
+
+const CRC8: CrcParams = CrcParams {
+    width: 8,
+    poly: 0x07,
+    init: 0,
+    refin: false,
+    refout: false,
+    xorout: 0,
+};
+
+const CRC16_MODBUS: CrcParams = CrcParams {
+    width: 16,
+    poly: 0x8005,
+    init: 0xffff,
+    refin: true,
+    refout: true,
+    xorout: 0,
+};
+
+const CRC16_CCITT: CrcParams = CrcParams {
+    width: 16,
+    poly: 0x1021,
+    init: 0xffff,
+    refin: false,
+    refout: false,
+    xorout: 0,
+};
+
+const CRC16_XMODEM: CrcParams = CrcParams {
+    width: 16,
+    poly: 0x1021,
+    init: 0,
+    refin: false,
+    refout: false,
+    xorout: 0,
+};
+
+const CRC32: CrcParams = CrcParams {
+    width: 32,
+    poly: 0x04c11db7,
+    init: 0xffffffff,
+    refin: true,
+    refout: true,
+    xorout: 0xffffffff,
+};
+
+const CRC32C: CrcParams = CrcParams {
+    width: 32,
+    poly: 0x1edc6f41,
+    init: 0xffffffff,
+    refin: true,
+    refout: true,
+    xorout: 0xffffffff,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Checksum {
+    /// XOR of all bytes.
+    Xor8,
+    /// Sum of all bytes, modulo 256.
+    Sum8,
+    /// Sum of all bytes, modulo 65536.
+    Sum16,
+    /// The two's complement of the 8 bit sum, as used by e.g. Intel HEX and Modbus ASCII (LRC).
+    TwosComplement8,
+    Fletcher16,
+    /// CRC-8/SMBUS
+    Crc8,
+    Crc16Modbus,
+    /// CRC-16/CCITT-FALSE
+    Crc16Ccitt,
+    Crc16Xmodem,
+    Crc32,
+    /// CRC-32C (Castagnoli)
+    Crc32C,
+    /// A CRC with user defined parameters.
+    Custom(CrcParams),
+}
+
+impl Display for Checksum {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Checksum::Xor8 => write!(f, "XOR"),
+            Checksum::Sum8 => write!(f, "Sum8"),
+            Checksum::Sum16 => write!(f, "Sum16"),
+            Checksum::TwosComplement8 => write!(f, "LRC"),
+            Checksum::Fletcher16 => write!(f, "Fletcher-16"),
+            Checksum::Crc8 => write!(f, "CRC-8"),
+            Checksum::Crc16Modbus => write!(f, "CRC-16/Modbus"),
+            Checksum::Crc16Ccitt => write!(f, "CRC-16/CCITT"),
+            Checksum::Crc16Xmodem => write!(f, "CRC-16/XMODEM"),
+            Checksum::Crc32 => write!(f, "CRC-32"),
+            Checksum::Crc32C => write!(f, "CRC-32C"),
+            Checksum::Custom(params) => write!(f, "CRC-{} (custom)", params.width),
+        }
+    }
+}
+
+impl Checksum {
+    /// Number of bytes the checksum takes in a frame.
+    pub fn len(&self) -> usize {
+        match self {
+            Checksum::Xor8 | Checksum::Sum8 | Checksum::TwosComplement8 | Checksum::Crc8 => 1,
+            Checksum::Sum16
+            | Checksum::Fletcher16
+            | Checksum::Crc16Modbus
+            | Checksum::Crc16Ccitt
+            | Checksum::Crc16Xmodem => 2,
+            Checksum::Crc32 | Checksum::Crc32C => 4,
+            Checksum::Custom(params) => (params.width as usize).div_ceil(8),
+        }
+    }
+
+    pub fn compute(&self, bytes: &[u8]) -> u64 {
+        let sum = || bytes.iter().map(|b| *b as u64).sum::<u64>();
+        match self {
+            Checksum::Xor8 => bytes.iter().fold(0, |acc, b| acc ^ b) as u64,
+            Checksum::Sum8 => sum() & 0xff,
+            Checksum::Sum16 => sum() & 0xffff,
+            Checksum::TwosComplement8 => (sum() as u8).wrapping_neg() as u64,
+            Checksum::Fletcher16 => {
+                let (low, high) = bytes.iter().fold((0u64, 0u64), |(low, high), b| {
+                    let low = (low + *b as u64) % 255;
+                    (low, (high + low) % 255)
+                });
+                high << 8 | low
+            }
+            Checksum::Crc8 => CRC8.compute(bytes),
+            Checksum::Crc16Modbus => CRC16_MODBUS.compute(bytes),
+            Checksum::Crc16Ccitt => CRC16_CCITT.compute(bytes),
+            Checksum::Crc16Xmodem => CRC16_XMODEM.compute(bytes),
+            Checksum::Crc32 => CRC32.compute(bytes),
+            Checksum::Crc32C => CRC32C.compute(bytes),
+            Checksum::Custom(params) => params.compute(bytes),
+        }
+    }
+
+    /// The checksum of the bytes as it is appended to a frame.
+    pub fn trailer(&self, bytes: &[u8], big_endian: bool) -> Vec<u8> {
+        let value = self.compute(bytes).to_be_bytes();
+        let mut result = value[8 - self.len()..].to_vec();
+        if !big_endian {
+            result.reverse();
+        }
+        result
+    }
+
+    /// Checks, whether the checksum of `data` equals the start of `trailer`. Returns
+    /// whether it matched big endian, or `None` if it did not match in either order.
+    pub fn matches(&self, data: &[u8], trailer: &[u8]) -> Option<bool> {
+        let trailer = trailer.get(..self.len())?;
+        [true, false]
+            .into_iter()
+            .find(|big_endian| self.trailer(data, *big_endian) == trailer)
+    }
+}
+
+/// Offsets of the first byte covered by a checksum, to leave out e.g. a start byte.
+pub const CHECKSUM_STARTS: [usize; 4] = [0, 1, 2, 3];
+
+/// Numbers of bytes at the end of a frame that are not covered by a checksum.
+pub const CHECKSUM_ENDS: [usize; 3] = [0, 1, 2];
+
+/// Numbers of bytes following the checksum at the end of a frame, e.g. an ETX or CR LF.
+pub const CHECKSUM_TRAILERS: [usize; 3] = [0, 1, 2];
+
+/// Validation of the checksums of received frames. The checksum is expected at the
+/// end of every frame, followed by `after` bytes, and covers the bytes from `start`
+/// up to the checksum.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RxValidation {
+    /// The checksum, `None` if received frames are not validated.
+    pub checksum: Option<Checksum>,
+    pub big_endian: bool,
+    pub start: usize,
+    pub after: usize,
+}
+
+impl RxValidation {
+    /// Returns whether the checksum of the frame is valid, or `None` if validation
+    /// is off. Frames too short to hold the checksum are invalid.
+    pub fn validate(&self, data: &[u8]) -> Option<bool> {
+        let checksum = self.checksum?;
+        let Some(end) = self
+            .after
+            .checked_add(checksum.len())
+            .and_then(|n| data.len().checked_sub(n))
+        else {
+            return Some(false);
+        };
+        if end < self.start {
+            return Some(false);
+        }
+        let trailer = &data[end..end + checksum.len()];
+        Some(checksum.trailer(&data[self.start..end], self.big_endian) == trailer)
+    }
+}
+
+impl Display for RxValidation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Some(checksum) = self.checksum else {
+            return write!(f, "Off");
+        };
+        write!(f, "{}", checksum)?;
+        if checksum.len() > 1 {
+            write!(f, " {}", if self.big_endian { "BE" } else { "LE" })?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_values() {
+        // the check values of the CRC catalogue are computed over "123456789":
+        let check = b"123456789";
+        let expected = [
+            (Checksum::Xor8, 0x31),
+            (Checksum::Sum8, 0xdd),
+            (Checksum::Sum16, 0x1dd),
+            (Checksum::TwosComplement8, 0x23),
+            (Checksum::Fletcher16, 0x1ede),
+            (Checksum::Crc8, 0xf4),
+            (Checksum::Crc16Modbus, 0x4b37),
+            (Checksum::Crc16Ccitt, 0x29b1),
+            (Checksum::Crc16Xmodem, 0x31c3),
+            (Checksum::Crc32, 0xcbf43926),
+            (Checksum::Crc32C, 0xe3069283),
+        ];
+        for (checksum, value) in expected {
+            assert_eq!(checksum.compute(check), value, "{}", checksum);
+        }
+        assert_eq!(Checksum::Fletcher16.compute(b"abcde"), 0xc8f0);
+    }
+
+    #[test]
+    fn test_custom_crc_and_matching() {
+        // CRC-16/KERMIT:
+        let params = CrcParams::parse("width=16 poly=0x1021 refin=true refout=true").unwrap();
+        assert_eq!(Checksum::Custom(params).compute(b"123456789"), 0x2189);
+        assert_eq!(
+            CrcParams::parse(&CRC16_MODBUS.to_string()).unwrap(),
+            CRC16_MODBUS
+        );
+        assert!(CrcParams::parse("width=0").is_err());
+        assert!(CrcParams::parse("width=65").is_err());
+        // 272 would be 16 when truncated to a byte:
+        assert!(CrcParams::parse("width=272 poly=0x1021").is_err());
+
+        // settings files are checked too, a width of 0 would panic when computing:
+        let json = serde_json::to_string(&CRC16_MODBUS).unwrap();
+        assert_eq!(
+            serde_json::from_str::<CrcParams>(&json).unwrap(),
+            CRC16_MODBUS
+        );
+        let bad = json.replace("\"width\":16", "\"width\":0");
+        assert!(serde_json::from_str::<CrcParams>(&bad).is_err());
+        let validation = RxValidation {
+            checksum: Some(Checksum::Custom(CRC16_MODBUS)),
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&validation).unwrap();
+        assert_eq!(
+            serde_json::from_str::<RxValidation>(&json).unwrap(),
+            validation
+        );
+        let bad = json.replace("\"width\":16", "\"width\":65");
+        assert!(serde_json::from_str::<RxValidation>(&bad).is_err());
+        assert!(CrcParams::parse("width=16 poly=xyz").is_err());
+        assert!(CrcParams::parse("size=16").is_err());
+
+        // a Modbus request with its CRC, low byte first:
+        let frame = [0x01, 0x03, 0x00, 0x00, 0x00, 0x0A, 0xC5, 0xCD];
+        let modbus = Checksum::Crc16Modbus;
+        assert_eq!(modbus.trailer(&frame[..6], false), [0xC5, 0xCD]);
+        assert_eq!(modbus.matches(&frame[..6], &frame[6..]), Some(false));
+        assert_eq!(Checksum::Crc16Ccitt.matches(&frame[..6], &frame[6..]), None);
+        assert_eq!(modbus.matches(&frame[..6], &frame[7..]), None);
+    }
+
+    #[test]
+    fn test_rx_validation() {
+        let mut validation = RxValidation::default();
+        assert_eq!(validation.validate(b"abc"), None);
+
+        // STX, payload, XOR over the payload, ETX:
+        validation.checksum = Some(Checksum::Xor8);
+        validation.start = 1;
+        validation.after = 1;
+        assert_eq!(validation.validate(&[0x02, 0x31, 0x32, 0x03, 0x03]), Some(true));
+        assert_eq!(validation.validate(&[0x02, 0x31, 0x33, 0x03, 0x03]), Some(false));
+        assert_eq!(validation.validate(&[0x02, 0x03]), Some(false));
+        // a huge number of bytes after the checksum, e.g. from a hand edited file:
+        validation.after = usize::MAX;
+        assert_eq!(validation.validate(&[0x02, 0x03]), Some(false));
+        assert_eq!(validation.to_string(), "XOR");
+
+        validation = RxValidation {
+            checksum: Some(Checksum::Crc16Modbus),
+            ..Default::default()
+        };
+        let frame = [0x01, 0x03, 0x00, 0x00, 0x00, 0x0A, 0xC5, 0xCD];
+        assert_eq!(validation.validate(&frame), Some(true));
+        validation.big_endian = true;
+        assert_eq!(validation.validate(&frame), Some(false));
+        assert_eq!(validation.to_string(), "CRC-16/Modbus BE");
+    }
+}
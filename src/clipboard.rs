@@ -0,0 +1,146 @@
+use std::{fmt::Display, io::Write};
+
+use snafu::{prelude::*, Whatever};
+
+pub const COPY_FORMATS: [CopyFormat; 5] = [
+    CopyFormat::Hex,
+    CopyFormat::CArray,
+    CopyFormat::RustBytes,
+    CopyFormat::Base64,
+    CopyFormat::Raw,
+];
+
+/// Format of bytes copied to the clipboard.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CopyFormat {
+    /// `01 02 0A`
+    Hex,
+    /// `{ 0x01, 0x02, 0x0A }`
+    CArray,
+    /// `b"\x01\x02\n"`
+    RustBytes,
+    Base64,
+    /// The bytes as text, invalid UTF-8 is replaced.
+    Raw,
+}
+
+impl Display for CopyFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CopyFormat::Hex => write!(f, "Hex"),
+            CopyFormat::CArray => write!(f, "C array"),
+            CopyFormat::RustBytes => write!(f, "Rust byte string"),
+            CopyFormat::Base64 => write!(f, "Base64"),
+            CopyFormat::Raw => write!(f, "Raw text"),
+        }
+    }
+}
+
+impl CopyFormat {
+    /// The key selecting the format in the copy popup.
+    pub fn key(&self) -> char {
+        match self {
+            CopyFormat::Hex => 'h',
+            CopyFormat::CArray => 'c',
+            CopyFormat::RustBytes => 'r',
+            CopyFormat::Base64 => 'b',
+            CopyFormat::Raw => 't',
+        }
+    }
+
+    pub fn format(&self, bytes: &[u8]) -> String {
+        match self {
+            CopyFormat::Hex => bytes
+                .iter()
+                .map(|b| format!("{:02X}", b))
+                .collect::<Vec<_>>()
+                .join(" "),
+            CopyFormat::CArray => format!(
+                "{{ {} }}",
+                bytes
+                    .iter()
+                    .map(|b| format!("0x{:02X}", b))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            CopyFormat::RustBytes => format!(
+                "b\"{}\"",
+                bytes
+                    .iter()
+                    .flat_map(|b| std::ascii::escape_default(*b))
+                    .map(|b| b as char)
+                    .collect::<String>()
+            ),
+            CopyFormat::Base64 => base64(bytes),
+            CopyFormat::Raw => String::from_utf8_lossy(bytes).into_owned(),
+        }
+    }
+}
+
+/// Encodes the bytes as standard base64 with padding.
+pub fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] =
+        b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut result = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let value = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |value, (i, b)| value | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                result.push(ALPHABET[(value >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                result.push('=');
+            }
+        }
+    }
+    result
+}
+
+/// The OSC 52 escape sequence, that asks the terminal to put the text into the
+/// system clipboard. As it is interpreted by the terminal, it also works over SSH.
+fn osc52(text: &str) -> String {
+    format!("\x1b]52;c;{}\x07", base64(text.as_bytes()))
+}
+
+/// Copies the text to the system clipboard, see `osc52`. Terminals that do not
+/// support OSC 52 ignore it.
+pub fn copy_to_clipboard(text: &str) -> Result<(), Whatever> {
+    let mut stdout = std::io::stdout();
+    stdout
+        .write_all(osc52(text).as_bytes())
+        .and_then(|_| stdout.flush())
+        .whatever_context("Failed to write to the terminal")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_copy_formats() {
+        let bytes = [0x01, b'A', b'"', b'\n', 0xFF];
+        assert_eq!(CopyFormat::Hex.format(&bytes), "01 41 22 0A FF");
+        assert_eq!(
+            CopyFormat::CArray.format(&bytes),
+            "{ 0x01, 0x41, 0x22, 0x0A, 0xFF }"
+        );
+        assert_eq!(
+            CopyFormat::RustBytes.format(&bytes),
+            "b\"\\x01A\\\"\\n\\xff\""
+        );
+        assert_eq!(CopyFormat::Raw.format(b"OK\r\n"), "OK\r\n");
+    }
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64(&[0xFF, 0xFE]), "//4=");
+        assert_eq!(osc52("hi"), "\x1b]52;c;aGk=\x07");
+    }
+}
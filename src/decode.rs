@@ -0,0 +1,373 @@
+use std::fmt::Display;
+
+use chrono::{DateTime, Datelike, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{serialtypes::format_byte_for_display, DisplayMode};
+
+/// Maximum number of bytes decoded, i.e. the maximum length of strings.
+pub const MAX_DECODE_LEN: usize = 32;
+
+/// Number of bytes of the largest numeric value.
+pub const MAX_VALUE_LEN: usize = 8;
+
+pub const ENDIANNESSES: [Endianness; 4] = [
+    Endianness::Little,
+    Endianness::Big,
+    Endianness::BigWordSwap,
+    Endianness::LittleWordSwap,
+];
+
+/// Number of fraction bits of the fixed point values, i.e. the `n` of `Qm.n`.
+pub const Q_FRACTION_BITS: [u32; 6] = [8, 12, 15, 16, 24, 31];
+
+/// Byte order of multi-byte values. Besides big and little endian, the two mixed
+/// orders of 16-bit words are supported, as used by e.g. Modbus devices for 32-bit
+/// values spread over two registers.
+#[derive(Debug, Default, PartialEq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Endianness {
+    /// ABCD
+    Big,
+    /// DCBA
+    #[default]
+    Little,
+    /// CDAB: big endian words, least significant word first.
+    #[serde(rename = "cdab")]
+    BigWordSwap,
+    /// BADC: little endian words, most significant word first.
+    #[serde(rename = "badc")]
+    LittleWordSwap,
+}
+
+impl Display for Endianness {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Endianness::Big => write!(f, "Big"),
+            Endianness::Little => write!(f, "Little"),
+            Endianness::BigWordSwap => write!(f, "CDAB"),
+            Endianness::LittleWordSwap => write!(f, "BADC"),
+        }
+    }
+}
+
+/// Takes the first `N` bytes and puts them into big endian order. Words are only
+/// swapped in values made of whole words, 24-bit values use the byte order within
+/// a word.
+pub fn ordered<const N: usize>(bytes: &[u8], endianness: Endianness) -> Option<[u8; N]> {
+    let mut value: [u8; N] = bytes.get(..N)?.try_into().ok()?;
+    match endianness {
+        Endianness::Big => {}
+        Endianness::Little => value.reverse(),
+        Endianness::BigWordSwap if N.is_multiple_of(2) => {
+            value.reverse();
+            value.chunks_mut(2).for_each(|word| word.reverse());
+        }
+        Endianness::LittleWordSwap if N.is_multiple_of(2) => {
+            value.chunks_mut(2).for_each(|word| word.reverse())
+        }
+        Endianness::BigWordSwap => {}
+        Endianness::LittleWordSwap => value.reverse(),
+    }
+    Some(value)
+}
+
+/// Converts an IEEE 754 half precision float.
+pub fn f16_to_f32(bits: u16) -> f32 {
+    let sign = if bits & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = ((bits >> 10) & 0x1f) as i32;
+    let mantissa = (bits & 0x3ff) as f32;
+    match exponent {
+        0 => sign * mantissa * 2f32.powi(-24),
+        0x1f if mantissa == 0.0 => sign * f32::INFINITY,
+        0x1f => f32::NAN,
+        _ => sign * (1.0 + mantissa / 1024.0) * 2f32.powi(exponent - 15),
+    }
+}
+
+/// Decodes packed BCD, most significant digit first. Returns `None`, if a nibble
+/// is not a decimal digit.
+pub fn bcd(bytes: &[u8]) -> Option<u64> {
+    bytes
+        .iter()
+        .flat_map(|byte| [byte >> 4, byte & 0x0f])
+        .try_fold(0u64, |value, digit| {
+            (digit <= 9).then(|| value * 10 + digit as u64)
+        })
+}
+
+/// Reads the groups of 7 bits of a LEB128 value, at most 10 bytes. Returns the
+/// lower 64 bits of the value, the number of bytes and the bits of the tenth byte
+/// beyond the 64th bit.
+fn leb128(bytes: &[u8]) -> Option<(u64, usize, u8)> {
+    let mut value = 0u64;
+    for (i, byte) in bytes.iter().take(10).enumerate() {
+        let payload = byte & 0x7f;
+        value |= (payload as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            let excess = if i == 9 { payload >> 1 } else { 0 };
+            return Some((value, i + 1, excess));
+        }
+    }
+    None
+}
+
+/// Decodes an unsigned LEB128 value. Returns the value and the number of bytes
+/// it takes, or `None` if the value does not end within the bytes or exceeds 64 bits.
+pub fn leb128_unsigned(bytes: &[u8]) -> Option<(u64, usize)> {
+    let (value, len, excess) = leb128(bytes)?;
+    (excess == 0).then_some((value, len))
+}
+
+/// Decodes a signed LEB128 value, see `leb128_unsigned`.
+pub fn leb128_signed(bytes: &[u8]) -> Option<(i64, usize)> {
+    let (value, len, excess) = leb128(bytes)?;
+    let bits = 7 * len as u32;
+    if bits >= 64 {
+        // the bits beyond the 64th have to repeat the sign:
+        let sign_extension = if (value as i64) < 0 { 0x3f } else { 0 };
+        return (excess == sign_extension).then_some((value as i64, len));
+    }
+    // sign extend from the last bit read:
+    let shift = 64 - bits;
+    Some((((value << shift) as i64) >> shift, len))
+}
+
+/// Returns the time, if it is plausible for a timestamp, i.e. between 1980 and 2100.
+pub fn unix_time(value: i64, millis: bool) -> Option<DateTime<Utc>> {
+    let time = if millis {
+        DateTime::from_timestamp_millis(value)?
+    } else {
+        DateTime::from_timestamp(value, 0)?
+    };
+    (1980..=2100).contains(&time.year()).then_some(time)
+}
+
+/// The printable ASCII characters at the start of the bytes.
+pub fn ascii_prefix(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .take_while(|byte| (0x20..0x7f).contains(*byte))
+        .map(|byte| *byte as char)
+        .collect()
+}
+
+/// The printable characters of the valid UTF-8 at the start of the bytes. A
+/// character cut off at the end of the bytes is left out.
+pub fn utf8_prefix(bytes: &[u8]) -> String {
+    let valid = match std::str::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(e) => std::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap_or_default(),
+    };
+    valid.chars().take_while(|c| !c.is_control()).collect()
+}
+
+/// The value of a signed fixed point number with the given number of fraction bits.
+fn fixed_point(raw: i64, fraction_bits: u32) -> f64 {
+    raw as f64 / (1u64 << fraction_bits) as f64
+}
+
+/// Decodes the bytes in every supported format, one line per format. Formats that
+/// need more bytes than given are left out.
+pub fn decode(bytes: &[u8], endianness: Endianness, fraction_bits: u32) -> Vec<String> {
+    let Some(&one_byte) = bytes.first() else {
+        return vec![];
+    };
+    let mut items = vec![
+        format!("binary (MSB first): {:08b}", one_byte),
+        format!(
+            "u8: {}  i8: {}  char: {}",
+            one_byte,
+            one_byte as i8,
+            format_byte_for_display(one_byte, DisplayMode::Ascii)
+        ),
+    ];
+
+    let two = ordered::<2>(bytes, endianness);
+    let three = ordered::<3>(bytes, endianness);
+    let four = ordered::<4>(bytes, endianness);
+    let eight = ordered::<8>(bytes, endianness);
+
+    let mut bcd_values = vec![bcd(&[one_byte])];
+    bcd_values.extend(two.map(|two| bcd(&two)));
+    bcd_values.extend(four.map(|four| bcd(&four)));
+    let bcd_values: Vec<String> = bcd_values
+        .iter()
+        .map(|value| value.map_or("-".to_string(), |v| v.to_string()))
+        .collect();
+    items.push(format!("BCD: {}", bcd_values.join(" / ")));
+
+    if let Some(two) = two {
+        items.push(format!(
+            "u16: {}  i16: {}  f16: {}",
+            u16::from_be_bytes(two),
+            i16::from_be_bytes(two),
+            f16_to_f32(u16::from_be_bytes(two))
+        ));
+        if fraction_bits < 16 {
+            let raw = i16::from_be_bytes(two) as i64;
+            items.push(format!(
+                "Q{}.{}: {}",
+                15 - fraction_bits,
+                fraction_bits,
+                fixed_point(raw, fraction_bits)
+            ));
+        }
+    }
+    if let Some(three) = three {
+        let unsigned = u32::from_be_bytes([0, three[0], three[1], three[2]]);
+        // sign extend from bit 23:
+        let signed = ((unsigned << 8) as i32) >> 8;
+        items.push(format!("u24: {}  i24: {}", unsigned, signed));
+    }
+    if let Some(four) = four {
+        items.push(format!(
+            "u32: {}  i32: {}",
+            u32::from_be_bytes(four),
+            i32::from_be_bytes(four)
+        ));
+        items.push(format!("f32: {}", f32::from_be_bytes(four)));
+        let raw = i32::from_be_bytes(four) as i64;
+        items.push(format!(
+            "Q{}.{}: {}",
+            31 - fraction_bits,
+            fraction_bits,
+            fixed_point(raw, fraction_bits)
+        ));
+        let seconds = u32::from_be_bytes(four) as i64;
+        if let Some(time) = unix_time(seconds, false) {
+            items.push(format!(
+                "time32 s: {}",
+                time.format("%Y-%m-%d %H:%M:%S UTC")
+            ));
+        }
+    }
+    if let Some(eight) = eight {
+        items.push(format!("u64: {}", u64::from_be_bytes(eight)));
+        items.push(format!("i64: {}", i64::from_be_bytes(eight)));
+        items.push(format!("f64: {}", f64::from_be_bytes(eight)));
+        let value = i64::from_be_bytes(eight);
+        if let Some(time) = unix_time(value, false) {
+            items.push(format!(
+                "time64 s: {}",
+                time.format("%Y-%m-%d %H:%M:%S UTC")
+            ));
+        }
+        if let Some(time) = unix_time(value, true) {
+            items.push(format!(
+                "time64 ms: {}",
+                time.format("%Y-%m-%d %H:%M:%S%.3f UTC")
+            ));
+        }
+    }
+
+    if let (Some((unsigned, len)), Some((signed, _))) =
+        (leb128_unsigned(bytes), leb128_signed(bytes))
+    {
+        items.push(format!(
+            "LEB128: {} (signed: {}, {} bytes)",
+            unsigned, signed, len
+        ));
+    }
+
+    let ascii = ascii_prefix(bytes);
+    if !ascii.is_empty() {
+        items.push(format!("ASCII: \"{}\"", ascii));
+    }
+    let utf8 = utf8_prefix(bytes);
+    if !utf8.is_ascii() {
+        items.push(format!("UTF-8: \"{}\"", utf8));
+    }
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_byte_orders() {
+        let bytes = [0x0A, 0x0B, 0x0C, 0x0D];
+        assert_eq!(
+            ordered::<4>(&bytes, Endianness::Big),
+            Some([0x0A, 0x0B, 0x0C, 0x0D])
+        );
+        assert_eq!(
+            ordered::<4>(&bytes, Endianness::Little),
+            Some([0x0D, 0x0C, 0x0B, 0x0A])
+        );
+        assert_eq!(
+            ordered::<4>(&bytes, Endianness::BigWordSwap),
+            Some([0x0C, 0x0D, 0x0A, 0x0B])
+        );
+        assert_eq!(
+            ordered::<4>(&bytes, Endianness::LittleWordSwap),
+            Some([0x0B, 0x0A, 0x0D, 0x0C])
+        );
+        assert_eq!(
+            ordered::<2>(&bytes, Endianness::BigWordSwap),
+            Some([0x0A, 0x0B])
+        );
+        assert_eq!(
+            ordered::<3>(&bytes, Endianness::LittleWordSwap),
+            Some([0x0C, 0x0B, 0x0A])
+        );
+        assert_eq!(ordered::<8>(&bytes, Endianness::Big), None);
+
+        // a Modbus float of 123.456, low word first:
+        let modbus = [0xE9, 0x79, 0x42, 0xF6];
+        let value = f32::from_be_bytes(ordered(&modbus, Endianness::BigWordSwap).unwrap());
+        assert_eq!(value, 123.456);
+    }
+
+    #[test]
+    fn test_leb128_limits() {
+        let mut max = [0xFF; 10];
+        max[9] = 0x01;
+        assert_eq!(leb128_unsigned(&max), Some((u64::MAX, 10)));
+        max[9] = 0x02;
+        assert_eq!(leb128_unsigned(&max), None);
+        assert_eq!(leb128_unsigned(&[0x80; 11]), None);
+
+        let mut min = [0x80; 10];
+        min[9] = 0x7F;
+        assert_eq!(leb128_signed(&min), Some((i64::MIN, 10)));
+        min[9] = 0x01;
+        assert_eq!(leb128_signed(&min), None);
+    }
+
+    #[test]
+    fn test_value_formats() {
+        assert_eq!(f16_to_f32(0x3C00), 1.0);
+        assert_eq!(f16_to_f32(0xC000), -2.0);
+        assert_eq!(f16_to_f32(0x0001), 2f32.powi(-24));
+        assert_eq!(f16_to_f32(0x7C00), f32::INFINITY);
+        assert_eq!(bcd(&[0x12, 0x34]), Some(1234));
+        assert_eq!(bcd(&[0x1A]), None);
+        assert_eq!(leb128_unsigned(&[0xE5, 0x8E, 0x26]), Some((624485, 3)));
+        assert_eq!(leb128_signed(&[0xC0, 0xBB, 0x78]), Some((-123456, 3)));
+        assert_eq!(leb128_unsigned(&[0x80, 0x80]), None);
+        assert_eq!(fixed_point(-384, 8), -1.5);
+        assert_eq!(unix_time(0, false), None);
+        assert_eq!(
+            unix_time(1_700_000_000_000, true).map(|t| t.timestamp()),
+            Some(1_700_000_000)
+        );
+        assert_eq!(ascii_prefix(b"OK\r\n"), "OK");
+        assert_eq!(utf8_prefix("Grüße\n".as_bytes()), "Grüße");
+        // a character cut off at the end:
+        assert_eq!(utf8_prefix(&"äö".as_bytes()[..3]), "ä");
+    }
+
+    #[test]
+    fn test_decode_honours_endianness() {
+        let bytes = [1, 0, 0, 0, 0, 0, 0, 0];
+        let little = decode(&bytes, Endianness::Little, 8);
+        assert!(little.contains(&"u64: 1".to_string()));
+        assert!(little.contains(&"u24: 1  i24: 1".to_string()));
+        let big = decode(&bytes, Endianness::Big, 8);
+        assert!(big.contains(&"u64: 72057594037927936".to_string()));
+        assert!(big.contains(&"Q7.8: 1".to_string()));
+        assert!(big.contains(&"BCD: 1 / 100 / 1000000".to_string()));
+    }
+}
@@ -0,0 +1,456 @@
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use chrono::Local;
+use snafu::{prelude::*, Whatever};
+
+use crate::{
+    pcap::write_pcapng,
+    portthread::{Annotation, HistoryEntry, SerialStateMessage},
+    timing::{TimestampFormatter, TimestampSettings, TimestampTarget},
+};
+
+pub const EXPORT_FORMATS: [ExportFormat; 6] = [
+    ExportFormat::Csv,
+    ExportFormat::Json,
+    ExportFormat::HexDump,
+    ExportFormat::CArray,
+    ExportFormat::PythonBytes,
+    ExportFormat::Pcapng,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    /// timestamp, direction, hex, ascii
+    Csv,
+    /// An array of objects, one per entry
+    Json,
+    /// xxd style hexdump, one block per entry
+    HexDump,
+    /// One C array per entry
+    CArray,
+    /// One python bytes literal per entry
+    PythonBytes,
+    /// pcapng capture for Wireshark, one packet per entry
+    Pcapng,
+}
+
+impl Display for ExportFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExportFormat::Csv => write!(f, "CSV"),
+            ExportFormat::Json => write!(f, "JSON"),
+            ExportFormat::HexDump => write!(f, "Hexdump"),
+            ExportFormat::CArray => write!(f, "C Array"),
+            ExportFormat::PythonBytes => write!(f, "Python bytes"),
+            ExportFormat::Pcapng => write!(f, "pcapng (Wireshark)"),
+        }
+    }
+}
+
+impl ExportFormat {
+    /// The key that selects this format in the export popup.
+    pub fn key(&self) -> char {
+        match self {
+            ExportFormat::Csv => 'c',
+            ExportFormat::Json => 'j',
+            ExportFormat::HexDump => 'h',
+            ExportFormat::CArray => 'a',
+            ExportFormat::PythonBytes => 'p',
+            ExportFormat::Pcapng => 'w',
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Json => "json",
+            ExportFormat::HexDump => "txt",
+            ExportFormat::CArray => "c",
+            ExportFormat::PythonBytes => "py",
+            ExportFormat::Pcapng => "pcapng",
+        }
+    }
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter()
+        .map(|x| format!("{:02X}", x))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Printable ASCII characters are kept, everything else becomes a '.'.
+fn to_printable(data: &[u8]) -> String {
+    data.iter()
+        .map(|x| {
+            if x.is_ascii_graphic() || *x == b' ' {
+                *x as char
+            } else {
+                '.'
+            }
+        })
+        .collect()
+}
+
+fn csv_escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        return format!("\"{}\"", field.replace('"', "\"\""));
+    }
+    field.to_string()
+}
+
+/// Name used for the variables in the C and python exports.
+fn variable_name(entry: &HistoryEntry, index: usize) -> String {
+    format!("{}_{}", entry.rx_tx.to_string().to_lowercase(), index)
+}
+
+fn write_csv<W: Write>(
+    writer: &mut W,
+    messages: impl Iterator<Item = SerialStateMessage>,
+    timestamps: &mut TimestampFormatter,
+) -> std::io::Result<()> {
+    writeln!(writer, "timestamp,direction,hex,ascii")?;
+    for msg in messages {
+        timestamps.observe(&msg);
+        match msg {
+            SerialStateMessage::DataEvent(entry) => writeln!(
+                writer,
+                "{},{},{},{}",
+                csv_escape(&timestamps.format(entry.timestamp, TimestampTarget::File)),
+                entry.rx_tx,
+                to_hex(&entry.data),
+                csv_escape(&to_printable(&entry.data))
+            )?,
+            SerialStateMessage::ErrorEvent(err) => writeln!(writer, ",,,{}", csv_escape(&err))?,
+            SerialStateMessage::Started => writeln!(writer, ",,,--- Started ---")?,
+            SerialStateMessage::Stopped => writeln!(writer, ",,,--- Stopped ---")?,
+            SerialStateMessage::Annotation(note) => writeln!(
+                writer,
+                "{},note,,{}",
+                csv_escape(&timestamps.format(note.timestamp, TimestampTarget::File)),
+                csv_escape(&note.text)
+            )?,
+        }
+    }
+    Ok(())
+}
+
+fn write_json<W: Write>(
+    writer: &mut W,
+    messages: impl Iterator<Item = SerialStateMessage>,
+    timestamps: &mut TimestampFormatter,
+) -> std::io::Result<()> {
+    // written element by element, so that large histories don't have to
+    // be held in memory as a whole:
+    writeln!(writer, "[")?;
+    for (index, msg) in messages.enumerate() {
+        timestamps.observe(&msg);
+        let value = match msg {
+            SerialStateMessage::DataEvent(entry) => serde_json::json!({
+                "timestamp": timestamps.format(entry.timestamp, TimestampTarget::Json),
+                "direction": entry.rx_tx.to_string(),
+                "hex": to_hex(&entry.data),
+                "ascii": to_printable(&entry.data),
+            }),
+            SerialStateMessage::ErrorEvent(err) => serde_json::json!({ "error": err }),
+            SerialStateMessage::Started => serde_json::json!({ "event": "started" }),
+            SerialStateMessage::Stopped => serde_json::json!({ "event": "stopped" }),
+            SerialStateMessage::Annotation(note) => serde_json::json!({
+                "timestamp": timestamps.format(note.timestamp, TimestampTarget::Json),
+                "annotation": note.text,
+            }),
+        };
+        if index > 0 {
+            writeln!(writer, ",")?;
+        }
+        write!(writer, "  {}", value)?;
+    }
+    writeln!(writer, "\n]")?;
+    Ok(())
+}
+
+fn write_hexdump<W: Write>(
+    writer: &mut W,
+    messages: impl Iterator<Item = SerialStateMessage>,
+    timestamps: &mut TimestampFormatter,
+) -> std::io::Result<()> {
+    for item in data_entries(messages, timestamps) {
+        let (entry, timestamp) = match item {
+            DataItem::Entry(entry, timestamp) => (entry, timestamp),
+            DataItem::Note(note, timestamp) => {
+                writeln!(writer, "# {}\n", note_comment(&note, &timestamp))?;
+                continue;
+            }
+        };
+        writeln!(
+            writer,
+            "# {} {} ({} bytes)",
+            timestamp,
+            entry.rx_tx,
+            entry.data.len()
+        )?;
+        for (line, chunk) in entry.data.chunks(16).enumerate() {
+            let hex = chunk
+                .chunks(2)
+                .map(|pair| pair.iter().map(|x| format!("{:02x}", x)).collect::<String>())
+                .collect::<Vec<String>>()
+                .join(" ");
+            writeln!(writer, "{:08x}: {:<40} {}", line * 16, hex, to_printable(chunk))?;
+        }
+        writeln!(writer)?;
+    }
+    Ok(())
+}
+
+fn write_c_array<W: Write>(
+    writer: &mut W,
+    messages: impl Iterator<Item = SerialStateMessage>,
+    timestamps: &mut TimestampFormatter,
+) -> std::io::Result<()> {
+    let mut index = 0;
+    for item in data_entries(messages, timestamps) {
+        let (entry, timestamp) = match item {
+            DataItem::Entry(entry, timestamp) => (entry, timestamp),
+            DataItem::Note(note, timestamp) => {
+                writeln!(writer, "// {}\n", note_comment(&note, &timestamp))?;
+                continue;
+            }
+        };
+        // a zero length array is not valid C:
+        if entry.data.is_empty() {
+            continue;
+        }
+        writeln!(writer, "// {}", timestamp)?;
+        writeln!(
+            writer,
+            "const unsigned char {}[{}] = {{",
+            variable_name(&entry, index),
+            entry.data.len()
+        )?;
+        for chunk in entry.data.chunks(12) {
+            let line = chunk
+                .iter()
+                .map(|x| format!("0x{:02X},", x))
+                .collect::<Vec<String>>()
+                .join(" ");
+            writeln!(writer, "    {}", line)?;
+        }
+        writeln!(writer, "}};\n")?;
+        index += 1;
+    }
+    Ok(())
+}
+
+fn write_python_bytes<W: Write>(
+    writer: &mut W,
+    messages: impl Iterator<Item = SerialStateMessage>,
+    timestamps: &mut TimestampFormatter,
+) -> std::io::Result<()> {
+    let mut index = 0;
+    for item in data_entries(messages, timestamps) {
+        let (entry, timestamp) = match item {
+            DataItem::Entry(entry, timestamp) => (entry, timestamp),
+            DataItem::Note(note, timestamp) => {
+                writeln!(writer, "# {}", note_comment(&note, &timestamp))?;
+                continue;
+            }
+        };
+        let literal: String = entry.data.iter().map(|x| format!("\\x{:02x}", x)).collect();
+        writeln!(writer, "# {}", timestamp)?;
+        writeln!(writer, "{} = b\"{}\"", variable_name(&entry, index), literal)?;
+        index += 1;
+    }
+    Ok(())
+}
+
+/// The messages written by the formats that only contain data. Annotations are
+/// written as comments, so they are not lost. Both come with their formatted
+/// timestamp.
+enum DataItem {
+    Entry(HistoryEntry, String),
+    Note(Annotation, String),
+}
+
+fn data_entries<'a>(
+    messages: impl Iterator<Item = SerialStateMessage> + 'a,
+    timestamps: &'a mut TimestampFormatter,
+) -> impl Iterator<Item = DataItem> + 'a {
+    messages.filter_map(move |msg| {
+        timestamps.observe(&msg);
+        match msg {
+            SerialStateMessage::DataEvent(entry) => {
+                let timestamp = timestamps.format(entry.timestamp, TimestampTarget::File);
+                Some(DataItem::Entry(entry, timestamp))
+            }
+            SerialStateMessage::Annotation(note) => {
+                let timestamp = timestamps.format(note.timestamp, TimestampTarget::File);
+                Some(DataItem::Note(note, timestamp))
+            }
+            _ => None,
+        }
+    })
+}
+
+fn note_comment(note: &Annotation, timestamp: &str) -> String {
+    // keep the comment on a single line:
+    format!(
+        "{} note: {}",
+        timestamp,
+        note.text.replace(['\r', '\n'], " ")
+    )
+}
+
+/// Writes the messages to `writer` in the given format. pcapng stores the
+/// timestamps in binary, so the timestamp settings don't apply to it.
+pub fn write_export<W: Write>(
+    writer: &mut W,
+    messages: impl Iterator<Item = SerialStateMessage>,
+    format: ExportFormat,
+    timestamps: &TimestampSettings,
+) -> std::io::Result<()> {
+    let timestamps = &mut TimestampFormatter::new(timestamps.clone());
+    match format {
+        ExportFormat::Csv => write_csv(writer, messages, timestamps),
+        ExportFormat::Json => write_json(writer, messages, timestamps),
+        ExportFormat::HexDump => write_hexdump(writer, messages, timestamps),
+        ExportFormat::CArray => write_c_array(writer, messages, timestamps),
+        ExportFormat::PythonBytes => write_python_bytes(writer, messages, timestamps),
+        ExportFormat::Pcapng => write_pcapng(writer, messages),
+    }
+}
+
+/// Returns a file name for an export in the given format, based on the current time.
+pub fn default_export_path(format: ExportFormat) -> PathBuf {
+    PathBuf::from(format!(
+        "klemme_export_{}.{}",
+        Local::now().format("%Y%m%d_%H%M%S"),
+        format.extension()
+    ))
+}
+
+/// Exports the messages to the file at `path`.
+pub fn export_to_file(
+    path: &Path,
+    messages: impl Iterator<Item = SerialStateMessage>,
+    format: ExportFormat,
+    timestamps: &TimestampSettings,
+) -> Result<(), Whatever> {
+    let file = File::create(path)
+        .with_whatever_context(|_| format!("Failed to create {}", path.display()))?;
+    let mut writer = BufWriter::new(file);
+    write_export(&mut writer, messages, format, timestamps)
+        .with_whatever_context(|_| format!("Failed to write {}", path.display()))?;
+    writer
+        .flush()
+        .with_whatever_context(|_| format!("Failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{portthread::RxTx, timing::TimestampStyle};
+
+    use super::*;
+
+    fn export_string(messages: Vec<SerialStateMessage>, format: ExportFormat) -> String {
+        let mut out: Vec<u8> = vec![];
+        write_export(&mut out, messages.into_iter(), format, &Default::default()).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn sample() -> Vec<SerialStateMessage> {
+        vec![
+            SerialStateMessage::Started,
+            SerialStateMessage::DataEvent(HistoryEntry {
+                rx_tx: RxTx::Tx,
+                data: b"AT,\r".to_vec(),
+                ..Default::default()
+            }),
+        ]
+    }
+
+    #[test]
+    fn test_csv_export() {
+        let csv = export_string(sample(), ExportFormat::Csv);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "timestamp,direction,hex,ascii");
+        assert_eq!(lines[1], ",,,--- Started ---");
+        assert!(lines[2].ends_with(",TX,41 54 2C 0D,\"AT,.\""));
+    }
+
+    #[test]
+    fn test_csv_escapes_custom_timestamps() {
+        let settings = TimestampSettings {
+            style: TimestampStyle::Custom,
+            custom_format: "%d.%m., \"%H\"".to_string(),
+            ..Default::default()
+        };
+        let mut out: Vec<u8> = vec![];
+        write_export(&mut out, sample().into_iter(), ExportFormat::Csv, &settings).unwrap();
+        let csv = String::from_utf8(out).unwrap();
+        let (timestamp, rest) = csv.lines().nth(2).unwrap().split_once(",TX,").unwrap();
+        assert!(timestamp.starts_with('"') && timestamp.ends_with("\"\"\""));
+        assert!(timestamp.contains("., \"\""));
+        assert_eq!(rest, "41 54 2C 0D,\"AT,.\"");
+    }
+
+    #[test]
+    fn test_json_export_is_valid_json() {
+        let json = export_string(sample(), ExportFormat::Json);
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value[0]["event"], "started");
+        assert_eq!(value[1]["hex"], "41 54 2C 0D");
+    }
+
+    #[test]
+    fn test_hexdump_export() {
+        let dump = export_string(sample(), ExportFormat::HexDump);
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(
+            lines[1],
+            "00000000: 4154 2c0d                                AT,."
+        );
+    }
+
+    #[test]
+    fn test_c_array_and_python_export() {
+        let c = export_string(sample(), ExportFormat::CArray);
+        assert!(c.contains("const unsigned char tx_0[4] = {\n    0x41, 0x54, 0x2C, 0x0D,\n};"));
+        let py = export_string(sample(), ExportFormat::PythonBytes);
+        assert!(py.contains("tx_0 = b\"\\x41\\x54\\x2c\\x0d\""));
+    }
+
+    #[test]
+    fn test_c_array_export_skips_empty_entries() {
+        let mut messages = vec![SerialStateMessage::DataEvent(HistoryEntry {
+            rx_tx: RxTx::Tx,
+            ..Default::default()
+        })];
+        messages.extend(sample());
+        let c = export_string(messages, ExportFormat::CArray);
+        assert!(!c.contains("[0]"));
+        assert!(c.contains("const unsigned char tx_0[4] = {"));
+    }
+
+    #[test]
+    fn test_annotations_survive_export() {
+        let mut messages = vec![SerialStateMessage::Annotation(Annotation::new(
+            "reset, then\nflashed".to_string(),
+        ))];
+        messages.extend(sample());
+
+        let csv = export_string(messages.clone(), ExportFormat::Csv);
+        assert!(csv.contains(",note,,\"reset, then\nflashed\"\n"));
+        let json = export_string(messages.clone(), ExportFormat::Json);
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value[0]["annotation"], "reset, then\nflashed");
+        let py = export_string(messages, ExportFormat::PythonBytes);
+        let lines: Vec<&str> = py.lines().collect();
+        assert!(lines[0].starts_with("# ") && lines[0].ends_with(" note: reset, then flashed"));
+        assert!(lines[2].starts_with("tx_0 = "));
+    }
+}
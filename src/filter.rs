@@ -0,0 +1,232 @@
+use chrono::NaiveTime;
+use snafu::{prelude::*, Whatever};
+
+use crate::{
+    checksum::RxValidation,
+    portthread::{RxTx, SerialStateMessage},
+    search::SearchQuery,
+};
+
+pub const DIRECTION_FILTERS: [Option<RxTx>; 3] = [None, Some(RxTx::Rx), Some(RxTx::Tx)];
+
+/// An inclusive range of times of day, either end may be open.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimeRange {
+    pub from: Option<NaiveTime>,
+    pub to: Option<NaiveTime>,
+}
+
+impl TimeRange {
+    /// Parses ranges like `10:00:00-10:05:30.5`, `10:00-` or `-10:05`.
+    pub fn parse(input: &str) -> Result<TimeRange, Whatever> {
+        let Some((from, to)) = input.split_once('-') else {
+            whatever!("Time range '{}' must have the form from-to", input);
+        };
+        let parse_time = |time: &str| -> Result<Option<NaiveTime>, Whatever> {
+            let time = time.trim();
+            if time.is_empty() {
+                return Ok(None);
+            }
+            NaiveTime::parse_from_str(time, "%H:%M:%S%.f")
+                .or_else(|_| NaiveTime::parse_from_str(time, "%H:%M"))
+                .map(Some)
+                .with_whatever_context(|_| format!("Invalid time '{}'", time))
+        };
+        Ok(TimeRange {
+            from: parse_time(from)?,
+            to: parse_time(to)?,
+        })
+    }
+
+    fn contains(&self, time: NaiveTime) -> bool {
+        self.from.is_none_or(|from| from <= time) && self.to.is_none_or(|to| time <= to)
+    }
+}
+
+/// Restricts the history to the entries the user is interested in. All criteria
+/// that are set have to match.
+#[derive(Debug, Clone, Default)]
+pub struct HistoryFilter {
+    /// Only data entries of this direction.
+    pub direction: Option<RxTx>,
+    /// Only data or error entries containing a match, together with the input
+    /// the query was created from.
+    pub pattern: Option<(String, SearchQuery)>,
+    /// Only data entries received or sent within this time range.
+    pub time_range: Option<(String, TimeRange)>,
+    /// Only errors, annotations and start/stop markers.
+    pub markers_only: bool,
+    /// Only data entries longer than this number of bytes.
+    pub min_len: Option<usize>,
+    /// Only RX data entries with a bad checksum, according to this validation.
+    pub bad_checksum: Option<RxValidation>,
+}
+
+impl HistoryFilter {
+    pub fn is_active(&self) -> bool {
+        self.direction.is_some()
+            || self.pattern.is_some()
+            || self.time_range.is_some()
+            || self.markers_only
+            || self.min_len.is_some()
+            || self.bad_checksum.is_some()
+    }
+
+    pub fn matches(&self, msg: &SerialStateMessage) -> bool {
+        match msg {
+            SerialStateMessage::DataEvent(entry) => {
+                !self.markers_only
+                    && self.direction.as_ref().is_none_or(|d| *d == entry.rx_tx)
+                    && self.min_len.is_none_or(|len| entry.data.len() > len)
+                    && self.bad_checksum.is_none_or(|validation| {
+                        entry.rx_tx == RxTx::Rx && validation.validate(&entry.data) == Some(false)
+                    })
+                    && self
+                        .time_range
+                        .as_ref()
+                        .is_none_or(|(_, range)| range.contains(entry.timestamp.time()))
+                    && self
+                        .pattern
+                        .as_ref()
+                        .is_none_or(|(_, query)| query.is_match(&entry.data))
+            }
+            SerialStateMessage::Annotation(note) => {
+                self.direction.is_none()
+                    && self.min_len.is_none()
+                    && self.bad_checksum.is_none()
+                    && self
+                        .time_range
+                        .as_ref()
+                        .is_none_or(|(_, range)| range.contains(note.timestamp.time()))
+                    && self
+                        .pattern
+                        .as_ref()
+                        .is_none_or(|(_, query)| query.is_match(note.text.as_bytes()))
+            }
+            // markers carry no timestamp, so the time range does not apply to them:
+            SerialStateMessage::ErrorEvent(err) => {
+                self.direction.is_none()
+                    && self.min_len.is_none()
+                    && self.bad_checksum.is_none()
+                    && self
+                        .pattern
+                        .as_ref()
+                        .is_none_or(|(_, query)| query.is_match(err.as_bytes()))
+            }
+            SerialStateMessage::Started | SerialStateMessage::Stopped => {
+                self.direction.is_none()
+                    && self.min_len.is_none()
+                    && self.pattern.is_none()
+                    && self.bad_checksum.is_none()
+            }
+        }
+    }
+
+    /// A short description of the active criteria, e.g. `RX, /OK/, >4 bytes`.
+    pub fn description(&self) -> String {
+        let mut parts = vec![];
+        if let Some(direction) = &self.direction {
+            parts.push(direction.to_string());
+        }
+        if let Some((text, _)) = &self.pattern {
+            parts.push(format!("/{}/", text));
+        }
+        if let Some((text, _)) = &self.time_range {
+            parts.push(text.clone());
+        }
+        if self.markers_only {
+            parts.push("markers".to_string());
+        }
+        if let Some(len) = self.min_len {
+            parts.push(format!(">{} bytes", len));
+        }
+        if self.bad_checksum.is_some() {
+            parts.push("bad checksum".to_string());
+        }
+        parts.join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Local, TimeZone};
+
+    use crate::{checksum::Checksum, portthread::HistoryEntry, search::SearchKind};
+
+    use super::*;
+
+    fn data(rx_tx: RxTx, hour: u32, data: &[u8]) -> SerialStateMessage {
+        SerialStateMessage::DataEvent(HistoryEntry {
+            timestamp: Local.with_ymd_and_hms(2024, 1, 2, hour, 0, 0).unwrap(),
+            rx_tx,
+            data: data.to_vec(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_filters_combine() {
+        let filter = HistoryFilter {
+            direction: Some(RxTx::Rx),
+            pattern: Some((
+                "OK".to_string(),
+                SearchQuery::parse(SearchKind::Text, "OK").unwrap(),
+            )),
+            ..Default::default()
+        };
+        assert!(filter.is_active());
+        assert!(filter.matches(&data(RxTx::Rx, 10, b"OK\r\n")));
+        assert!(!filter.matches(&data(RxTx::Tx, 10, b"OK\r\n")));
+        assert!(!filter.matches(&data(RxTx::Rx, 10, b"ERROR")));
+        assert!(!filter.matches(&SerialStateMessage::Started));
+        assert_eq!(filter.description(), "RX, /OK/");
+    }
+
+    #[test]
+    fn test_markers_length_and_time_range() {
+        let markers = HistoryFilter {
+            markers_only: true,
+            ..Default::default()
+        };
+        assert!(markers.matches(&SerialStateMessage::Stopped));
+        assert!(markers.matches(&SerialStateMessage::ErrorEvent("x".to_string())));
+        assert!(!markers.matches(&data(RxTx::Rx, 10, b"x")));
+
+        let length = HistoryFilter {
+            min_len: Some(2),
+            ..Default::default()
+        };
+        assert!(!length.matches(&data(RxTx::Rx, 10, b"ab")));
+        assert!(length.matches(&data(RxTx::Rx, 10, b"abc")));
+
+        let range = TimeRange::parse("09:30-10:00:00").unwrap();
+        let time = HistoryFilter {
+            time_range: Some(("09:30-10:00:00".to_string(), range)),
+            ..Default::default()
+        };
+        assert!(time.matches(&data(RxTx::Rx, 10, b"a")));
+        assert!(!time.matches(&data(RxTx::Rx, 11, b"a")));
+        assert!(time.matches(&SerialStateMessage::Started));
+
+        let bad = HistoryFilter {
+            bad_checksum: Some(RxValidation {
+                checksum: Some(Checksum::Sum8),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert!(bad.matches(&data(RxTx::Rx, 10, &[1, 2, 4])));
+        assert!(!bad.matches(&data(RxTx::Rx, 10, &[1, 2, 3])));
+        assert!(!bad.matches(&data(RxTx::Tx, 10, &[1, 2, 4])));
+        assert!(!bad.matches(&SerialStateMessage::Started));
+    }
+
+    #[test]
+    fn test_parse_time_range() {
+        let range = TimeRange::parse("-12:00:01.5").unwrap();
+        assert_eq!(range.from, None);
+        assert_eq!(range.to, NaiveTime::from_hms_milli_opt(12, 0, 1, 500));
+        assert!(TimeRange::parse("12:00").is_err());
+        assert!(TimeRange::parse("25:00-").is_err());
+    }
+}
@@ -0,0 +1,223 @@
+use std::{fmt::Display, str::FromStr};
+
+use ratatui::style::{Color, Modifier, Style};
+use serde::{Deserialize, Serialize};
+
+use crate::search::{SearchKind, SearchQuery};
+
+/// The colors the rules editor rotates through. Any color ratatui can parse,
+/// e.g. `LightRed` or `#FF8000`, may be used in the .klemme file.
+pub const HIGHLIGHT_COLORS: [Option<Color>; 10] = [
+    None,
+    Some(Color::Red),
+    Some(Color::Green),
+    Some(Color::Yellow),
+    Some(Color::Blue),
+    Some(Color::Magenta),
+    Some(Color::Cyan),
+    Some(Color::White),
+    Some(Color::Black),
+    Some(Color::DarkGray),
+];
+
+pub const HIGHLIGHT_MODIFIERS: [HighlightModifier; 5] = [
+    HighlightModifier::None,
+    HighlightModifier::Bold,
+    HighlightModifier::Dim,
+    HighlightModifier::Italic,
+    HighlightModifier::Underlined,
+];
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum HighlightModifier {
+    #[default]
+    None,
+    Bold,
+    Dim,
+    Italic,
+    Underlined,
+}
+
+impl Display for HighlightModifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HighlightModifier::None => write!(f, "None"),
+            HighlightModifier::Bold => write!(f, "Bold"),
+            HighlightModifier::Dim => write!(f, "Dim"),
+            HighlightModifier::Italic => write!(f, "Italic"),
+            HighlightModifier::Underlined => write!(f, "Underlined"),
+        }
+    }
+}
+
+/// Styles all bytes matching `pattern`, or the whole entry if `whole_line` is set.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HighlightRule {
+    pub kind: SearchKind,
+    pub pattern: String,
+    #[serde(default)]
+    pub fg: Option<String>,
+    #[serde(default)]
+    pub bg: Option<String>,
+    #[serde(default)]
+    pub modifier: HighlightModifier,
+    #[serde(default)]
+    pub whole_line: bool,
+}
+
+fn parse_color(color: &Option<String>) -> Option<Color> {
+    color.as_ref().and_then(|c| Color::from_str(c).ok())
+}
+
+/// Returns the name of the color following `color` in `HIGHLIGHT_COLORS`.
+fn next_color(color: &Option<String>) -> Option<String> {
+    let mut selected_index = HIGHLIGHT_COLORS
+        .iter()
+        .position(|x| *x == parse_color(color))
+        .unwrap_or(0);
+    selected_index += 1;
+    selected_index %= HIGHLIGHT_COLORS.len();
+    HIGHLIGHT_COLORS[selected_index].map(|c| c.to_string())
+}
+
+impl HighlightRule {
+    pub fn new(kind: SearchKind, pattern: String) -> HighlightRule {
+        HighlightRule {
+            kind,
+            pattern,
+            fg: Some(Color::Red.to_string()),
+            bg: None,
+            modifier: HighlightModifier::None,
+            whole_line: false,
+        }
+    }
+
+    pub fn style(&self) -> Style {
+        let mut style = Style::new();
+        if let Some(fg) = parse_color(&self.fg) {
+            style = style.fg(fg);
+        }
+        if let Some(bg) = parse_color(&self.bg) {
+            style = style.bg(bg);
+        }
+        match self.modifier {
+            HighlightModifier::None => style,
+            HighlightModifier::Bold => style.add_modifier(Modifier::BOLD),
+            HighlightModifier::Dim => style.add_modifier(Modifier::DIM),
+            HighlightModifier::Italic => style.add_modifier(Modifier::ITALIC),
+            HighlightModifier::Underlined => style.add_modifier(Modifier::UNDERLINED),
+        }
+    }
+
+    pub fn rotate_fg(&mut self) {
+        self.fg = next_color(&self.fg);
+    }
+
+    pub fn rotate_bg(&mut self) {
+        self.bg = next_color(&self.bg);
+    }
+
+    pub fn rotate_modifier(&mut self) {
+        let mut selected_index = HIGHLIGHT_MODIFIERS
+            .iter()
+            .position(|x| *x == self.modifier)
+            .unwrap_or(0);
+        selected_index += 1;
+        selected_index %= HIGHLIGHT_MODIFIERS.len();
+        self.modifier = HIGHLIGHT_MODIFIERS[selected_index];
+    }
+}
+
+impl Display for HighlightRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let color = |c: &Option<String>| c.clone().unwrap_or("-".to_string());
+        write!(
+            f,
+            "{} '{}' fg:{} bg:{} {}{}",
+            self.kind,
+            self.pattern,
+            color(&self.fg),
+            color(&self.bg),
+            self.modifier,
+            if self.whole_line { " line" } else { "" }
+        )
+    }
+}
+
+/// The compiled highlight rules. Rules with an invalid pattern are ignored.
+#[derive(Debug, Default)]
+pub struct Highlighter {
+    rules: Vec<(SearchQuery, Style, bool)>,
+}
+
+impl Highlighter {
+    pub fn new(rules: &[HighlightRule]) -> Highlighter {
+        Highlighter {
+            rules: rules
+                .iter()
+                .filter_map(|rule| {
+                    let query = SearchQuery::parse(rule.kind, &rule.pattern).ok()?;
+                    Some((query, rule.style(), rule.whole_line))
+                })
+                .collect(),
+        }
+    }
+
+    /// Returns the style of every byte of `data`. Later rules take precedence
+    /// over earlier ones.
+    pub fn styles(&self, data: &[u8], base: Style) -> Vec<Style> {
+        let mut styles = vec![base; data.len()];
+        for (query, style, whole_line) in &self.rules {
+            if *whole_line {
+                if query.is_match(data) {
+                    styles.iter_mut().for_each(|s| *s = s.patch(*style));
+                }
+                continue;
+            }
+            for range in query.find_all(data) {
+                styles[range].iter_mut().for_each(|s| *s = s.patch(*style));
+            }
+        }
+        styles
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rules_style_matches_and_lines() {
+        let mut sync = HighlightRule::new(SearchKind::Hex, "AA 55".to_string());
+        sync.fg = None;
+        sync.modifier = HighlightModifier::Bold;
+        let error = HighlightRule {
+            whole_line: true,
+            ..HighlightRule::new(SearchKind::Regex, "ERR".to_string())
+        };
+        let invalid = HighlightRule::new(SearchKind::Hex, "A".to_string());
+        let highlighter = Highlighter::new(&[sync, error, invalid]);
+
+        let base = Style::new().fg(Color::Gray);
+        let bold = base.add_modifier(Modifier::BOLD);
+        assert_eq!(
+            highlighter.styles(&[0x00, 0xAA, 0x55], base),
+            vec![base, bold, bold]
+        );
+        let styles = highlighter.styles(b"\xAA\x55ERR", base);
+        assert_eq!(styles[0], bold.fg(Color::Red));
+        assert_eq!(styles[4], base.fg(Color::Red));
+    }
+
+    #[test]
+    fn test_rotate_colors() {
+        let mut rule = HighlightRule::new(SearchKind::Text, "x".to_string());
+        rule.fg = Some("light-red".to_string());
+        rule.rotate_fg();
+        assert_eq!(rule.fg, Some("Red".to_string()));
+        rule.bg = Some("DarkGray".to_string());
+        rule.rotate_bg();
+        assert_eq!(rule.bg, None);
+        assert_eq!(rule.style(), Style::new().fg(Color::Red));
+    }
+}
@@ -0,0 +1,430 @@
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use snafu::{prelude::*, Whatever};
+
+use crate::portthread::SerialStateMessage;
+
+/// Number of entries a disk backed history keeps in memory. Everything older
+/// than this is read back from the history file on demand.
+const HISTORY_WINDOW: usize = 512;
+
+/// Size of a single record in the index file (the offset of the record in the
+/// data file as little endian u64).
+const INDEX_RECORD_SIZE: u64 = 8;
+
+/// Size of the length prefix of each record in the data file.
+const LENGTH_PREFIX_SIZE: u64 = 4;
+
+/// The on-disk part of a history.
+///
+/// The store consists of two files:
+/// * the data file, which contains one record per entry. Each record is a little
+///   endian u32 length, followed by the JSON serialized `SerialStateMessage`.
+/// * the index file, which contains the offset of each record in the data file
+///   as little endian u64.
+///
+/// Records are always written to the data file before their offset is appended
+/// to the index, so a crash can only ever leave an unindexed tail in the data file
+/// or a partial index record, both of which are dropped when the store is reopened.
+///
+/// The index file is locked exclusively while the store is open.
+#[derive(Debug)]
+struct HistoryStore {
+    data_path: PathBuf,
+    index_path: PathBuf,
+    data: File,
+    index: File,
+    data_len: u64,
+}
+
+impl HistoryStore {
+    fn open(path: &Path) -> Result<(HistoryStore, usize), Whatever> {
+        let data_path = path.to_path_buf();
+        let mut index_path = data_path.clone().into_os_string();
+        index_path.push(".idx");
+        let index_path = PathBuf::from(index_path);
+
+        let data = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&data_path)
+            .with_whatever_context(|_| format!("Failed to open {}", data_path.display()))?;
+        let index = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&index_path)
+            .with_whatever_context(|_| format!("Failed to open {}", index_path.display()))?;
+        // Another klemme instance appending to the same files would corrupt the
+        // offsets of both, so only one instance may own the store at a time.
+        // The lock is released when the index file is closed.
+        index.try_lock().with_whatever_context(|_| {
+            format!(
+                "History {} is in use by another instance",
+                data_path.display()
+            )
+        })?;
+
+        let mut store = HistoryStore {
+            data_path,
+            index_path,
+            data,
+            index,
+            data_len: 0,
+        };
+        let len = store.recover()?;
+        Ok((store, len))
+    }
+
+    /// Drops all records that were not completely written, e.g. because klemme
+    /// crashed while appending, and returns the number of valid records.
+    fn recover(&mut self) -> Result<usize, Whatever> {
+        let file_len = self
+            .data
+            .metadata()
+            .whatever_context("Failed to read history file size")?
+            .len();
+        let index_len = self
+            .index
+            .metadata()
+            .whatever_context("Failed to read history index size")?
+            .len();
+
+        let mut num_records = (index_len / INDEX_RECORD_SIZE) as usize;
+        let mut data_len = 0;
+        while num_records > 0 {
+            if let Some(end) = self.record_end(num_records - 1, file_len) {
+                data_len = end;
+                break;
+            }
+            num_records -= 1;
+        }
+
+        self.index
+            .set_len(num_records as u64 * INDEX_RECORD_SIZE)
+            .whatever_context("Failed to truncate history index")?;
+        self.data
+            .set_len(data_len)
+            .whatever_context("Failed to truncate history file")?;
+        self.data_len = data_len;
+        Ok(num_records)
+    }
+
+    /// Returns the offset just behind the record at `index`, if the record
+    /// is fully contained in a data file of `file_len` bytes.
+    fn record_end(&self, index: usize, file_len: u64) -> Option<u64> {
+        let offset = self.read_offset(index)?;
+        let mut prefix = [0u8; LENGTH_PREFIX_SIZE as usize];
+        let mut data = &self.data;
+        data.seek(SeekFrom::Start(offset)).ok()?;
+        data.read_exact(&mut prefix).ok()?;
+        let end = offset + LENGTH_PREFIX_SIZE + u32::from_le_bytes(prefix) as u64;
+        if end > file_len {
+            return None;
+        }
+        Some(end)
+    }
+
+    fn read_offset(&self, index: usize) -> Option<u64> {
+        let mut offset = [0u8; INDEX_RECORD_SIZE as usize];
+        let mut idx = &self.index;
+        idx.seek(SeekFrom::Start(index as u64 * INDEX_RECORD_SIZE))
+            .ok()?;
+        idx.read_exact(&mut offset).ok()?;
+        Some(u64::from_le_bytes(offset))
+    }
+
+    fn read(&self, index: usize) -> Option<SerialStateMessage> {
+        let offset = self.read_offset(index)?;
+        let mut data = &self.data;
+        data.seek(SeekFrom::Start(offset)).ok()?;
+        let mut prefix = [0u8; LENGTH_PREFIX_SIZE as usize];
+        data.read_exact(&mut prefix).ok()?;
+        let mut record = vec![0u8; u32::from_le_bytes(prefix) as usize];
+        data.read_exact(&mut record).ok()?;
+        serde_json::from_slice(&record).ok()
+    }
+
+    fn append(&mut self, msg: &SerialStateMessage) -> Result<(), Whatever> {
+        let record = serde_json::to_vec(msg).whatever_context("Failed to serialize entry")?;
+        let mut buffer = Vec::with_capacity(record.len() + LENGTH_PREFIX_SIZE as usize);
+        buffer.extend_from_slice(&(record.len() as u32).to_le_bytes());
+        buffer.extend_from_slice(&record);
+
+        self.data
+            .seek(SeekFrom::Start(self.data_len))
+            .whatever_context("Failed to seek history file")?;
+        self.data
+            .write_all(&buffer)
+            .whatever_context("Failed to write history file")?;
+        self.index
+            .seek(SeekFrom::End(0))
+            .whatever_context("Failed to seek history index")?;
+        self.index
+            .write_all(&self.data_len.to_le_bytes())
+            .whatever_context("Failed to write history index")?;
+        self.data_len += buffer.len() as u64;
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<(), Whatever> {
+        self.data
+            .set_len(0)
+            .with_whatever_context(|_| format!("Failed to clear {}", self.data_path.display()))?;
+        self.index
+            .set_len(0)
+            .with_whatever_context(|_| format!("Failed to clear {}", self.index_path.display()))?;
+        self.data_len = 0;
+        Ok(())
+    }
+}
+
+/// The history of all messages received from the port thread.
+///
+/// An in-memory history keeps all entries in memory. A disk backed history
+/// (see `History::open`) appends every entry to a history file and only keeps
+/// the most recent `HISTORY_WINDOW` entries in memory, older entries are read
+/// back from disk when they are accessed. This keeps the memory footprint
+/// constant, regardless of how long a capture runs.
+#[derive(Debug, Default)]
+pub struct History {
+    window: VecDeque<SerialStateMessage>,
+    len: usize,
+    store: Option<HistoryStore>,
+}
+
+impl History {
+    /// Opens (or creates) the disk backed history at `path`. If the file contains
+    /// entries of a previous session, these are kept and become part of the history.
+    pub fn open(path: &Path) -> Result<History, Whatever> {
+        let (store, len) = HistoryStore::open(path)?;
+        let window = (len.saturating_sub(HISTORY_WINDOW)..len)
+            .filter_map(|i| store.read(i))
+            .collect();
+
+        Ok(History {
+            window,
+            len,
+            store: Some(store),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Index of the first entry that is held in memory.
+    fn window_start(&self) -> usize {
+        self.len - self.window.len()
+    }
+
+    /// Index of the first entry that can still be read. Once the store failed,
+    /// the entries that were only on disk are gone, but the indices of the
+    /// remaining entries stay the same.
+    fn first_available(&self) -> usize {
+        if self.store.is_some() {
+            0
+        } else {
+            self.window_start()
+        }
+    }
+
+    pub fn push(&mut self, msg: SerialStateMessage) {
+        if let Some(store) = &mut self.store {
+            if store.append(&msg).is_err() {
+                // The disk is no longer usable, keep going in memory only
+                // so that no further data is lost.
+                self.store = None;
+            }
+        }
+
+        self.window.push_back(msg);
+        self.len += 1;
+
+        if self.store.is_some() && self.window.len() > HISTORY_WINDOW {
+            self.window.pop_front();
+        }
+    }
+
+    /// Returns the entry at `index`, where 0 is the oldest entry.
+    pub fn get(&self, index: usize) -> Option<SerialStateMessage> {
+        if index >= self.len {
+            return None;
+        }
+        if index >= self.window_start() {
+            return self.window.get(index - self.window_start()).cloned();
+        }
+        self.store.as_ref()?.read(index)
+    }
+
+    /// Iterates over all entries from oldest to newest, together with their
+    /// index. Entries that are not held in memory are read from disk as the
+    /// iterator advances. Entries that can't be read, e.g. corrupt records or
+    /// entries lost with a failed store, are skipped, so the indices may have gaps.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (usize, SerialStateMessage)> + '_ {
+        (self.first_available()..self.len).filter_map(|i| self.get(i).map(|msg| (i, msg)))
+    }
+
+    pub fn clear(&mut self) {
+        if let Some(store) = &mut self.store {
+            if store.clear().is_err() {
+                self.store = None;
+            }
+        }
+        self.window.clear();
+        self.window.shrink_to_fit();
+        self.len = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::portthread::{HistoryEntry, RxTx};
+
+    use super::*;
+
+    fn temp_history_path() -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let mut path = std::env::temp_dir();
+        path.push(format!(
+            "klemme_history_test_{}_{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        path
+    }
+
+    fn remove_history(path: &Path) {
+        let _ = std::fs::remove_file(path);
+        let mut idx = path.to_path_buf().into_os_string();
+        idx.push(".idx");
+        let _ = std::fs::remove_file(idx);
+    }
+
+    fn data_message(value: u8) -> SerialStateMessage {
+        SerialStateMessage::DataEvent(HistoryEntry {
+            rx_tx: RxTx::Rx,
+            data: vec![value],
+            ..Default::default()
+        })
+    }
+
+    fn data_of(msg: Option<SerialStateMessage>) -> Vec<u8> {
+        match msg {
+            Some(SerialStateMessage::DataEvent(entry)) => entry.data,
+            _ => panic!("Expected a data event"),
+        }
+    }
+
+    #[test]
+    fn test_entries_outside_window_are_read_from_disk() {
+        let path = temp_history_path();
+        let mut history = History::open(&path).unwrap();
+        for i in 0..(HISTORY_WINDOW + 10) {
+            history.push(data_message(i as u8));
+        }
+
+        assert_eq!(history.window.len(), HISTORY_WINDOW);
+        assert_eq!(history.len(), HISTORY_WINDOW + 10);
+        assert_eq!(data_of(history.get(0)), vec![0]);
+        assert_eq!(data_of(history.get(9)), vec![9]);
+        assert_eq!(
+            data_of(history.get(HISTORY_WINDOW + 9)),
+            vec![(HISTORY_WINDOW + 9) as u8]
+        );
+        assert_eq!(history.get(HISTORY_WINDOW + 10), None);
+        remove_history(&path);
+    }
+
+    #[test]
+    fn test_reopen_drops_partially_written_record() {
+        let path = temp_history_path();
+        {
+            let mut history = History::open(&path).unwrap();
+            history.push(data_message(1));
+            history.push(SerialStateMessage::Started);
+            history.push(data_message(2));
+        }
+
+        // simulate a crash while writing the last record:
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        let len = file.metadata().unwrap().len();
+        file.set_len(len - 3).unwrap();
+
+        let mut history = History::open(&path).unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.get(1), Some(SerialStateMessage::Started));
+
+        history.push(data_message(3));
+        drop(history);
+        let history = History::open(&path).unwrap();
+        assert_eq!(history.len(), 3);
+        assert_eq!(data_of(history.get(2)), vec![3]);
+        remove_history(&path);
+    }
+
+    #[test]
+    fn test_failed_store_keeps_indices() {
+        let path = temp_history_path();
+        let mut history = History::open(&path).unwrap();
+        for i in 0..(HISTORY_WINDOW + 10) {
+            history.push(data_message(i as u8));
+        }
+
+        // a read only handle makes the next append fail, like a full disk:
+        history.store.as_mut().unwrap().data = File::open(&path).unwrap();
+        history.push(data_message(0xFF));
+        assert!(history.store.is_none());
+        assert_eq!(history.len(), HISTORY_WINDOW + 11);
+
+        // the entries only on disk are gone, the others keep their index:
+        assert_eq!(history.get(0), None);
+        assert_eq!(data_of(history.get(10)), vec![10]);
+        assert_eq!(data_of(history.get(HISTORY_WINDOW + 10)), vec![0xFF]);
+        let entries: Vec<(usize, SerialStateMessage)> = history.iter().collect();
+        assert_eq!(entries.len(), HISTORY_WINDOW + 1);
+        for (index, msg) in entries {
+            assert_eq!(history.get(index), Some(msg));
+        }
+        remove_history(&path);
+    }
+
+    #[test]
+    fn test_clear_truncates_store() {
+        let path = temp_history_path();
+        let mut history = History::open(&path).unwrap();
+        history.push(data_message(1));
+        history.clear();
+        assert_eq!(history.len(), 0);
+
+        drop(history);
+        let history = History::open(&path).unwrap();
+        assert_eq!(history.len(), 0);
+        remove_history(&path);
+    }
+
+    #[test]
+    fn test_store_is_exclusive() {
+        let path = temp_history_path();
+        let mut history = History::open(&path).unwrap();
+        history.push(data_message(1));
+
+        assert!(History::open(&path).is_err());
+        assert_eq!(history.len(), 1);
+
+        drop(history);
+        let history = History::open(&path).unwrap();
+        assert_eq!(data_of(history.get(0)), vec![1]);
+        remove_history(&path);
+    }
+}
@@ -1,6 +1,6 @@
 use std::{fmt::Display, sync::mpsc::Sender};
 
-use crossterm::event::KeyCode;
+use crossterm::event::{KeyCode, KeyModifiers};
 use ratatui::{
     style::{Style, Stylize},
     symbols::border,
@@ -8,7 +8,11 @@ use ratatui::{
     widgets::{Block, Paragraph},
 };
 
-use crate::{mode::ApplicationMode, portthread::SerialCommand};
+use crate::{
+    checksum::{Checksum, CHECKSUM_ENDS, CHECKSUM_STARTS},
+    mode::ApplicationMode,
+    portthread::SerialCommand,
+};
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum InputMode {
@@ -18,6 +22,7 @@ pub enum InputMode {
 
 const INPUT_MODES: [InputMode; 2] = [InputMode::Default, InputMode::Hex];
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum CRLFSetting {
     None,
@@ -33,6 +38,14 @@ const CRLF_SETTINGS: [CRLFSetting; 4] = [
     CRLFSetting::CRLF,
 ];
 
+const TX_CHECKSUMS: [Option<Checksum>; 6] = [
+    None,
+    Some(Checksum::Xor8),
+    Some(Checksum::Sum8),
+    Some(Checksum::Crc16Modbus),
+    Some(Checksum::Crc16Ccitt),
+    Some(Checksum::Crc32),
+];
 
 impl Display for CRLFSetting {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -62,14 +75,40 @@ pub struct InteractiveMode {
     command_sender: Sender<SerialCommand>,
     crlf: CRLFSetting,
     retain_input: bool,
+    /// Checksum appended to every frame sent, before the line ending.
+    tx_checksum: Option<Checksum>,
+    tx_checksum_big_endian: bool,
+    tx_checksum_start: usize,
+    /// Number of bytes at the end of the input the checksum does not cover.
+    tx_checksum_end: usize,
 }
 
 impl ApplicationMode for InteractiveMode {
     fn handle_key_event(&mut self, key_event: crossterm::event::KeyEvent) {
+        // AltGr arrives as Ctrl+Alt on Windows, so characters typed with it must
+        // not be taken for shortcuts:
+        if key_event.modifiers == KeyModifiers::CONTROL {
+            match key_event.code {
+                KeyCode::Char('e') => {
+                    self.tx_checksum_big_endian = !self.tx_checksum_big_endian;
+                    return;
+                }
+                KeyCode::Char('r') => {
+                    self.rotate_tx_checksum_start();
+                    return;
+                }
+                KeyCode::Char('t') => {
+                    self.rotate_tx_checksum_end();
+                    return;
+                }
+                _ => {}
+            }
+        }
         match key_event.code {
             KeyCode::F(4) => self.rotate_crlf_setting(),
             KeyCode::F(3) => self.rotate_input_mode(),
             KeyCode::F(5) => self.toggle_retain_input(),
+            KeyCode::F(11) => self.rotate_tx_checksum(),
             KeyCode::Char(x) => {
                 if self.input_mode == InputMode::Hex {
                     if x.is_ascii_hexdigit() || x == ' ' {
@@ -100,15 +139,18 @@ impl ApplicationMode for InteractiveMode {
 
         let header = Line::from(vec![
             "TX".fg(ratatui::style::Color::LightGreen),
-            format!("(Input").fg(ratatui::style::Color::Gray),
+            "(Input".fg(ratatui::style::Color::Gray),
             "(F3)".fg(highlight_color),
             format!(": {} ", self.input_mode).fg(ratatui::style::Color::Gray),
-            format!("CRLF").fg(ratatui::style::Color::Gray),
+            "CRLF".fg(ratatui::style::Color::Gray),
             "(F4)".fg(highlight_color),
             format!(": {} ", self.crlf).fg(ratatui::style::Color::Gray),
-            format!("Retain Input").fg(ratatui::style::Color::Gray),
+            "Retain Input".fg(ratatui::style::Color::Gray),
             "(F5)".fg(highlight_color),
             format!(": {} ", self.retain_input).fg(ratatui::style::Color::Gray),
+            "Checksum".fg(ratatui::style::Color::Gray),
+            "(F11)".fg(highlight_color),
+            format!(": {} ", self.tx_checksum_description()).fg(ratatui::style::Color::Gray),
         ]);
 
         let block = Block::bordered()
@@ -135,6 +177,10 @@ impl InteractiveMode {
             command_sender,
             crlf: CRLFSetting::None,
             retain_input: false,
+            tx_checksum: None,
+            tx_checksum_big_endian: false,
+            tx_checksum_start: 0,
+            tx_checksum_end: 0,
         }
     }
 
@@ -180,6 +226,20 @@ impl InteractiveMode {
         input.clone()
     }
 
+    /// Appends the TX checksum, computed over the input from the configured start
+    /// offset up to the configured number of bytes before the end.
+    fn apply_tx_checksum(&self, input: Vec<u8>) -> Vec<u8> {
+        let Some(checksum) = self.tx_checksum else {
+            return input;
+        };
+        let end = input.len().saturating_sub(self.tx_checksum_end);
+        let covered = input.get(self.tx_checksum_start..end).unwrap_or_default();
+        let trailer = checksum.trailer(covered, self.tx_checksum_big_endian);
+        let mut res = input;
+        res.extend(trailer);
+        res
+    }
+
     /// Adds the necessary CRLF bytes to the send buffer according to the
     /// current CRLF setting.
     fn apply_crlf_setting(&mut self, input: Vec<u8>) -> Vec<u8> {
@@ -197,15 +257,16 @@ impl InteractiveMode {
             }
             _ => {}
         }
-        return res;
+        res
     }
 
     /// Sends the contents of the `send_buffer` to the serial port.
     /// The contents of `send_buffer` are processed according to the current
-    /// `input_mode` and `crlf` settings before being sent.
+    /// `input_mode`, `tx_checksum` and `crlf` settings before being sent.
     fn send_tx_buffer(&mut self) {
         let mut the_buffer = self.send_buffer.clone();
         the_buffer = self.apply_input_mode(the_buffer);
+        the_buffer = self.apply_tx_checksum(the_buffer);
         the_buffer = self.apply_crlf_setting(the_buffer);
         self.send_command(SerialCommand::Send(the_buffer));
         if !self.retain_input {
@@ -244,6 +305,76 @@ impl InteractiveMode {
         self.crlf = CRLF_SETTINGS[selected_idx];
     }
 
+    /// Replaces the send buffer by the given bytes. Bytes that cannot be typed are
+    /// only kept in hex input mode, so if there are any, the input mode is switched
+    /// to hex.
+    pub fn set_send_buffer(&mut self, bytes: &[u8]) {
+        let printable = bytes.iter().all(|b| (0x20..0x7f).contains(b));
+        if printable && self.input_mode != InputMode::Hex {
+            self.send_buffer = bytes.to_vec();
+            return;
+        }
+        self.input_mode = InputMode::Hex;
+        self.send_buffer = bytes
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<Vec<_>>()
+            .join(" ")
+            .into_bytes();
+    }
+
+    /// Rotates the checksum appended to every frame sent, starting with none.
+    fn rotate_tx_checksum(&mut self) {
+        let mut selected_idx = TX_CHECKSUMS
+            .iter()
+            .position(|&x| x == self.tx_checksum)
+            .unwrap_or(0);
+        selected_idx += 1;
+        selected_idx %= TX_CHECKSUMS.len();
+        self.tx_checksum = TX_CHECKSUMS[selected_idx];
+    }
+
+    fn rotate_tx_checksum_start(&mut self) {
+        let mut selected_idx = CHECKSUM_STARTS
+            .iter()
+            .position(|&x| x == self.tx_checksum_start)
+            .unwrap_or(0);
+        selected_idx += 1;
+        selected_idx %= CHECKSUM_STARTS.len();
+        self.tx_checksum_start = CHECKSUM_STARTS[selected_idx];
+    }
+
+    fn rotate_tx_checksum_end(&mut self) {
+        let mut selected_idx = CHECKSUM_ENDS
+            .iter()
+            .position(|&x| x == self.tx_checksum_end)
+            .unwrap_or(0);
+        selected_idx += 1;
+        selected_idx %= CHECKSUM_ENDS.len();
+        self.tx_checksum_end = CHECKSUM_ENDS[selected_idx];
+    }
+
+    fn tx_checksum_description(&self) -> String {
+        let Some(checksum) = self.tx_checksum else {
+            return "None".to_string();
+        };
+        let endianness = if checksum.len() == 1 {
+            ""
+        } else if self.tx_checksum_big_endian {
+            " BE"
+        } else {
+            " LE"
+        };
+        let mut description = format!("{}{}", checksum, endianness);
+        if self.tx_checksum_start > 0 {
+            description += &format!(" from byte {}", self.tx_checksum_start);
+        }
+        if self.tx_checksum_end > 0 {
+            description += &format!(" to end-{}", self.tx_checksum_end);
+        }
+        description
+    }
+
     fn send_command(&self, cmd: SerialCommand) {
         self.command_sender.send(cmd).unwrap();
     }
@@ -252,3 +383,70 @@ impl InteractiveMode {
         self.retain_input = !self.retain_input;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use crossterm::event::KeyEvent;
+
+    use super::*;
+
+    fn interactive_mode(checksum: Checksum) -> InteractiveMode {
+        let (sender, _) = mpsc::channel();
+        let mut mode = InteractiveMode::new(sender);
+        mode.tx_checksum = Some(checksum);
+        mode
+    }
+
+    #[test]
+    fn test_tx_checksum_algorithms() {
+        let frame = b"123456789".to_vec();
+        let cases = [
+            (Checksum::Xor8, vec![0x31]),
+            (Checksum::Sum8, vec![0xDD]),
+            (Checksum::Crc16Modbus, vec![0x37, 0x4B]),
+            (Checksum::Crc16Ccitt, vec![0xB1, 0x29]),
+            (Checksum::Crc32, vec![0x26, 0x39, 0xF4, 0xCB]),
+        ];
+        for (checksum, trailer) in cases {
+            let mut mode = interactive_mode(checksum);
+            let mut expected = frame.clone();
+            expected.extend(&trailer);
+            assert_eq!(mode.apply_tx_checksum(frame.clone()), expected, "{}", checksum);
+
+            mode.tx_checksum_big_endian = true;
+            expected.truncate(frame.len());
+            expected.extend(trailer.iter().rev());
+            assert_eq!(mode.apply_tx_checksum(frame.clone()), expected, "{}", checksum);
+        }
+    }
+
+    #[test]
+    fn test_tx_checksum_offsets() {
+        let mut mode = interactive_mode(Checksum::Sum8);
+        mode.tx_checksum_start = 1;
+        assert_eq!(mode.apply_tx_checksum(vec![0x02, 0x10, 0x20]), vec![0x02, 0x10, 0x20, 0x30]);
+        mode.tx_checksum_end = 1;
+        assert_eq!(mode.apply_tx_checksum(vec![0x02, 0x10, 0x20]), vec![0x02, 0x10, 0x20, 0x10]);
+        // offsets beyond the input leave nothing to cover:
+        mode.tx_checksum_end = 2;
+        assert_eq!(mode.apply_tx_checksum(vec![0x02, 0x10]), vec![0x02, 0x10, 0x00]);
+        assert_eq!(interactive_mode(Checksum::Xor8).apply_tx_checksum(vec![]), vec![0x00]);
+    }
+
+    #[test]
+    fn test_only_checksum_shortcuts_are_intercepted() {
+        let mut mode = interactive_mode(Checksum::Xor8);
+        mode.handle_key_event(KeyEvent::new(KeyCode::Char('e'), KeyModifiers::CONTROL));
+        assert!(mode.tx_checksum_big_endian);
+        assert!(mode.send_buffer.is_empty());
+
+        // AltGr+Q is '@' on a German keyboard:
+        let altgr = KeyModifiers::CONTROL | KeyModifiers::ALT;
+        mode.handle_key_event(KeyEvent::new(KeyCode::Char('@'), altgr));
+        mode.handle_key_event(KeyEvent::new(KeyCode::Char('e'), altgr));
+        assert_eq!(mode.send_buffer, b"@e");
+        assert!(mode.tx_checksum_big_endian);
+    }
+}
@@ -1,15 +1,21 @@
 use std::{
     fmt::Display,
     io,
+    path::{Path, PathBuf},
     thread::{self},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use history::History;
 use mode::{ApplicationMode, Mode};
-use portthread::{SerialCommand, SerialStateMessage};
+use portthread::{Annotation, PortError, SerialCommand, SerialContext, SerialStateMessage};
 use ratatui::{
     layout::{Constraint, Layout, Rect},
+    style::{Color, Stylize},
+    symbols::border,
+    text::Line,
+    widgets::{Block, Paragraph},
     DefaultTerminal, Frame,
 };
 use serde::{Deserialize, Serialize};
@@ -18,11 +24,35 @@ use std::sync::mpsc;
 use std::sync::mpsc::{Receiver, Sender};
 
 mod analyzer_mode;
+mod capturelog;
+mod checksum;
+mod clipboard;
+mod decode;
+mod export;
+mod filter;
+mod highlight;
+mod history;
 mod interactive_mode;
 mod mode;
+mod pattern;
+mod pcap;
 mod portthread;
+mod protocol;
+mod replay;
+mod search;
 mod serialtypes;
+mod session;
 mod settings_mode;
+mod simulator;
+mod stats;
+mod template;
+mod timing;
+mod traffic;
+
+const HISTORY_FILE: &str = ".klemme_history";
+
+/// Number of frames a replay moves on ctrl+left/right.
+const REPLAY_SEEK_FRAMES: isize = 10;
 
 const DISPLAY_MODES: [DisplayMode; 5] = [
     DisplayMode::Decimal,
@@ -53,9 +83,123 @@ impl Display for DisplayMode {
     }
 }
 
+const USAGE: &str = "Usage: klemme [--open <session file>] [--replay <session file> [--speed <factor>] [--step] [--pty]] [--simulate <rule file>] [--protocol <protocol file>]";
+
+/// Options given on the command line.
+#[derive(Debug, Default, PartialEq)]
+struct Arguments {
+    /// Session file to open for offline analysis.
+    open: Option<PathBuf>,
+    /// Session file to replay as virtual device.
+    replay: Option<PathBuf>,
+    replay_speed: Option<f64>,
+    /// Start the replay paused, so it can be stepped through frame by frame.
+    replay_step: bool,
+    /// Mirror the replay to a pseudo terminal.
+    replay_pty: bool,
+    /// Rule file of a simulated device to talk to.
+    simulate: Option<PathBuf>,
+    /// Protocol description, the frames in the history are decoded with.
+    protocol: Option<PathBuf>,
+}
+
+fn parse_args(args: &[String]) -> Result<Arguments, String> {
+    let mut arguments = Arguments::default();
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--open" | "-o" => {
+                let file = iter.next().ok_or("--open requires a session file")?;
+                arguments.open = Some(PathBuf::from(file));
+            }
+            "--replay" | "-r" => {
+                let file = iter.next().ok_or("--replay requires a session file")?;
+                arguments.replay = Some(PathBuf::from(file));
+            }
+            "--speed" => {
+                let speed = iter.next().ok_or("--speed requires a factor")?;
+                let speed = speed
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|s| *s > 0.0)
+                    .ok_or(format!("Invalid speed: {}", speed))?;
+                arguments.replay_speed = Some(speed);
+            }
+            "--step" => arguments.replay_step = true,
+            "--pty" => arguments.replay_pty = true,
+            "--simulate" => {
+                let file = iter.next().ok_or("--simulate requires a rule file")?;
+                arguments.simulate = Some(PathBuf::from(file));
+            }
+            "--protocol" | "-p" => {
+                let file = iter.next().ok_or("--protocol requires a protocol file")?;
+                arguments.protocol = Some(PathBuf::from(file));
+            }
+            _ => return Err(format!("Unknown argument: {}", arg)),
+        }
+    }
+    let sources = [
+        arguments.open.is_some(),
+        arguments.replay.is_some(),
+        arguments.simulate.is_some(),
+    ];
+    if sources.iter().filter(|x| **x).count() > 1 {
+        return Err("Only one of --open, --replay and --simulate can be used".to_string());
+    }
+    Ok(arguments)
+}
+
+/// Builds the app according to the command line.
+fn create_app(arguments: Arguments) -> Result<App, String> {
+    let protocol = match &arguments.protocol {
+        Some(path) => Some(protocol::Protocol::load(path).map_err(|e| e.to_string())?),
+        None => None,
+    };
+
+    if let Some(path) = arguments.open {
+        let session = session::load_session(&path).map_err(|e| e.to_string())?;
+        let mut app = App::from_session(session, &path);
+        app.analyzermode.set_protocol(protocol);
+        return Ok(app);
+    }
+
+    let mut app = App::default();
+    app.analyzermode.set_protocol(protocol);
+    if let Some(path) = arguments.replay {
+        let session = session::load_session(&path).map_err(|e| e.to_string())?;
+        let mut replay = replay::Replay::from_session(
+            &session,
+            arguments.replay_speed.unwrap_or(1.0),
+            arguments.replay_step,
+        );
+        if arguments.replay_pty {
+            replay.open_pty().map_err(|e| e.to_string())?;
+        }
+        app.replay = Some(replay);
+        app.enter_interactive_mode();
+    }
+    if let Some(path) = arguments.simulate {
+        let config = simulator::SimulatorConfig::load(&path).map_err(|e| e.to_string())?;
+        app.simulator = Some(config);
+        app.enter_interactive_mode();
+    }
+    Ok(app)
+}
+
 fn main() -> io::Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    let arguments = parse_args(&args).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        eprintln!("{}", USAGE);
+        std::process::exit(1);
+    });
+    let mut app = create_app(arguments).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+
     let mut terminal = ratatui::init();
-    let app_result = App::default().run(&mut terminal);
+    let app_result = app.run(&mut terminal);
     ratatui::restore();
     app_result
 }
@@ -69,27 +213,73 @@ pub struct App {
     settingsmode: settings_mode::SettingsMode,
     analyzermode: analyzer_mode::AnalyzerMode,
     interactivemode: interactive_mode::InteractiveMode,
+    capturelog: capturelog::CaptureLog,
+    /// Set, if a recorded session is replayed instead of opening the serial port.
+    replay: Option<replay::Replay>,
+    /// Set, if a simulated device is used instead of the serial port.
+    simulator: Option<simulator::SimulatorConfig>,
+    /// Traffic counters maintained by the port thread.
+    traffic: traffic::SharedTrafficStats,
 }
 
 impl Default for App {
     fn default() -> Self {
+        let settings = settings_mode::SettingsMode::new();
+
+        // keep the history on disk, so long captures don't eat up memory
+        // and survive a crash. Fall back to memory, if the CWD is gone or not writable
+        // or another instance already records its history there.
+        let history = std::env::current_dir()
+            .ok()
+            .and_then(|dir| History::open(&dir.join(HISTORY_FILE)).ok())
+            .unwrap_or_default();
+
+        App::new(settings, history)
+    }
+}
+
+impl App {
+    fn new(settings: settings_mode::SettingsMode, history: History) -> Self {
         let (stx, rtx): (Sender<SerialStateMessage>, Receiver<SerialStateMessage>) =
             mpsc::channel();
         let (tx, rx): (Sender<SerialCommand>, Receiver<SerialCommand>) = mpsc::channel();
-        let settings = settings_mode::SettingsMode::new();
-        portthread::port_background_thread(rx, stx);
-
-        let data = App {
+        let capturelog = capturelog::CaptureLog::new(settings.get_capture_log_settings());
+        let traffic = traffic::SharedTrafficStats::default();
+        portthread::port_background_thread(rx, stx, traffic.clone());
+        let mut analyzermode = analyzer_mode::AnalyzerMode::new(history);
+        analyzermode.set_highlight_rules(settings.get_highlight_rules());
+        analyzermode.set_templates(settings.get_templates());
+        analyzermode.set_delta_settings(settings.get_delta_settings());
+
+        App {
             mode: Mode::Normal,
             exit: false,
             command_sender: tx.clone(),
             state_receiver: rtx,
             settingsmode: settings,
-            analyzermode: analyzer_mode::AnalyzerMode::new(),
+            analyzermode,
             interactivemode: interactive_mode::InteractiveMode::new(tx),
-        };
+            capturelog,
+            replay: None,
+            simulator: None,
+            traffic,
+        }
+    }
 
-        data
+    /// Creates an app that shows a saved session in analyzer mode. The session is
+    /// kept in memory only, neither the history file nor the .klemme file are touched.
+    fn from_session(session: session::Session, path: &Path) -> Self {
+        let mut settings = session.settings;
+        settings.set_session_file(&path.display().to_string());
+
+        let mut history = History::default();
+        for msg in session.history {
+            history.push(msg);
+        }
+
+        let mut app = App::new(settings, history);
+        app.enable_mode(Mode::Analyzer);
+        app
     }
 }
 
@@ -138,6 +328,8 @@ impl App {
 
     fn do_settings_mode(&mut self, key_event: KeyEvent) {
         self.settingsmode.handle_key_event(key_event);
+        self.capturelog
+            .apply_settings(self.settingsmode.get_capture_log_settings());
         match key_event.code {
             KeyCode::Up => self.analyzermode.scroll_up(),
             KeyCode::Down => self.analyzermode.scroll_down(),
@@ -147,6 +339,18 @@ impl App {
     }
 
     fn do_interactive_mode(&mut self, key_event: KeyEvent) {
+        if let Some(replay) = &self.replay {
+            let ctrl = key_event.modifiers.contains(KeyModifiers::CONTROL);
+            match key_event.code {
+                KeyCode::F(7) => replay.toggle_pause(),
+                KeyCode::F(8) => replay.step(),
+                KeyCode::Left if ctrl => replay.seek(-REPLAY_SEEK_FRAMES),
+                KeyCode::Right if ctrl => replay.seek(REPLAY_SEEK_FRAMES),
+                KeyCode::Up if ctrl => replay.scale_speed(2.0),
+                KeyCode::Down if ctrl => replay.scale_speed(0.5),
+                _ => {}
+            }
+        }
         self.interactivemode.handle_key_event(key_event);
         match key_event.code {
             KeyCode::PageUp => self.analyzermode.scroll_up(),
@@ -156,7 +360,37 @@ impl App {
     }
 
     fn do_analyzer_mode(&mut self, key_event: KeyEvent) {
+        if key_event.code == KeyCode::Char('s') && !self.analyzermode.has_popup() {
+            self.save_session();
+            return;
+        }
         self.analyzermode.handle_key_event(key_event);
+        if let Some(rules) = self.analyzermode.take_edited_highlight_rules() {
+            self.settingsmode.set_highlight_rules(rules);
+        }
+        if let Some(delta) = self.analyzermode.take_edited_delta_settings() {
+            self.settingsmode.set_delta_settings(delta);
+        }
+        if let Some(text) = self.analyzermode.take_annotation() {
+            self.record_message(SerialStateMessage::Annotation(Annotation::new(text)));
+        }
+        if let Some(bytes) = self.analyzermode.take_selection_to_send() {
+            self.interactivemode.set_send_buffer(&bytes);
+        }
+    }
+
+    /// Saves the current settings and the complete history to a session file in the CWD.
+    fn save_session(&mut self) {
+        let path = session::default_session_path();
+        let status = match session::save_session(
+            &path,
+            &self.settingsmode,
+            self.analyzermode.history().iter().map(|(_, msg)| msg),
+        ) {
+            Ok(_) => format!("Session saved to {}", path.display()),
+            Err(e) => e.to_string(),
+        };
+        self.analyzermode.set_status_message(status);
     }
 
     fn do_normal_mode(&mut self, key_event: KeyEvent) {
@@ -175,6 +409,11 @@ impl App {
     }
 
     fn handle_key_event(&mut self, key_event: KeyEvent) {
+        // open popups and prompts get all keys, e.g. while an annotation is typed:
+        if self.analyzermode.has_popup() {
+            self.do_analyzer_mode(key_event);
+            return;
+        }
         if key_event.code == KeyCode::Esc && self.mode != Mode::Normal {
             self.enter_normal_mode();
             return;
@@ -183,18 +422,31 @@ impl App {
             self.settingsmode.rotate_display_mode();
         }
 
+        if key_event.code == KeyCode::F(6) {
+            self.settingsmode.toggle_capture_log();
+            self.capturelog
+                .apply_settings(self.settingsmode.get_capture_log_settings());
+        }
+
         if key_event.code == KeyCode::F(9) {
             self.analyzermode.rotate_time_display_mode();
         }
 
         if key_event.code == KeyCode::F(10) {
             self.analyzermode.clear_history();
+            if let Ok(mut traffic) = self.traffic.lock() {
+                traffic.reset(Instant::now());
+            }
+        }
+
+        if key_event.code == KeyCode::F(12) {
+            self.analyzermode.open_annotation_prompt();
+            return;
         }
 
         match self.mode {
             Mode::Settings => {
                 self.do_settings_mode(key_event);
-                return;
             }
             Mode::Interactive => self.do_interactive_mode(key_event),
             Mode::Analyzer => self.do_analyzer_mode(key_event),
@@ -210,9 +462,87 @@ impl App {
         self.settingsmode.render(area, buf);
     }
 
+    /// Shows the connection state and the traffic counters of the port thread.
+    fn draw_status_bar(&self, area: Rect, buf: &mut Frame) {
+        let stats = match self.traffic.lock() {
+            Ok(traffic) => traffic.clone(),
+            Err(_) => return,
+        };
+        let now = Instant::now();
+        let connection = match (&stats.port, stats.duration(now)) {
+            (Some(port), Some(duration)) => vec![
+                "● ".fg(Color::Green),
+                format!("{} {} ", port, traffic::format_duration(duration)).fg(Color::Gray),
+            ],
+            _ => vec!["○ ".fg(Color::Red), "Disconnected ".fg(Color::Gray)],
+        };
+        let errors = format!("Errors:{}", stats.errors).fg(if stats.errors > 0 {
+            Color::LightRed
+        } else {
+            Color::Gray
+        });
+        let counters = format!(
+            "RX {}/{} TX {}/{} {}/s (peak {}/s)",
+            traffic::format_bytes(stats.rx_bytes),
+            stats.rx_frames,
+            traffic::format_bytes(stats.tx_bytes),
+            stats.tx_frames,
+            traffic::format_bytes(stats.current_rate),
+            traffic::format_bytes(stats.peak_rate)
+        );
+
+        let mut first_line = connection;
+        first_line.push(errors);
+        if let Some((checked, bad)) = self.analyzermode.checksum_counts() {
+            first_line.push(
+                format!(" Bad:{}/{}", bad, checked)
+                    .fg(if bad > 0 { Color::LightRed } else { Color::Gray }),
+            );
+        }
+        let text = vec![Line::from(first_line), Line::from(counters.fg(Color::Gray))];
+        let block = Block::bordered()
+            .title("Status")
+            .border_set(border::THICK)
+            .border_style(Color::Gray);
+        buf.render_widget(Paragraph::new(text).block(block), area);
+    }
+
+    /// Moves all messages the port thread sent since the last frame into the
+    /// history, writing them to the capture log on the way.
+    fn process_serial_state(&mut self) {
+        while let Ok(msg) = self.state_receiver.try_recv() {
+            self.record_message(msg);
+        }
+        self.analyzermode.update_data(
+            self.settingsmode.get_display_mode(),
+            self.settingsmode.get_timestamp_settings(),
+            self.settingsmode.get_rx_validation(),
+        );
+    }
+
+    /// Adds a message to the history and writes it to the capture log.
+    fn record_message(&mut self, msg: SerialStateMessage) {
+        let display_mode = self.settingsmode.get_display_mode();
+        let timestamps = self.settingsmode.get_timestamp_settings();
+        if let Err(e) =
+            self.capturelog
+                .log(&msg, self.settingsmode.get_port(), display_mode, timestamps)
+        {
+            // don't retry on every message, the user has to re-enable logging:
+            self.settingsmode.toggle_capture_log();
+            self.capturelog
+                .apply_settings(self.settingsmode.get_capture_log_settings());
+            self.analyzermode.add_to_history(&e.to_string());
+        }
+        self.analyzermode.add_message(msg);
+    }
+
     fn draw_rxtxbuffer(&mut self, area: Rect, buf: &mut Frame) {
-        self.analyzermode
-            .update_data(&self.state_receiver, self.settingsmode.get_display_mode());
+        self.process_serial_state();
+        if let (Some(replay), Mode::Interactive) = (&self.replay, &self.mode) {
+            self.analyzermode.set_status_message(replay.status());
+        }
+        self.analyzermode.set_history_height(area.height);
         self.analyzermode.render(area, buf);
     }
 
@@ -221,17 +551,20 @@ impl App {
     }
 
     /// Renders the application's UI. The UI is split into three rows.
-    /// The first row contains the header, which displays the current settings.
+    /// The first row contains the header, which displays the current settings and the status bar.
     /// The second row contains the RX/TX buffer, which displays the data sent and received over the serial port.
     /// The third row contains the TX line, which is where the user can enter data to send over the serial port.
     pub fn draw(&mut self, frame: &mut Frame) {
         let chunks = Layout::vertical([
-            Constraint::Length(3),
+            Constraint::Length(5),
             Constraint::Min(0),
             Constraint::Length(3),
         ])
         .split(frame.area());
-        self.draw_settings(chunks[0], frame);
+        let [settings_area, status_area] =
+            Layout::horizontal([Constraint::Min(0), Constraint::Length(50)]).areas(chunks[0]);
+        self.draw_settings(settings_area, frame);
+        self.draw_status_bar(status_area, frame);
         self.draw_rxtxbuffer(chunks[1], frame);
         self.draw_tx_line(chunks[2], frame);
     }
@@ -247,7 +580,7 @@ impl App {
     /// This function will panic if there is a failure in opening the serial port or setting
     /// the read/write timeouts.
     fn enter_interactive_mode(&mut self) {
-        if let Ok(ctx) = self.settingsmode.create_serial_context() {
+        if let Ok(ctx) = self.create_serial_context() {
             self.send_command(SerialCommand::Stop);
             self.send_command(SerialCommand::Start(ctx));
             self.enable_mode(mode::Mode::Interactive);
@@ -257,6 +590,20 @@ impl App {
         }
     }
 
+    /// Creates the context for the port thread. Replays and simulated devices
+    /// take precedence over the serial port configured in the settings.
+    fn create_serial_context(&self) -> Result<SerialContext, PortError> {
+        if let Some(replay) = &self.replay {
+            return Ok(replay.create_context());
+        }
+        if let Some(simulator) = &self.simulator {
+            return simulator
+                .create_context()
+                .map_err(|_| PortError::FailedToOpen);
+        }
+        self.settingsmode.create_serial_context()
+    }
+
     /// Exits the current mode and enters the settings mode, which is a mode where the user can adjust
     /// the port, baud rate, stop bits, parity, and data bits of the serial connection.
     fn enter_settings_mode(&mut self) {
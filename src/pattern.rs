@@ -0,0 +1,93 @@
+use snafu::{prelude::*, Whatever};
+
+/// A sequence of bytes, where individual bytes may be wildcards, e.g. `AA ?? 01`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BytePattern {
+    bytes: Vec<Option<u8>>,
+}
+
+impl BytePattern {
+    /// Parses a hex pattern. Two hex digits form a byte, `??` matches any byte.
+    /// Whitespace between bytes is optional.
+    pub fn parse_hex(pattern: &str) -> Result<BytePattern, Whatever> {
+        let digits: Vec<char> = pattern.chars().filter(|c| !c.is_whitespace()).collect();
+        if !digits.len().is_multiple_of(2) {
+            whatever!("Odd number of hex digits in '{}'", pattern);
+        }
+
+        let mut bytes = vec![];
+        for pair in digits.chunks(2) {
+            if pair == ['?', '?'] {
+                bytes.push(None);
+                continue;
+            }
+            let byte: String = pair.iter().collect();
+            let byte = u8::from_str_radix(&byte, 16)
+                .ok()
+                .with_whatever_context(|| {
+                    format!("Invalid hex byte '{}' in '{}'", byte, pattern)
+                })?;
+            bytes.push(Some(byte));
+        }
+        Ok(BytePattern { bytes })
+    }
+
+    /// A pattern that matches the given bytes exactly.
+    pub fn from_bytes(data: &[u8]) -> BytePattern {
+        BytePattern {
+            bytes: data.iter().map(|x| Some(*x)).collect(),
+        }
+    }
+
+    /// Number of bytes the pattern matches.
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Returns true, if the pattern matches `data` at `pos`.
+    pub fn matches_at(&self, data: &[u8], pos: usize) -> bool {
+        if pos + self.bytes.len() > data.len() {
+            return false;
+        }
+        self.bytes
+            .iter()
+            .zip(&data[pos..])
+            .all(|(p, d)| p.is_none_or(|p| p == *d))
+    }
+
+    /// Returns the position of the first match in `data`.
+    pub fn find(&self, data: &[u8]) -> Option<usize> {
+        if self.bytes.is_empty() {
+            return None;
+        }
+        (0..data.len()).find(|pos| self.matches_at(data, *pos))
+    }
+
+    /// Returns the bytes of the pattern, if it does not contain wildcards.
+    pub fn to_bytes(&self) -> Option<Vec<u8>> {
+        self.bytes.iter().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_hex_with_wildcards() {
+        let pattern = BytePattern::parse_hex("AA ?? 01").unwrap();
+        assert_eq!(pattern.find(&[0x00, 0xAA, 0x42, 0x01]), Some(1));
+        assert_eq!(pattern.find(&[0xAA, 0x42, 0x02]), None);
+        assert_eq!(pattern.to_bytes(), None);
+        assert_eq!(
+            BytePattern::parse_hex("0d0A").unwrap().to_bytes(),
+            Some(vec![0x0D, 0x0A])
+        );
+    }
+
+    #[test]
+    fn test_parse_hex_errors() {
+        assert!(BytePattern::parse_hex("AA B").is_err());
+        assert!(BytePattern::parse_hex("GG").is_err());
+    }
+}
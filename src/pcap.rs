@@ -0,0 +1,324 @@
+use std::io::Write;
+
+use chrono::{DateTime, Local};
+
+use crate::portthread::{Annotation, RxTx, SerialStateMessage};
+
+/// Link type of the captured packets. DLT_USER0 is reserved for private use,
+/// so Wireshark users can map their own dissector onto it.
+pub const LINKTYPE_USER0: u16 = 147;
+
+/// Every packet starts with a one byte pseudo header holding the direction.
+pub const PSEUDO_HEADER_RX: u8 = 0x00;
+pub const PSEUDO_HEADER_TX: u8 = 0x01;
+
+/// The length of an option is a u16, longer comments are cut off.
+const MAX_COMMENT_LEN: usize = u16::MAX as usize;
+
+const SECTION_HEADER_BLOCK: u32 = 0x0A0D0D0A;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 0x00000001;
+const ENHANCED_PACKET_BLOCK: u32 = 0x00000006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B3C4D;
+
+const OPT_END_OF_OPT: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const OPT_EPB_FLAGS: u16 = 2;
+const EPB_FLAGS_INBOUND: u32 = 0x01;
+const EPB_FLAGS_OUTBOUND: u32 = 0x02;
+
+fn padding(len: usize) -> usize {
+    (4 - len % 4) % 4
+}
+
+/// Writes a block with the given type and body, adding the length fields
+/// before and after the body.
+fn write_block<W: Write>(writer: &mut W, block_type: u32, body: &[u8]) -> std::io::Result<()> {
+    let total_len = (12 + body.len()) as u32;
+    writer.write_all(&block_type.to_le_bytes())?;
+    writer.write_all(&total_len.to_le_bytes())?;
+    writer.write_all(body)?;
+    writer.write_all(&total_len.to_le_bytes())
+}
+
+fn section_header_body(comments: &[String]) -> Vec<u8> {
+    let mut body = vec![];
+    body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+    body.extend_from_slice(&1u16.to_le_bytes()); // major version
+    body.extend_from_slice(&0u16.to_le_bytes()); // minor version
+    body.extend_from_slice(&(-1i64).to_le_bytes()); // section length unknown
+    write_options(&mut body, &[], comments);
+    body
+}
+
+fn interface_description_body() -> Vec<u8> {
+    let mut body = vec![];
+    body.extend_from_slice(&LINKTYPE_USER0.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes()); // reserved
+    body.extend_from_slice(&0u32.to_le_bytes()); // no snap length limit
+    body
+}
+
+fn write_option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    let len = u16::try_from(value.len()).expect("option values are clamped");
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&len.to_le_bytes());
+    body.extend_from_slice(value);
+    body.extend(std::iter::repeat_n(0u8, padding(value.len())));
+}
+
+/// Cuts the comment off at a character boundary, so that it fits into an option.
+fn clamp_comment(comment: &str) -> &str {
+    let mut len = comment.len().min(MAX_COMMENT_LEN);
+    while !comment.is_char_boundary(len) {
+        len -= 1;
+    }
+    &comment[..len]
+}
+
+/// Writes the options followed by one comment option per comment. Options are
+/// only written, if there are any.
+fn write_options(body: &mut Vec<u8>, options: &[(u16, &[u8])], comments: &[String]) {
+    if options.is_empty() && comments.is_empty() {
+        return;
+    }
+    for (code, value) in options {
+        write_option(body, *code, value);
+    }
+    for comment in comments {
+        write_option(body, OPT_COMMENT, clamp_comment(comment).as_bytes());
+    }
+    write_option(body, OPT_END_OF_OPT, &[]);
+}
+
+/// The text of the comment an annotation is stored as.
+fn annotation_comment(note: &Annotation) -> String {
+    format!("{} {}", note.timestamp.format("%Y-%m-%d %H:%M:%S%.3f"), note.text)
+}
+
+/// Builds an enhanced packet block for a single packet. Timestamps use the default
+/// resolution of microseconds.
+fn enhanced_packet_body(
+    timestamp: DateTime<Local>,
+    pseudo_header: u8,
+    data: &[u8],
+    options: &[(u16, &[u8])],
+    comments: &[String],
+) -> Vec<u8> {
+    let mut packet = vec![pseudo_header];
+    packet.extend_from_slice(data);
+
+    let timestamp = timestamp.timestamp_micros() as u64;
+    let mut body = vec![];
+    body.extend_from_slice(&0u32.to_le_bytes()); // interface id
+    body.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+    body.extend_from_slice(&(timestamp as u32).to_le_bytes());
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes()); // captured length
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes()); // original length
+    body.extend_from_slice(&packet);
+    body.extend(std::iter::repeat_n(0u8, padding(packet.len())));
+
+    write_options(&mut body, options, comments);
+    body
+}
+
+/// A data entry, whose packet is written once the annotations following it are known.
+struct PendingPacket {
+    timestamp: DateTime<Local>,
+    rx_tx: RxTx,
+    data: Vec<u8>,
+    comments: Vec<String>,
+}
+
+impl PendingPacket {
+    fn write<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        let (pseudo_header, flags) = match self.rx_tx {
+            RxTx::Rx => (PSEUDO_HEADER_RX, EPB_FLAGS_INBOUND),
+            RxTx::Tx => (PSEUDO_HEADER_TX, EPB_FLAGS_OUTBOUND),
+        };
+        let body = enhanced_packet_body(
+            self.timestamp,
+            pseudo_header,
+            &self.data,
+            &[(OPT_EPB_FLAGS, &flags.to_le_bytes())],
+            &self.comments,
+        );
+        write_block(writer, ENHANCED_PACKET_BLOCK, &body)
+    }
+}
+
+/// Writes all data entries of the history as pcapng file, one packet per entry.
+/// Annotations are stored as comments of the packet before them, or of the
+/// section header, if there is no packet before them. Start/stop markers and
+/// errors are not part of the capture.
+pub fn write_pcapng<W: Write>(
+    writer: &mut W,
+    messages: impl Iterator<Item = SerialStateMessage>,
+) -> std::io::Result<()> {
+    let mut header_comments = vec![];
+    let mut pending: Option<PendingPacket> = None;
+    for msg in messages {
+        match msg {
+            SerialStateMessage::DataEvent(entry) => {
+                match pending.take() {
+                    Some(packet) => packet.write(writer)?,
+                    None => write_header(writer, &header_comments)?,
+                }
+                pending = Some(PendingPacket {
+                    timestamp: entry.timestamp,
+                    rx_tx: entry.rx_tx,
+                    data: entry.data,
+                    comments: vec![],
+                });
+            }
+            SerialStateMessage::Annotation(note) => match &mut pending {
+                Some(packet) => packet.comments.push(annotation_comment(&note)),
+                None => header_comments.push(annotation_comment(&note)),
+            },
+            _ => {}
+        }
+    }
+    match pending {
+        Some(packet) => packet.write(writer),
+        None => write_header(writer, &header_comments),
+    }
+}
+
+fn write_header<W: Write>(writer: &mut W, comments: &[String]) -> std::io::Result<()> {
+    write_block(writer, SECTION_HEADER_BLOCK, &section_header_body(comments))?;
+    write_block(writer, INTERFACE_DESCRIPTION_BLOCK, &interface_description_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::portthread::HistoryEntry;
+
+    use super::*;
+
+    struct Packet {
+        timestamp: u64,
+        data: Vec<u8>,
+        flags: Option<u32>,
+        comments: Vec<String>,
+    }
+
+    fn u16_at(data: &[u8], pos: usize) -> u16 {
+        u16::from_le_bytes(data[pos..pos + 2].try_into().unwrap())
+    }
+
+    fn u32_at(data: &[u8], pos: usize) -> u32 {
+        u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap())
+    }
+
+    /// Parses the options starting at `pos`, returns the flags and the comments.
+    fn parse_options(body: &[u8], mut pos: usize) -> (Option<u32>, Vec<String>) {
+        let (mut flags, mut comments) = (None, vec![]);
+        while pos < body.len() && u16_at(body, pos) != OPT_END_OF_OPT {
+            let len = u16_at(body, pos + 2) as usize;
+            let value = &body[pos + 4..pos + 4 + len];
+            match u16_at(body, pos) {
+                OPT_EPB_FLAGS => flags = Some(u32_at(value, 0)),
+                OPT_COMMENT => comments.push(String::from_utf8(value.to_vec()).unwrap()),
+                code => panic!("unexpected option {}", code),
+            }
+            pos += 4 + len + padding(len);
+        }
+        (flags, comments)
+    }
+
+    /// Minimal pcapng reader, returns the link type, the section comments and
+    /// all packets.
+    fn parse(file: &[u8]) -> (u16, Vec<String>, Vec<Packet>) {
+        let mut pos = 0;
+        let mut link_type = 0;
+        let mut section_comments = vec![];
+        let mut packets = vec![];
+        while pos < file.len() {
+            let block_type = u32_at(file, pos);
+            let len = u32_at(file, pos + 4) as usize;
+            assert_eq!(u32_at(file, pos + len - 4) as usize, len);
+            let body = &file[pos + 8..pos + len - 4];
+            match block_type {
+                SECTION_HEADER_BLOCK => {
+                    assert_eq!(u32_at(body, 0), BYTE_ORDER_MAGIC);
+                    section_comments = parse_options(body, 16).1;
+                }
+                INTERFACE_DESCRIPTION_BLOCK => link_type = u16_at(body, 0),
+                ENHANCED_PACKET_BLOCK => {
+                    let timestamp = ((u32_at(body, 4) as u64) << 32) | u32_at(body, 8) as u64;
+                    let captured = u32_at(body, 12) as usize;
+                    let data = body[20..20 + captured].to_vec();
+                    let (flags, comments) = parse_options(body, 20 + captured + padding(captured));
+                    packets.push(Packet {
+                        timestamp,
+                        data,
+                        flags,
+                        comments,
+                    });
+                }
+                _ => panic!("unexpected block type {:x}", block_type),
+            }
+            pos += len;
+        }
+        (link_type, section_comments, packets)
+    }
+
+    fn annotation(timestamp: DateTime<Local>, text: &str) -> SerialStateMessage {
+        SerialStateMessage::Annotation(Annotation {
+            timestamp,
+            text: text.to_string(),
+        })
+    }
+
+    #[test]
+    fn test_pcapng_round_trip() {
+        let timestamp = DateTime::parse_from_rfc3339("2024-01-02T03:04:05.678901+00:00")
+            .unwrap()
+            .with_timezone(&Local);
+        let messages = vec![
+            annotation(timestamp, "power on"),
+            SerialStateMessage::Started,
+            SerialStateMessage::DataEvent(HistoryEntry {
+                timestamp,
+                rx_tx: RxTx::Tx,
+                data: vec![0x01, 0x02, 0x03],
+                ..Default::default()
+            }),
+            SerialStateMessage::DataEvent(HistoryEntry {
+                timestamp,
+                rx_tx: RxTx::Rx,
+                data: vec![0xAA; 8],
+                ..Default::default()
+            }),
+            annotation(timestamp, "reset"),
+        ];
+
+        let mut file = vec![];
+        write_pcapng(&mut file, messages.into_iter()).unwrap();
+        let (link_type, section_comments, packets) = parse(&file);
+
+        let time = timestamp.format("%Y-%m-%d %H:%M:%S%.3f");
+        assert_eq!(link_type, LINKTYPE_USER0);
+        assert_eq!(section_comments, vec![format!("{} power on", time)]);
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].timestamp, timestamp.timestamp_micros() as u64);
+        assert_eq!(packets[0].data, vec![PSEUDO_HEADER_TX, 0x01, 0x02, 0x03]);
+        assert_eq!(packets[0].flags, Some(EPB_FLAGS_OUTBOUND));
+        assert!(packets[0].comments.is_empty());
+        assert_eq!(packets[1].data[0], PSEUDO_HEADER_RX);
+        assert_eq!(packets[1].data.len(), 9);
+        assert_eq!(packets[1].flags, Some(EPB_FLAGS_INBOUND));
+        assert_eq!(packets[1].comments, vec![format!("{} reset", time)]);
+    }
+
+    #[test]
+    fn test_long_comments_are_clamped() {
+        let text = "ä".repeat(MAX_COMMENT_LEN);
+        let mut file = vec![];
+        write_pcapng(&mut file, [annotation(Local::now(), &text)].into_iter()).unwrap();
+        let (_, section_comments, packets) = parse(&file);
+
+        assert!(packets.is_empty());
+        assert!(section_comments[0].len() <= MAX_COMMENT_LEN);
+        assert!(section_comments[0].ends_with('ä'));
+    }
+}
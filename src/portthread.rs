@@ -1,8 +1,11 @@
 use std::{
-    fmt::Display, sync::mpsc::{Receiver, Sender}, thread, vec
+    fmt::Display, io::ErrorKind, sync::mpsc::{Receiver, Sender}, thread, time::Instant, vec
 };
 
-use chrono::{DateTime, Local};
+use chrono::{DateTime, Local, TimeDelta};
+use serde::{Deserialize, Serialize};
+
+use crate::traffic::{SharedTrafficStats, TrafficStats};
 
 pub enum PortError {
     BadSettings,
@@ -10,19 +13,53 @@ pub enum PortError {
     FailedToOpen,
 }
 
+/// Anything the port thread can exchange data with. Reads are expected to
+/// time out (instead of blocking forever), if there is no data, so that the
+/// port thread can keep processing commands.
+pub trait Transport: Send + std::fmt::Debug {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize>;
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize>;
+}
+
+impl Transport for serial2::SerialPort {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        serial2::SerialPort::read(self, buffer)
+    }
+
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        serial2::SerialPort::write(self, data)
+    }
+}
+
 #[derive(Debug)]
 pub struct SerialContext {
     port_name: String,
-    com_port: Option<serial2::SerialPort>,
+    com_port: Option<Box<dyn Transport>>,
+    /// Transmission time of one character in nanoseconds, 0 if unknown.
+    char_time_ns: u32,
 }
 
 impl SerialContext {
     pub fn new(port_name: String, com_port: serial2::SerialPort) -> Self {
+        SerialContext::with_transport(port_name, Box::new(com_port))
+    }
+
+    /// Creates a context for a transport other than a serial port, e.g. a replay.
+    pub fn with_transport(port_name: String, transport: Box<dyn Transport>) -> Self {
         SerialContext {
             port_name,
-            com_port: Some(com_port),
+            com_port: Some(transport),
+            char_time_ns: 0,
         }
     }
+
+    /// Sets the time it takes to transmit one character (start bit, data bits,
+    /// parity and stop bits), which is used to estimate the arrival time of every
+    /// received byte.
+    pub fn with_char_time(mut self, char_time_ns: u32) -> Self {
+        self.char_time_ns = char_time_ns;
+        self
+    }
 }
 
 impl PartialEq for SerialContext {
@@ -31,7 +68,7 @@ impl PartialEq for SerialContext {
     }
 }
 
-#[derive(Debug, Default, PartialEq, Clone)]
+#[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
 pub enum RxTx {
     #[default]
     Rx,
@@ -47,11 +84,31 @@ impl Display for RxTx {
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+/// The bytes returned by a single read of the port.
+#[derive(Debug, Default, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct ChunkTiming {
+    /// Index of the first byte of the chunk within the entry.
+    pub offset: usize,
+    /// Microseconds between the timestamp of the entry and the read that
+    /// returned the chunk, measured with a monotonic clock.
+    pub micros: u64,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct HistoryEntry {
     pub timestamp: DateTime<Local>,
     pub rx_tx: RxTx,
     pub data: vec::Vec<u8>,
+    /// Timing of the reads the entry was aggregated from. Empty for sent data.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<ChunkTiming>,
+    /// Transmission time of one character in nanoseconds, 0 if unknown.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub char_time_ns: u32,
+}
+
+fn is_zero(value: &u32) -> bool {
+    *value == 0
 }
 
 impl Default for HistoryEntry {
@@ -59,7 +116,71 @@ impl Default for HistoryEntry {
         Self {
             timestamp: Local::now(),
             rx_tx: RxTx::Rx,
-            data: vec![]
+            data: vec![],
+            chunks: vec![],
+            char_time_ns: 0,
+        }
+    }
+}
+
+impl HistoryEntry {
+    /// Returns the arrival time of the byte at `offset`, relative to the timestamp
+    /// of the entry. A read returns after the last byte of its chunk arrived, so
+    /// if the character time is known, the earlier bytes of the chunk are
+    /// estimated to have arrived one character time apart. Otherwise all bytes of
+    /// a chunk share the time of the read.
+    pub fn byte_time(&self, offset: usize) -> Option<TimeDelta> {
+        if offset >= self.data.len() {
+            return None;
+        }
+        let chunk = self.chunks.partition_point(|c| c.offset <= offset).checked_sub(1)?;
+        let read_time = TimeDelta::microseconds(self.chunks[chunk].micros as i64);
+        if self.char_time_ns == 0 {
+            return Some(read_time);
+        }
+        let chunk_end = self.chunks.get(chunk + 1).map_or(self.data.len(), |c| c.offset);
+        let later_bytes = (chunk_end - 1 - offset) as i64;
+        let estimate = read_time - TimeDelta::nanoseconds(later_bytes * self.char_time_ns as i64);
+        // a byte cannot have arrived before the previous read returned:
+        let previous_read = chunk
+            .checked_sub(1)
+            .map(|c| TimeDelta::microseconds(self.chunks[c].micros as i64));
+        Some(previous_read.map_or(estimate, |previous| estimate.max(previous)))
+    }
+}
+
+/// Wall clock time derived from a monotonic clock, so that timestamps taken
+/// in a row never go backwards, even if the system time is adjusted.
+struct MonotonicClock {
+    start: Instant,
+    start_time: DateTime<Local>,
+}
+
+impl MonotonicClock {
+    fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            start_time: Local::now(),
+        }
+    }
+
+    fn now(&self) -> DateTime<Local> {
+        self.start_time + TimeDelta::from_std(self.start.elapsed()).unwrap_or_default()
+    }
+}
+
+/// A note the user dropped into the history, e.g. "pressed reset button".
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Annotation {
+    pub timestamp: DateTime<Local>,
+    pub text: String,
+}
+
+impl Annotation {
+    pub fn new(text: String) -> Self {
+        Self {
+            timestamp: Local::now(),
+            text,
         }
     }
 }
@@ -72,11 +193,11 @@ pub enum PortThreadState {
 
 impl PartialEq for PortThreadState {
     fn eq(&self, other: &Self) -> bool {
-        match (self, other) {
-            (PortThreadState::Stopped, PortThreadState::Stopped) => true,
-            (PortThreadState::Running(_), PortThreadState::Running(_)) => true,
-            _ => false,
-        }
+        matches!(
+            (self, other),
+            (PortThreadState::Stopped, PortThreadState::Stopped)
+                | (PortThreadState::Running(_), PortThreadState::Running(_))
+        )
     }
 }
 
@@ -86,12 +207,25 @@ pub enum SerialCommand {
     Send(Vec<u8>),
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub enum SerialStateMessage {
     DataEvent(HistoryEntry),
     ErrorEvent(String),
     Started,
     Stopped,
+    Annotation(Annotation),
+}
+
+impl SerialStateMessage {
+    /// Returns the time the message was recorded at. Errors and start/stop
+    /// markers carry no timestamp.
+    pub fn timestamp(&self) -> Option<DateTime<Local>> {
+        match self {
+            SerialStateMessage::DataEvent(entry) => Some(entry.timestamp),
+            SerialStateMessage::Annotation(note) => Some(note.timestamp),
+            _ => None,
+        }
+    }
 }
 
 /// Returns the next command from the main thread, or `None` if there are no commands
@@ -107,13 +241,13 @@ fn receive_command(state: &PortThreadState, rx: &Receiver<SerialCommand>) -> Opt
         if let Ok(rxd) = rx.recv() {
             return Some(rxd);
         }
-        return None;
+        None
     } else {
         if let Ok(rxd) = rx.try_recv() {
             return Some(rxd);
         }
-        return None;
-    };
+        None
+    }
 }
 
 /// Starts a background thread that is responsible for managing the serial port.
@@ -134,10 +268,13 @@ fn receive_command(state: &PortThreadState, rx: &Receiver<SerialCommand>) -> Opt
 ///   main thread.
 /// - `SerialStateMessage::ErrorEvent(String)`: The background thread has
 ///   encountered an error while writing to the serial port.
-pub fn port_background_thread(rx: Receiver<SerialCommand>, tx: Sender<SerialStateMessage>) {
+///
+/// The traffic counters in `traffic` are kept up to date while the port is open.
+pub fn port_background_thread(rx: Receiver<SerialCommand>, tx: Sender<SerialStateMessage>, traffic: SharedTrafficStats) {
     thread::spawn(move || {
         let mut state = PortThreadState::Stopped;
         let mut last_entry = HistoryEntry::default();
+        let mut clock = MonotonicClock::new();
 
         loop {
             let mut data_to_send: Vec<u8> = vec![];
@@ -145,6 +282,11 @@ pub fn port_background_thread(rx: Receiver<SerialCommand>, tx: Sender<SerialStat
             // if the state is stopped, wait until rx receives something:
             let _cmd = receive_command(&state, &rx);
 
+            // while stopped, no command means the main thread has gone away:
+            if state == PortThreadState::Stopped && _cmd.is_none() {
+                break;
+            }
+
             if let Some(cmd) = _cmd {
                 match cmd {
                     SerialCommand::Send(data) => {
@@ -152,12 +294,20 @@ pub fn port_background_thread(rx: Receiver<SerialCommand>, tx: Sender<SerialStat
                     }
                     SerialCommand::Stop => {
                         if state != PortThreadState::Stopped {
+                            if let Ok(mut traffic) = traffic.lock() {
+                                traffic.disconnected();
+                            }
                             let _ = tx.send(SerialStateMessage::Stopped);
                             state = PortThreadState::Stopped;
                         }
                     }
                     SerialCommand::Start(ctx) => {
                         if state == PortThreadState::Stopped {
+                            // re-synchronize with the system time once per session:
+                            clock = MonotonicClock::new();
+                            if let Ok(mut traffic) = traffic.lock() {
+                                traffic.connected(&ctx.port_name, Instant::now());
+                            }
                             let _ = tx.send(SerialStateMessage::Started);
                             state = PortThreadState::Running(ctx);
                         }
@@ -167,8 +317,8 @@ pub fn port_background_thread(rx: Receiver<SerialCommand>, tx: Sender<SerialStat
 
             match state {
                 PortThreadState::Stopped => {}
-                PortThreadState::Running(ref ctx) => {
-                    send_receive(ctx, &mut last_entry, data_to_send, &tx);
+                PortThreadState::Running(ref mut ctx) => {
+                    send_receive(ctx, &clock, &mut last_entry, data_to_send, &tx, &traffic);
                 }
             }
         }
@@ -176,17 +326,29 @@ pub fn port_background_thread(rx: Receiver<SerialCommand>, tx: Sender<SerialStat
 }
 
 
-fn send_receive(ctx: &SerialContext, last_entry: &mut HistoryEntry, data_to_send: Vec<u8>, tx: &Sender<SerialStateMessage>) {    
-    if let Some(p) = &ctx.com_port {
-        if data_to_send.len() != 0 {
-            if let Ok(_) = p.write(&data_to_send) {
+fn send_receive(ctx: &mut SerialContext, clock: &MonotonicClock, last_entry: &mut HistoryEntry, data_to_send: Vec<u8>, tx: &Sender<SerialStateMessage>, traffic: &SharedTrafficStats) {    
+    // the lock is only held briefly, so the status bar can always read the counters:
+    let update_traffic = |update: &dyn Fn(&mut TrafficStats)| {
+        if let Ok(mut traffic) = traffic.lock() {
+            update(&mut traffic);
+        }
+    };
+    if let Some(p) = &mut ctx.com_port {
+        if !data_to_send.is_empty() {
+            if p.write(&data_to_send).is_ok() {
+                update_traffic(&|t| {
+                    t.add_bytes(RxTx::Tx, data_to_send.len());
+                    t.add_frame(RxTx::Tx);
+                });
                 let entry = HistoryEntry {
-                    timestamp: Local::now(),
+                    timestamp: clock.now(),
                     rx_tx: RxTx::Tx,
                     data: data_to_send,
+                    ..Default::default()
                 };                                            
                 tx.send(SerialStateMessage::DataEvent(entry)).unwrap();
             } else {
+                update_traffic(&|t| t.add_error());
                 tx.send(SerialStateMessage::ErrorEvent(
                     "Failed to write to port".to_string(),
                 ))
@@ -195,35 +357,79 @@ fn send_receive(ctx: &SerialContext, last_entry: &mut HistoryEntry, data_to_send
         }
         // receive data:
         let mut buffer: [u8; 256] = [0u8; 256];
-        if let Ok(data) = p.read(&mut buffer) {
-            /*
-                How this works:
-                Since we occasionally get several reads at the same timestamp,
-                we aggregate everything we receive withinin a small number of
-                milliseconds into one entry.
-             */        
-            handle_received_bytes(last_entry, buffer[0..data].to_vec(), tx);
+        let result = p.read(&mut buffer);
+        // taken right after the read returned, so that it is as close as possible
+        // to the arrival of the data:
+        let now = clock.now();
+        match result {
+            Ok(data) if data > 0 => {
+                /*
+                    How this works:
+                    Since we occasionally get several reads at the same timestamp,
+                    we aggregate everything we receive withinin a small number of
+                    milliseconds into one entry.
+                 */
+                last_entry.char_time_ns = ctx.char_time_ns;
+                update_traffic(&|t| t.add_bytes(RxTx::Rx, data));
+                if handle_received_bytes(last_entry, buffer[0..data].to_vec(), now, tx) {
+                    update_traffic(&|t| t.add_frame(RxTx::Rx));
+                }
+            }
+            result => {
+                if let Err(e) = result {
+                    if !matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock | ErrorKind::Interrupted) {
+                        update_traffic(&|t| t.add_error());
+                    }
+                }
+                // nothing received (usually a timeout), so whatever is pending
+                // will not grow anymore:
+                if flush_pending_bytes(last_entry, now, tx) {
+                    update_traffic(&|t| t.add_frame(RxTx::Rx));
+                }
+            }
         }
     }
+    update_traffic(&|t| t.tick(Instant::now()));
 }
 
-fn handle_received_bytes(last_entry: &mut HistoryEntry, received_data: Vec<u8>, tx: &Sender<SerialStateMessage>) {
+/// Sends the aggregated entry, once no more data arrived for the aggregation time.
+/// Returns true, if an entry was sent.
+fn flush_pending_bytes(last_entry: &mut HistoryEntry, now: DateTime<Local>, tx: &Sender<SerialStateMessage>) -> bool {
+    if last_entry.data.is_empty() {
+        return false;
+    }
+    let ms = (now - last_entry.timestamp).num_milliseconds();
+    if ms >= 5 {
+        tx.send(SerialStateMessage::DataEvent(last_entry.clone())).unwrap();
+        last_entry.data.clear();
+        return true;
+    }
+    false
+}
 
-    let mut entry = HistoryEntry {
-        timestamp: Local::now(),
-        rx_tx: RxTx::Rx,
-        data: received_data,
-    };    
-    let ms = (entry.timestamp - last_entry.timestamp).num_milliseconds();
+/// Adds the received bytes to the pending entry, or sends the pending entry and
+/// starts a new one. Returns true, if an entry was sent.
+fn handle_received_bytes(last_entry: &mut HistoryEntry, mut received_data: Vec<u8>, timestamp: DateTime<Local>, tx: &Sender<SerialStateMessage>) -> bool {
+    let elapsed = timestamp - last_entry.timestamp;
 
-    if ms < 5
+    if elapsed.num_milliseconds() < 5 && !last_entry.data.is_empty()
     {
-        last_entry.data.append(&mut entry.data);
+        last_entry.chunks.push(ChunkTiming {
+            offset: last_entry.data.len(),
+            micros: elapsed.num_microseconds().unwrap_or(0).max(0) as u64,
+        });
+        last_entry.data.append(&mut received_data);
+        false
     }
     else {
-        tx.send(SerialStateMessage::DataEvent(last_entry.clone())).unwrap();    
-        last_entry.data = entry.data;
-        last_entry.timestamp = Local::now();                
+        let sent = !last_entry.data.is_empty();
+        if sent {
+            tx.send(SerialStateMessage::DataEvent(last_entry.clone())).unwrap();
+        }
+        last_entry.data = received_data;
+        last_entry.timestamp = timestamp;
+        last_entry.chunks = vec![ChunkTiming::default()];
+        sent
     } 
 }
 
@@ -241,7 +447,7 @@ mod tests {
         let mut last_entry = HistoryEntry::default();
         let (tx, _) = mpsc::channel();
         let received_data = vec![0x01, 0x02, 0x03, 0x04, 0x05, 0x06];
-        handle_received_bytes(&mut last_entry, received_data, &tx);
+        handle_received_bytes(&mut last_entry, received_data, Local::now(), &tx);
         // simple case, no aggregation of data:
         assert_eq!(last_entry.data, vec![0x01, 0x02, 0x03, 0x04, 0x05, 0x06]);
     }
@@ -256,9 +462,9 @@ mod tests {
 
         let received_data = vec![0x01, 0x02, 0x03, 0x04, 0x05, 0x06];
         last_entry.timestamp = Local::now();
-        handle_received_bytes(&mut last_entry, received_data, &tx);
+        handle_received_bytes(&mut last_entry, received_data, Local::now(), &tx);
         let received_data = vec![0x01, 0x02, 0x03, 0x04, 0x05, 0x06];
-        handle_received_bytes(&mut last_entry, received_data, &tx);
+        handle_received_bytes(&mut last_entry, received_data, Local::now(), &tx);
         // simple case, no aggregation of data:
         assert_eq!(last_entry.data, vec![0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06]);
     }
@@ -269,10 +475,10 @@ mod tests {
         let mut last_entry = HistoryEntry::default();
         let (tx, rx) = mpsc::channel();
         let received_data = vec![0x01, 0x02, 0x03, 0x04, 0x05, 0x06];        
-        handle_received_bytes(&mut last_entry, received_data, &tx);
+        handle_received_bytes(&mut last_entry, received_data, Local::now(), &tx);
         let received_data = vec![0x03, 0x04, 0x05, 0x06, 0x07, 0x08];
         last_entry.timestamp = Local::now().checked_sub_days(Days::new(1)).unwrap();
-        let _ = handle_received_bytes(&mut last_entry, received_data, &tx);
+        handle_received_bytes(&mut last_entry, received_data, Local::now(), &tx);
         // simple case, no aggregation of data:
         assert_eq!(last_entry.data, vec![0x03, 0x04, 0x05, 0x06, 0x07, 0x08]);
         let recv = rx.recv().expect("Need message here!");
@@ -282,4 +488,48 @@ mod tests {
 
     }
 
-}
\ No newline at end of file
+    // Pending data is sent, once nothing was received for a while:
+    #[test]
+    fn test_flush_pending_bytes() {
+        let mut last_entry = HistoryEntry::default();
+        let (tx, rx) = mpsc::channel();
+        flush_pending_bytes(&mut last_entry, Local::now(), &tx);
+        assert!(rx.try_recv().is_err());
+
+        handle_received_bytes(&mut last_entry, vec![0x01, 0x02], Local::now(), &tx);
+        last_entry.timestamp = Local::now().checked_sub_days(Days::new(1)).unwrap();
+        flush_pending_bytes(&mut last_entry, Local::now(), &tx);
+        assert!(last_entry.data.is_empty());
+        let recv = rx.try_recv().expect("Need message here!");
+        if let SerialStateMessage::DataEvent(msg) = recv {
+            assert_eq!(msg.data, vec![0x01, 0x02]);
+        }
+    }
+
+    // Every read is recorded as a chunk of the aggregated entry:
+    #[test]
+    fn test_chunk_timing() {
+        let mut last_entry = HistoryEntry::default();
+        let (tx, _) = mpsc::channel();
+        let start = Local::now();
+        handle_received_bytes(&mut last_entry, vec![0x01, 0x02], start, &tx);
+        handle_received_bytes(&mut last_entry, vec![0x03, 0x04], start + TimeDelta::milliseconds(3), &tx);
+        assert_eq!(last_entry.timestamp, start);
+        assert_eq!(
+            last_entry.chunks,
+            vec![ChunkTiming { offset: 0, micros: 0 }, ChunkTiming { offset: 2, micros: 3000 }]
+        );
+        assert_eq!(last_entry.byte_time(1), Some(TimeDelta::zero()));
+        assert_eq!(last_entry.byte_time(2), Some(TimeDelta::milliseconds(3)));
+        assert_eq!(last_entry.byte_time(4), None);
+
+        // 1ms per character: the first byte of a chunk arrived one character
+        // time before the read returned, but not before the previous read.
+        last_entry.char_time_ns = 1_000_000;
+        assert_eq!(last_entry.byte_time(0), Some(TimeDelta::milliseconds(-1)));
+        assert_eq!(last_entry.byte_time(2), Some(TimeDelta::milliseconds(2)));
+        last_entry.char_time_ns = 5_000_000;
+        assert_eq!(last_entry.byte_time(2), Some(TimeDelta::zero()));
+    }
+
+}
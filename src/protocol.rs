@@ -0,0 +1,609 @@
+use std::{collections::BTreeMap, ops::Range, path::Path};
+
+use serde::Deserialize;
+use snafu::{prelude::*, Whatever};
+
+use crate::{
+    decode::Endianness,
+    pattern::BytePattern,
+    portthread::RxTx,
+    template::{format_value, hex, read_value, FieldType, Value},
+};
+
+/// Maximum nesting of structures, guards against types containing themselves.
+const MAX_DEPTH: usize = 16;
+
+/// Name of the amount, that reaches up to the end of the frame.
+const REST: &str = "rest";
+
+/// Type of a field, either a type of the analyzer or a structure from `types`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum SpecType {
+    Builtin(FieldType),
+    Struct(String),
+}
+
+/// A number of bytes or repetitions. Besides a fixed number, the value of an
+/// earlier field plus or minus a constant may be given, e.g. `"length - 2"`, or
+/// `"rest"` for the bytes up to the end of the frame, e.g. `"rest - 2"`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Amount {
+    Fixed(usize),
+    Expression(String),
+}
+
+/// A field of a frame, a message or a structure.
+#[derive(Debug, Clone, Deserialize)]
+pub struct FieldSpec {
+    pub name: String,
+    /// Without a type, the field is `bytes`.
+    #[serde(rename = "type", default)]
+    pub kind: Option<SpecType>,
+    /// Number of bytes of `bcd`, `ascii` and `bytes` fields. Structures with a size
+    /// always take that many bytes, no matter how many their fields take.
+    #[serde(default)]
+    pub size: Option<Amount>,
+    /// Number of times the field is repeated, `"rest"` repeats it up to the end
+    /// of the frame.
+    #[serde(default)]
+    pub repeat: Option<Amount>,
+    /// Hex bytes the field must contain, e.g. a sync word, `??` matches any byte.
+    #[serde(default)]
+    pub contents: Option<String>,
+    #[serde(default)]
+    pub endianness: Option<Endianness>,
+    #[serde(default)]
+    pub scale: Option<f64>,
+    #[serde(default)]
+    pub add: Option<f64>,
+    #[serde(default)]
+    pub unit: Option<String>,
+    #[serde(default)]
+    pub values: BTreeMap<i64, String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MessageSpec {
+    pub name: String,
+    /// Value of the `id_field`, that selects the message. Messages without an id
+    /// are tried for every frame.
+    #[serde(default)]
+    pub id: Option<i64>,
+    /// Restricts the message to received or sent frames.
+    #[serde(default)]
+    pub direction: Option<RxTx>,
+    #[serde(default)]
+    pub fields: Vec<FieldSpec>,
+}
+
+/// A protocol description file. Every frame consists of the `header`, the fields
+/// of a message and the `trailer`. The first message, whose id matches and whose
+/// `contents` are found, is used.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Protocol {
+    pub name: String,
+    #[serde(default)]
+    pub endianness: Endianness,
+    #[serde(default)]
+    pub header: Vec<FieldSpec>,
+    /// Name of the header field, whose value selects the message.
+    #[serde(default)]
+    pub id_field: Option<String>,
+    /// Fields following every message, e.g. a checksum.
+    #[serde(default)]
+    pub trailer: Vec<FieldSpec>,
+    /// Structures, that may be used as type of a field.
+    #[serde(default)]
+    pub types: BTreeMap<String, Vec<FieldSpec>>,
+    pub messages: Vec<MessageSpec>,
+}
+
+/// A decoded field. Structures and repeated fields have children instead of a value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldNode {
+    pub name: String,
+    pub range: Range<usize>,
+    pub value: String,
+    pub children: Vec<FieldNode>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedFrame {
+    pub message: String,
+    pub fields: Vec<FieldNode>,
+    /// Set, if the frame ended before all fields were decoded.
+    pub truncated: bool,
+}
+
+/// The frame does not contain the `contents` of a field, i.e. it is not the message.
+struct Mismatch;
+
+/// Splits an expression like `length - 2` into the name and the constant.
+fn parse_expression(expression: &str) -> Option<(&str, i64)> {
+    let expression = expression.trim();
+    let (name, constant) = match expression.find(['+', '-']) {
+        Some(i) => {
+            let constant: i64 = expression[i + 1..].trim().parse().ok()?;
+            let sign = if expression[i..].starts_with('-') {
+                -1
+            } else {
+                1
+            };
+            (expression[..i].trim(), sign * constant)
+        }
+        None => (expression, 0),
+    };
+    let valid = !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_');
+    valid.then_some((name, constant))
+}
+
+impl FieldSpec {
+    fn kind(&self) -> &SpecType {
+        const BYTES: SpecType = SpecType::Builtin(FieldType::Bytes);
+        self.kind.as_ref().unwrap_or(&BYTES)
+    }
+}
+
+impl Protocol {
+    pub fn load(path: &Path) -> Result<Protocol, Whatever> {
+        let text = std::fs::read_to_string(path)
+            .with_whatever_context(|_| format!("Failed to open {}", path.display()))?;
+        let protocol: Protocol = toml::from_str(&text).with_whatever_context(|e| {
+            format!("{} is not a valid protocol file: {}", path.display(), e)
+        })?;
+        protocol.check()?;
+        Ok(protocol)
+    }
+
+    /// Checks the description for errors, that would only show when decoding.
+    pub fn check(&self) -> Result<(), Whatever> {
+        let messages = self.messages.iter().map(|m| (m.name.as_str(), &m.fields));
+        let types = self
+            .types
+            .iter()
+            .map(|(name, fields)| (name.as_str(), fields));
+        let parts = [("header", &self.header), ("trailer", &self.trailer)]
+            .into_iter()
+            .chain(messages)
+            .chain(types);
+        for (part, fields) in parts {
+            for field in fields {
+                self.check_field(field).with_whatever_context(|e| {
+                    format!("Invalid field '{}' in {}: {}", field.name, part, e)
+                })?;
+            }
+        }
+        if let Some(id_field) = &self.id_field {
+            ensure_whatever!(
+                self.header.iter().any(|field| field.name == *id_field),
+                "The id field '{}' is not in the header",
+                id_field
+            );
+        } else if let Some(message) = self.messages.iter().find(|m| m.id.is_some()) {
+            whatever!(
+                "Message '{}' has an id, but there is no id_field",
+                message.name
+            );
+        }
+        Ok(())
+    }
+
+    fn check_field(&self, field: &FieldSpec) -> Result<(), Whatever> {
+        if let Some(contents) = &field.contents {
+            BytePattern::parse_hex(contents)?;
+            return Ok(());
+        }
+        for amount in [&field.size, &field.repeat].into_iter().flatten() {
+            if let Amount::Expression(expression) = amount {
+                ensure_whatever!(
+                    parse_expression(expression).is_some(),
+                    "Invalid expression '{}'",
+                    expression
+                );
+            }
+        }
+        match field.kind() {
+            SpecType::Builtin(kind) => ensure_whatever!(
+                kind.size().is_some() || field.size.is_some(),
+                "A size is required"
+            ),
+            SpecType::Struct(name) => {
+                ensure_whatever!(self.types.contains_key(name), "Unknown type '{}'", name)
+            }
+        }
+        Ok(())
+    }
+
+    /// Decodes the frame, `None` if it is none of the messages.
+    pub fn decode(&self, data: &[u8], rx_tx: &RxTx) -> Option<DecodedFrame> {
+        let mut decoder = Decoder {
+            protocol: self,
+            data,
+            pos: 0,
+            end: data.len(),
+            values: vec![],
+            truncated: false,
+        };
+        let header = decoder.fields(&self.header, 0).ok()?;
+        let id = self.id_field.as_ref().and_then(|name| decoder.value(name));
+
+        for message in &self.messages {
+            if message.direction.as_ref().is_some_and(|d| d != rx_tx) {
+                continue;
+            }
+            if message.id.is_some() && message.id.map(i128::from) != id {
+                continue;
+            }
+            let mut attempt = decoder.clone();
+            let Ok(fields) = attempt.fields(&message.fields, 0) else {
+                continue;
+            };
+            let Ok(trailer) = attempt.fields(&self.trailer, 0) else {
+                continue;
+            };
+            let mut nodes = header.clone();
+            nodes.extend(fields);
+            nodes.extend(trailer);
+            if attempt.pos < data.len() {
+                nodes.push(FieldNode {
+                    name: "(unparsed)".to_string(),
+                    range: attempt.pos..data.len(),
+                    value: hex(&data[attempt.pos..]),
+                    children: vec![],
+                });
+            }
+            return Some(DecodedFrame {
+                message: message.name.clone(),
+                fields: nodes,
+                truncated: attempt.truncated,
+            });
+        }
+        None
+    }
+}
+
+#[derive(Clone)]
+struct Decoder<'a> {
+    protocol: &'a Protocol,
+    data: &'a [u8],
+    pos: usize,
+    /// End of the bytes of the current structure.
+    end: usize,
+    /// The integer fields decoded so far, for lengths, repetitions and the message id.
+    values: Vec<(String, i128)>,
+    truncated: bool,
+}
+
+impl Decoder<'_> {
+    fn value(&self, name: &str) -> Option<i128> {
+        self.values
+            .iter()
+            .rev()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| *value)
+    }
+
+    /// Evaluates the amount, `None` if it refers to a missing field or is negative.
+    fn amount(&self, amount: &Amount) -> Option<usize> {
+        match amount {
+            Amount::Fixed(n) => Some(*n),
+            Amount::Expression(expression) => {
+                let (name, constant) = parse_expression(expression)?;
+                let value = match name {
+                    REST => (self.end - self.pos) as i128,
+                    _ => self.value(name)?,
+                };
+                usize::try_from(value + constant as i128).ok()
+            }
+        }
+    }
+
+    fn fields(&mut self, specs: &[FieldSpec], depth: usize) -> Result<Vec<FieldNode>, Mismatch> {
+        specs.iter().map(|spec| self.field(spec, depth)).collect()
+    }
+
+    fn field(&mut self, spec: &FieldSpec, depth: usize) -> Result<FieldNode, Mismatch> {
+        let Some(repeat) = &spec.repeat else {
+            return self.single(spec, spec.name.clone(), depth);
+        };
+        let start = self.pos;
+        let until_end = matches!(repeat, Amount::Expression(e) if e.trim() == REST);
+        let count = if until_end {
+            usize::MAX
+        } else {
+            match self.amount(repeat) {
+                Some(count) => count,
+                None => return Ok(self.missing(spec.name.clone())),
+            }
+        };
+
+        let mut children = vec![];
+        while children.len() < count && !self.truncated {
+            if until_end && self.pos >= self.end {
+                break;
+            }
+            let before = self.pos;
+            let name = format!("{}[{}]", spec.name, children.len());
+            children.push(self.single(spec, name, depth)?);
+            if self.pos == before && (until_end || children.len() > self.data.len()) {
+                // the field takes no bytes, it would be repeated forever:
+                break;
+            }
+        }
+        Ok(FieldNode {
+            name: spec.name.clone(),
+            range: start..self.pos,
+            value: format!("{} items", children.len()),
+            children,
+        })
+    }
+
+    /// A field, that could not be decoded because the frame ended before it.
+    fn missing(&mut self, name: String) -> FieldNode {
+        self.truncated = true;
+        let start = self.pos;
+        self.pos = self.end;
+        FieldNode {
+            name,
+            range: start..self.end,
+            value: "-".to_string(),
+            children: vec![],
+        }
+    }
+
+    fn single(
+        &mut self,
+        spec: &FieldSpec,
+        name: String,
+        depth: usize,
+    ) -> Result<FieldNode, Mismatch> {
+        let start = self.pos;
+        if let Some(contents) = &spec.contents {
+            let pattern = BytePattern::parse_hex(contents).map_err(|_| Mismatch)?;
+            if !pattern.matches_at(&self.data[..self.end], start) {
+                return Err(Mismatch);
+            }
+            self.pos += pattern.len();
+            return Ok(FieldNode {
+                name,
+                range: start..self.pos,
+                value: hex(&self.data[start..self.pos]),
+                children: vec![],
+            });
+        }
+
+        let size = match (&spec.size, spec.kind()) {
+            (_, SpecType::Builtin(kind)) if kind.size().is_some() => kind.size(),
+            (Some(size), _) => match self.amount(size) {
+                Some(size) => Some(size),
+                None => return Ok(self.missing(name)),
+            },
+            (None, _) => None,
+        };
+        // a size read from the data may be anything, so the end is checked:
+        let field_end = match size.map(|size| start.checked_add(size)) {
+            Some(Some(end)) if end <= self.end => Some(end),
+            Some(_) => return Ok(self.missing(name)),
+            None => None,
+        };
+
+        match spec.kind() {
+            SpecType::Struct(type_name) => {
+                let Some(fields) = self.protocol.types.get(type_name) else {
+                    return Ok(self.missing(name));
+                };
+                if depth >= MAX_DEPTH {
+                    return Ok(self.missing(name));
+                }
+                let end = self.end;
+                if let Some(field_end) = field_end {
+                    self.end = field_end;
+                }
+                let children = self.fields(fields, depth + 1);
+                if field_end.is_some() {
+                    self.pos = self.end;
+                }
+                self.end = end;
+                Ok(FieldNode {
+                    name,
+                    range: start..self.pos,
+                    value: String::new(),
+                    children: children?,
+                })
+            }
+            SpecType::Builtin(kind) => {
+                let end = field_end.unwrap_or(start);
+                let bytes = &self.data[start..end];
+                self.pos = end;
+                let endianness = spec.endianness.unwrap_or(self.protocol.endianness);
+                let value = read_value(*kind, bytes, endianness);
+                if let Some(Value::Integer(raw)) = &value {
+                    self.values.push((spec.name.clone(), *raw));
+                }
+                let value = match value {
+                    Some(value) => format_value(
+                        &value,
+                        spec.scale,
+                        spec.add,
+                        spec.unit.as_deref(),
+                        &spec.values,
+                    ),
+                    None => hex(bytes),
+                };
+                Ok(FieldNode {
+                    name,
+                    range: start..end,
+                    value,
+                    children: vec![],
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SENSOR_BUS: &str = r#"
+name = "Sensor bus"
+endianness = "big"
+id_field = "id"
+
+header = [
+  { name = "sync", contents = "AA 55" },
+  { name = "id", type = "u8" },
+  { name = "length", type = "u8" },
+]
+trailer = [{ name = "crc", type = "u16" }]
+
+[types]
+point = [
+  { name = "channel", type = "u8" },
+  { name = "value", type = "i16", scale = 0.1, unit = "V" },
+]
+
+[[messages]]
+name = "Readings"
+id = 1
+direction = "Rx"
+fields = [
+  { name = "state", type = "u8", values = { 0 = "Idle", 1 = "Running" } },
+  { name = "points", type = "point", repeat = "length - 1" },
+]
+
+[[messages]]
+name = "Text"
+id = 2
+fields = [{ name = "text", type = "ascii", size = "rest - 2" }]
+"#;
+
+    fn names(nodes: &[FieldNode]) -> Vec<(String, String)> {
+        nodes
+            .iter()
+            .map(|node| (node.name.clone(), node.value.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn test_decode_nested_and_variable_fields() {
+        let protocol: Protocol = toml::from_str(SENSOR_BUS).unwrap();
+        protocol.check().unwrap();
+
+        let frame = [
+            0xAA, 0x55, 0x01, 0x03, 0x01, 0x07, 0x00, 0x7B, 0x08, 0xFF, 0xF6, 0x12, 0x34,
+        ];
+        let decoded = protocol.decode(&frame, &RxTx::Rx).unwrap();
+        assert_eq!(decoded.message, "Readings");
+        assert!(!decoded.truncated);
+        assert_eq!(
+            names(&decoded.fields)[3..],
+            [
+                ("state".to_string(), "Running (1)".to_string()),
+                ("points".to_string(), "2 items".to_string()),
+                ("crc".to_string(), "4660".to_string()),
+            ]
+        );
+        let points = &decoded.fields[4].children;
+        assert_eq!(points[1].name, "points[1]");
+        assert_eq!(points[1].range, 8..11);
+        assert_eq!(
+            names(&points[1].children),
+            [
+                ("channel".to_string(), "8".to_string()),
+                ("value".to_string(), "-1 V".to_string()),
+            ]
+        );
+
+        // readings are only received:
+        assert!(protocol.decode(&frame, &RxTx::Tx).is_none());
+        // a wrong sync word:
+        assert!(protocol.decode(&[0xAA, 0x00, 0x01], &RxTx::Rx).is_none());
+    }
+
+    #[test]
+    fn test_truncated_and_unparsed_frames() {
+        let protocol: Protocol = toml::from_str(SENSOR_BUS).unwrap();
+        let decoded = protocol
+            .decode(&[0xAA, 0x55, 0x01, 0x03, 0x00, 0x07], &RxTx::Rx)
+            .unwrap();
+        assert!(decoded.truncated);
+        assert_eq!(decoded.fields[5].value, "-");
+
+        let decoded = protocol
+            .decode(b"\xAA\x55\x02\x00OK\x00\x00", &RxTx::Tx)
+            .unwrap();
+        assert_eq!(decoded.message, "Text");
+        assert_eq!(decoded.fields[3].value, "OK");
+
+        let decoded = protocol
+            .decode(&[0xAA, 0x55, 0x01, 0x01, 0x00, 0x00, 0x00, 0x42], &RxTx::Rx)
+            .unwrap();
+        assert_eq!(
+            names(&decoded.fields[6..]),
+            [("(unparsed)".to_string(), "42".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_check_protocol() {
+        assert_eq!(parse_expression("length - 2"), Some(("length", -2)));
+        assert_eq!(parse_expression("rest"), Some(("rest", 0)));
+        assert_eq!(parse_expression("a b"), None);
+
+        let mut protocol: Protocol = toml::from_str(SENSOR_BUS).unwrap();
+        protocol.messages[0].fields[1].kind = Some(SpecType::Struct("unknown".to_string()));
+        assert!(protocol.check().is_err());
+
+        let mut protocol: Protocol = toml::from_str(SENSOR_BUS).unwrap();
+        protocol.id_field = None;
+        assert!(protocol.check().is_err());
+
+        let mut protocol: Protocol = toml::from_str(SENSOR_BUS).unwrap();
+        protocol.messages[1].fields[0].size = None;
+        assert!(protocol.check().is_err());
+    }
+
+    #[test]
+    fn test_huge_length_field() {
+        let protocol: Protocol = toml::from_str(
+            r#"
+name = "Huge"
+id_field = "id"
+header = [{ name = "id", type = "u8" }]
+
+[types]
+blob = [{ name = "data", type = "bytes", size = "rest" }]
+
+[[messages]]
+name = "Bytes"
+id = 1
+fields = [
+  { name = "len", type = "u64" },
+  { name = "payload", type = "bytes", size = "len" },
+]
+
+[[messages]]
+name = "Struct"
+id = 2
+fields = [
+  { name = "len", type = "u64" },
+  { name = "inner", type = "blob", size = "len" },
+]
+"#,
+        )
+        .unwrap();
+        protocol.check().unwrap();
+
+        for id in [0x01, 0x02] {
+            let mut frame = vec![id];
+            frame.extend(0xFFFF_FFFF_FFFF_FFFEu64.to_le_bytes());
+            frame.push(0x00);
+            let decoded = protocol.decode(&frame, &RxTx::Rx).unwrap();
+            assert!(decoded.truncated);
+            assert_eq!(decoded.fields[2].value, "-");
+        }
+    }
+}
@@ -0,0 +1,335 @@
+use std::{
+    io::ErrorKind,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use snafu::{prelude::*, Whatever};
+
+use crate::{
+    portthread::{RxTx, SerialContext, SerialStateMessage, Transport},
+    session::Session,
+};
+
+/// How long a read waits at most, if no frame is due. Kept short, so that
+/// the port thread stays responsive to commands.
+const IDLE_TIMEOUT: Duration = Duration::from_millis(10);
+
+const MIN_REPLAY_SPEED: f64 = 1.0 / 64.0;
+const MAX_REPLAY_SPEED: f64 = 64.0;
+
+/// A single received frame of the recording.
+#[derive(Debug)]
+struct ReplayFrame {
+    /// Time since the first received frame of the recording.
+    offset: Duration,
+    data: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct ReplayState {
+    /// Index of the next frame to emit.
+    position: usize,
+    /// Number of bytes of the frame at `position` that have already been emitted.
+    byte_offset: usize,
+    paused: bool,
+    speed: f64,
+    /// Number of frames to emit regardless of timing and pause state.
+    pending_steps: usize,
+    /// Wall clock time at which the recording was at the given offset.
+    anchor: Option<(Instant, Duration)>,
+}
+
+/// Plays back the RX frames of a recorded session. The replay is controlled
+/// from the UI (pause, step, seek, speed), while the data is emitted by a
+/// `ReplayTransport` in the port thread, so it ends up in the history exactly
+/// like data from a real device.
+#[derive(Debug, Clone)]
+pub struct Replay {
+    frames: Arc<Vec<ReplayFrame>>,
+    state: Arc<Mutex<ReplayState>>,
+    pty: Option<Arc<ReplayPty>>,
+}
+
+/// Pseudo terminal the replayed data is mirrored to, so other software
+/// can consume the replay like a real device.
+#[derive(Debug)]
+struct ReplayPty {
+    master: serial2::SerialPort,
+    // the slave side is kept open, otherwise writes to the master fail
+    // as long as no other process has opened it.
+    _slave: serial2::SerialPort,
+    path: PathBuf,
+}
+
+impl Replay {
+    /// Creates a replay of all RX entries of the session.
+    pub fn from_session(session: &Session, speed: f64, paused: bool) -> Replay {
+        let mut start = None;
+        let frames = session
+            .history
+            .iter()
+            .filter_map(|msg| match msg {
+                SerialStateMessage::DataEvent(entry) if entry.rx_tx == RxTx::Rx => {
+                    let start = *start.get_or_insert(entry.timestamp);
+                    Some(ReplayFrame {
+                        offset: (entry.timestamp - start).to_std().unwrap_or_default(),
+                        data: entry.data.clone(),
+                    })
+                }
+                _ => None,
+            })
+            .collect();
+
+        Replay {
+            frames: Arc::new(frames),
+            state: Arc::new(Mutex::new(ReplayState {
+                position: 0,
+                byte_offset: 0,
+                paused,
+                speed: speed.clamp(MIN_REPLAY_SPEED, MAX_REPLAY_SPEED),
+                pending_steps: 0,
+                anchor: None,
+            })),
+            pty: None,
+        }
+    }
+
+    /// Mirrors all replayed data to a newly created pseudo terminal and
+    /// returns the path of the terminal other programs can open.
+    pub fn open_pty(&mut self) -> Result<PathBuf, Whatever> {
+        let (master, slave) =
+            serial2::SerialPort::pair().whatever_context("Failed to create pseudo terminal")?;
+        let path = pty_path(&slave)?;
+        self.pty = Some(Arc::new(ReplayPty {
+            master,
+            _slave: slave,
+            path: path.clone(),
+        }));
+        Ok(path)
+    }
+
+    /// Creates a context the port thread can be started with.
+    pub fn create_context(&self) -> SerialContext {
+        SerialContext::with_transport(
+            "Replay".to_string(),
+            Box::new(ReplayTransport {
+                replay: self.clone(),
+            }),
+        )
+    }
+
+    pub fn toggle_pause(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.paused = !state.paused;
+        state.anchor = None;
+    }
+
+    /// Emits the next frame immediately and pauses the replay.
+    pub fn step(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.paused = true;
+        state.pending_steps += 1;
+    }
+
+    /// Moves the replay position by the given number of frames.
+    pub fn seek(&self, frames: isize) {
+        let mut state = self.state.lock().unwrap();
+        state.position = state
+            .position
+            .saturating_add_signed(frames)
+            .min(self.frames.len());
+        state.byte_offset = 0;
+        state.pending_steps = 0;
+        state.anchor = None;
+    }
+
+    /// Multiplies the replay speed by the given factor.
+    pub fn scale_speed(&self, factor: f64) {
+        let mut state = self.state.lock().unwrap();
+        state.speed = (state.speed * factor).clamp(MIN_REPLAY_SPEED, MAX_REPLAY_SPEED);
+        state.anchor = None;
+    }
+
+    /// A short description of the replay state for the UI.
+    pub fn status(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let run_state = if state.position >= self.frames.len() {
+            "finished"
+        } else if state.paused {
+            "paused"
+        } else {
+            "playing"
+        };
+        let mut status = format!(
+            "Replay {}/{} x{} {}",
+            state.position,
+            self.frames.len(),
+            state.speed,
+            run_state
+        );
+        if let Some(pty) = &self.pty {
+            status += &format!(", PTY {}", pty.path.display());
+        }
+        status
+    }
+
+    /// Returns the data to emit next, if any is due. Otherwise returns the time
+    /// to wait before asking again.
+    fn next_data(&self, max_len: usize) -> Result<Vec<u8>, Duration> {
+        let mut state = self.state.lock().unwrap();
+        let Some(frame) = self.frames.get(state.position) else {
+            return Err(IDLE_TIMEOUT);
+        };
+
+        if state.byte_offset == 0 {
+            if state.pending_steps > 0 {
+                state.pending_steps -= 1;
+            } else if state.paused {
+                return Err(IDLE_TIMEOUT);
+            } else {
+                // keep the gap to the previous frame when (re)starting:
+                let previous = match state.position {
+                    0 => frame.offset,
+                    pos => self.frames[pos - 1].offset,
+                };
+                let (instant, offset) = *state.anchor.get_or_insert((Instant::now(), previous));
+                let due = instant + frame.offset.saturating_sub(offset).div_f64(state.speed);
+                let now = Instant::now();
+                if now < due {
+                    return Err((due - now).min(IDLE_TIMEOUT));
+                }
+            }
+        }
+
+        // a frame may be larger than the read buffer, the rest is emitted by the next read:
+        let end = (state.byte_offset + max_len).min(frame.data.len());
+        let data = frame.data[state.byte_offset..end].to_vec();
+        if end == frame.data.len() {
+            state.position += 1;
+            state.byte_offset = 0;
+        } else {
+            state.byte_offset = end;
+        }
+        Ok(data)
+    }
+}
+
+#[cfg(unix)]
+fn pty_path(slave: &serial2::SerialPort) -> Result<PathBuf, Whatever> {
+    use std::os::unix::io::AsRawFd;
+    std::fs::read_link(format!("/proc/self/fd/{}", slave.as_raw_fd()))
+        .whatever_context("Failed to determine the path of the pseudo terminal")
+}
+
+#[cfg(not(unix))]
+fn pty_path(_slave: &serial2::SerialPort) -> Result<PathBuf, Whatever> {
+    whatever!("Pseudo terminals are not supported on this platform")
+}
+
+/// The transport the port thread uses while replaying.
+#[derive(Debug)]
+pub struct ReplayTransport {
+    replay: Replay,
+}
+
+impl Transport for ReplayTransport {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        match self.replay.next_data(buffer.len()) {
+            Ok(data) => {
+                if let Some(pty) = &self.replay.pty {
+                    // the replay must go on, even if nobody listens on the PTY
+                    let _ = pty.master.write(&data);
+                }
+                buffer[..data.len()].copy_from_slice(&data);
+                Ok(data.len())
+            }
+            Err(wait) => {
+                thread::sleep(wait);
+                Err(ErrorKind::TimedOut.into())
+            }
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        // there is no device to talk to, anything sent is only shown in the history
+        Ok(data.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Local, TimeDelta};
+
+    use crate::portthread::HistoryEntry;
+
+    use super::*;
+
+    fn session() -> Session {
+        let start = Local::now();
+        let entry = |ms: i64, rx_tx: RxTx, data: Vec<u8>| {
+            SerialStateMessage::DataEvent(HistoryEntry {
+                timestamp: start + TimeDelta::milliseconds(ms),
+                rx_tx,
+                data,
+                ..Default::default()
+            })
+        };
+        Session {
+            version: crate::session::SESSION_VERSION,
+            settings: serde_json::from_value(serde_json::json!({
+                "port": "",
+                "baud": 9600,
+                "stopbits": 1,
+                "parity": "None",
+                "databits": 8,
+                "display_mode": "Hex",
+            }))
+            .unwrap(),
+            history: vec![
+                SerialStateMessage::Started,
+                entry(0, RxTx::Rx, vec![1]),
+                entry(10, RxTx::Tx, vec![2]),
+                entry(50, RxTx::Rx, vec![3, 4, 5]),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_replay_keeps_timing() {
+        let replay = Replay::from_session(&session(), 1.0, false);
+        let started = Instant::now();
+        assert_eq!(replay.next_data(256), Ok(vec![1]));
+        let wait = replay.next_data(256).unwrap_err();
+        assert!(wait > Duration::ZERO && wait <= IDLE_TIMEOUT);
+
+        let data = loop {
+            match replay.next_data(256) {
+                Ok(data) => break data,
+                Err(wait) => thread::sleep(wait),
+            }
+        };
+        assert_eq!(data, vec![3, 4, 5]);
+        assert!(started.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_step_and_seek() {
+        let replay = Replay::from_session(&session(), 1.0, true);
+        assert!(replay.next_data(256).is_err());
+
+        replay.step();
+        assert_eq!(replay.next_data(256), Ok(vec![1]));
+        assert!(replay.next_data(256).is_err());
+
+        replay.seek(-5);
+        replay.step();
+        assert_eq!(replay.next_data(2), Ok(vec![1]));
+        replay.step();
+        assert_eq!(replay.next_data(2), Ok(vec![3, 4]));
+        assert_eq!(replay.next_data(2), Ok(vec![5]));
+        assert!(replay.status().contains("finished"));
+    }
+}
@@ -0,0 +1,394 @@
+use std::{collections::BTreeMap, fmt::Display, ops::Range};
+
+use serde::{Deserialize, Serialize};
+use snafu::{prelude::*, Whatever};
+
+use crate::{
+    history::History,
+    pattern::BytePattern,
+    portthread::{RxTx, SerialStateMessage},
+};
+
+/// Consecutive entries are searched as one stream of bytes, so that matches
+/// spanning several entries are found. To limit memory usage, streams are
+/// split after this number of bytes.
+const MAX_STREAM_LEN: usize = 1024 * 1024;
+
+/// The last bytes of a split stream are searched again as the start of the next
+/// stream, so that matches up to this length are found across the split.
+const STREAM_OVERLAP: usize = 4096;
+
+pub const SEARCH_KINDS: [SearchKind; 3] = [SearchKind::Text, SearchKind::Hex, SearchKind::Regex];
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SearchKind {
+    /// The input is searched as ASCII text.
+    #[default]
+    Text,
+    /// The input is a sequence of hex bytes, `??` matches any byte.
+    Hex,
+    /// The input is a regular expression, matched against the raw bytes.
+    /// Unicode is disabled, so `.` and `\xNN` match a single byte.
+    Regex,
+}
+
+impl SearchKind {
+    /// Explains how the input is matched, shown in the prompt title.
+    pub fn hint(&self) -> &'static str {
+        match self {
+            SearchKind::Text => "",
+            SearchKind::Hex => " bytes, ?? = any",
+            SearchKind::Regex => " on raw bytes, \\xNN = byte",
+        }
+    }
+}
+
+impl Display for SearchKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SearchKind::Text => write!(f, "Text"),
+            SearchKind::Hex => write!(f, "Hex"),
+            SearchKind::Regex => write!(f, "Regex"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum SearchQuery {
+    Bytes(BytePattern),
+    Regex(regex::bytes::Regex),
+}
+
+impl SearchQuery {
+    pub fn parse(kind: SearchKind, input: &str) -> Result<SearchQuery, Whatever> {
+        if input.is_empty() {
+            whatever!("Empty search");
+        }
+        match kind {
+            SearchKind::Text => Ok(SearchQuery::Bytes(BytePattern::from_bytes(
+                input.as_bytes(),
+            ))),
+            SearchKind::Hex => Ok(SearchQuery::Bytes(BytePattern::parse_hex(input)?)),
+            SearchKind::Regex => regex::bytes::RegexBuilder::new(input)
+                .unicode(false)
+                .build()
+                .map(SearchQuery::Regex)
+                .with_whatever_context(|_| format!("Invalid regular expression '{}'", input)),
+        }
+    }
+
+    /// Returns true, if `data` contains at least one match.
+    pub fn is_match(&self, data: &[u8]) -> bool {
+        match self {
+            SearchQuery::Bytes(pattern) => pattern.find(data).is_some(),
+            SearchQuery::Regex(regex) => regex.find_iter(data).any(|m| !m.is_empty()),
+        }
+    }
+
+    /// Returns the ranges of all non overlapping, non empty matches in `data`.
+    pub fn find_all(&self, data: &[u8]) -> Vec<Range<usize>> {
+        match self {
+            SearchQuery::Bytes(pattern) => {
+                let mut matches = vec![];
+                let mut pos = 0;
+                while let Some(found) = pattern.find(&data[pos..]) {
+                    let start = pos + found;
+                    let end = start + pattern.len();
+                    matches.push(start..end);
+                    pos = end;
+                }
+                matches
+            }
+            SearchQuery::Regex(regex) => regex
+                .find_iter(data)
+                .filter(|m| !m.is_empty())
+                .map(|m| m.range())
+                .collect(),
+        }
+    }
+}
+
+/// A single match, which may span several entries.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchMatch {
+    /// Index of the history entry and the range of bytes in that entry, for
+    /// every entry the match covers.
+    pub segments: Vec<(usize, Range<usize>)>,
+}
+
+/// The matches of a search and the match that was jumped to last.
+#[derive(Debug, Default)]
+pub struct SearchResult {
+    matches: Vec<SearchMatch>,
+    /// For every entry with matches: the highlighted ranges and the index of their match.
+    by_entry: BTreeMap<usize, Vec<(Range<usize>, usize)>>,
+    current: usize,
+}
+
+impl SearchResult {
+    fn add(&mut self, m: SearchMatch) {
+        let index = self.matches.len();
+        for (entry, range) in &m.segments {
+            self.by_entry
+                .entry(*entry)
+                .or_default()
+                .push((range.clone(), index));
+        }
+        self.matches.push(m);
+    }
+
+    pub fn len(&self) -> usize {
+        self.matches.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.matches.is_empty()
+    }
+
+    pub fn current_index(&self) -> usize {
+        self.current
+    }
+
+    pub fn current(&self) -> Option<&SearchMatch> {
+        self.matches.get(self.current)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &SearchMatch> {
+        self.matches.iter()
+    }
+
+    /// Ranges to highlight in the entry with the given index, together with a flag
+    /// that tells, if the range belongs to the current match.
+    pub fn highlights(&self, entry: usize) -> Vec<(Range<usize>, bool)> {
+        self.by_entry
+            .get(&entry)
+            .map(|ranges| {
+                ranges
+                    .iter()
+                    .map(|(range, index)| (range.clone(), *index == self.current))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Selects the newest match.
+    pub fn select_last(&mut self) {
+        self.current = self.matches.len().saturating_sub(1);
+    }
+
+    /// Selects the next (newer) match, wrapping around at the end.
+    pub fn select_next(&mut self) {
+        if !self.matches.is_empty() {
+            self.current = (self.current + 1) % self.matches.len();
+        }
+    }
+
+    /// Selects the previous (older) match, wrapping around at the start.
+    pub fn select_previous(&mut self) {
+        if !self.matches.is_empty() {
+            self.current = (self.current + self.matches.len() - 1) % self.matches.len();
+        }
+    }
+}
+
+/// Consecutive data entries of the same direction, concatenated into one stream.
+#[derive(Default)]
+struct Stream {
+    direction: Option<RxTx>,
+    data: Vec<u8>,
+    /// Index of every entry in the stream and the offset of its first byte.
+    entries: Vec<(usize, usize)>,
+    /// Number of bytes of the first entry that are not part of the stream,
+    /// because they were searched with the previous stream.
+    skip: usize,
+}
+
+impl Stream {
+    /// Adds the matches in the stream to `result` and starts a new stream.
+    fn search(&mut self, query: &SearchQuery, result: &mut SearchResult) {
+        for found in query.find_all(&self.data) {
+            result.add(self.to_match(found));
+        }
+        *self = Stream::default();
+    }
+
+    /// Adds the matches starting before the last `STREAM_OVERLAP` bytes to
+    /// `result`. The remaining bytes are kept as the start of the next stream,
+    /// where matches starting in them are found.
+    fn search_and_split(&mut self, query: &SearchQuery, result: &mut SearchResult) {
+        let cut = self.data.len().saturating_sub(STREAM_OVERLAP);
+        for found in query.find_all(&self.data) {
+            if found.start < cut {
+                result.add(self.to_match(found));
+            }
+        }
+
+        let first = self.entries.partition_point(|(_, start)| *start <= cut) - 1;
+        let (index, start) = self.entries[first];
+        let skip = if first == 0 { self.skip } else { 0 } + cut - start;
+        self.entries = self.entries[first..]
+            .iter()
+            .map(|(index, start)| (*index, start.saturating_sub(cut)))
+            .collect();
+        self.entries[0] = (index, 0);
+        self.data.drain(..cut);
+        self.skip = skip;
+    }
+
+    /// Converts a range of the stream to the ranges of the entries it covers.
+    fn to_match(&self, found: Range<usize>) -> SearchMatch {
+        let segments = self
+            .entries
+            .iter()
+            .enumerate()
+            .filter_map(|(i, (entry, start))| {
+                let end = self
+                    .entries
+                    .get(i + 1)
+                    .map_or(self.data.len(), |(_, next)| *next);
+                let from = found.start.max(*start);
+                let to = found.end.min(end);
+                let skip = if i == 0 { self.skip } else { 0 };
+                (from < to).then(|| (*entry, from - start + skip..to - start + skip))
+            })
+            .collect();
+        SearchMatch { segments }
+    }
+}
+
+/// Searches the whole history. Matches may span several consecutive entries of
+/// the same direction.
+pub fn search(history: &History, query: &SearchQuery) -> SearchResult {
+    let mut result = SearchResult::default();
+    let mut stream = Stream::default();
+
+    for (index, msg) in history.iter() {
+        let SerialStateMessage::DataEvent(entry) = msg else {
+            stream.search(query, &mut result);
+            continue;
+        };
+        if stream.direction.as_ref() != Some(&entry.rx_tx) {
+            stream.search(query, &mut result);
+            stream.direction = Some(entry.rx_tx.clone());
+        } else if stream.data.len() > MAX_STREAM_LEN {
+            stream.search_and_split(query, &mut result);
+        }
+        stream.entries.push((index, stream.data.len()));
+        stream.data.extend_from_slice(&entry.data);
+    }
+    stream.search(query, &mut result);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::portthread::HistoryEntry;
+
+    use super::*;
+
+    fn history(messages: Vec<SerialStateMessage>) -> History {
+        let mut history = History::default();
+        for msg in messages {
+            history.push(msg);
+        }
+        history
+    }
+
+    fn data(rx_tx: RxTx, data: &[u8]) -> SerialStateMessage {
+        SerialStateMessage::DataEvent(HistoryEntry {
+            rx_tx,
+            data: data.to_vec(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_text_search_spans_entries() {
+        let history = history(vec![
+            data(RxTx::Rx, b"hel"),
+            data(RxTx::Rx, b"lo hello"),
+            data(RxTx::Tx, b"hello"),
+        ]);
+        let query = SearchQuery::parse(SearchKind::Text, "hello").unwrap();
+        let result = search(&history, &query);
+
+        assert_eq!(result.len(), 3);
+        assert_eq!(result.matches[0].segments, vec![(0, 0..3), (1, 0..2)]);
+        assert_eq!(result.matches[1].segments, vec![(1, 3..8)]);
+        assert_eq!(result.matches[2].segments, vec![(2, 0..5)]);
+        assert_eq!(result.highlights(1), vec![(0..2, true), (3..8, false)]);
+    }
+
+    #[test]
+    fn test_direction_change_and_markers_split_streams() {
+        let history = history(vec![
+            data(RxTx::Rx, b"ab"),
+            data(RxTx::Tx, b"cd"),
+            SerialStateMessage::Stopped,
+            data(RxTx::Tx, b"ef"),
+        ]);
+        let query = SearchQuery::parse(SearchKind::Text, "bc").unwrap();
+        assert!(search(&history, &query).is_empty());
+        let query = SearchQuery::parse(SearchKind::Text, "de").unwrap();
+        assert!(search(&history, &query).is_empty());
+    }
+
+    #[test]
+    fn test_hex_and_regex_search() {
+        let history = history(vec![data(RxTx::Rx, &[0xAA, 0x10, 0x01, b'O', b'K'])]);
+        let query = SearchQuery::parse(SearchKind::Hex, "AA ?? 01").unwrap();
+        assert_eq!(
+            search(&history, &query).matches[0].segments,
+            vec![(0, 0..3)]
+        );
+
+        let query = SearchQuery::parse(SearchKind::Regex, "O[KX]").unwrap();
+        assert_eq!(
+            search(&history, &query).matches[0].segments,
+            vec![(0, 3..5)]
+        );
+        assert!(SearchQuery::parse(SearchKind::Regex, "(").is_err());
+    }
+
+    #[test]
+    fn test_match_across_stream_split() {
+        let mut first = vec![0u8; MAX_STREAM_LEN + 1];
+        let len = first.len();
+        first[len - 2..].copy_from_slice(b"he");
+        let history = history(vec![data(RxTx::Rx, &first), data(RxTx::Rx, b"llo hello")]);
+        let query = SearchQuery::parse(SearchKind::Text, "hello").unwrap();
+        let result = search(&history, &query);
+
+        assert_eq!(result.len(), 2);
+        assert_eq!(
+            result.matches[0].segments,
+            vec![(0, len - 2..len), (1, 0..3)]
+        );
+        assert_eq!(result.matches[1].segments, vec![(1, 4..9)]);
+    }
+
+    #[test]
+    fn test_regex_matches_bytes() {
+        let history = history(vec![data(RxTx::Rx, &[0x01, 0xFF, 0x02])]);
+        let query = SearchQuery::parse(SearchKind::Regex, r"\xFF.").unwrap();
+        assert_eq!(
+            search(&history, &query).matches[0].segments,
+            vec![(0, 1..3)]
+        );
+    }
+
+    #[test]
+    fn test_select_next_and_previous_wrap() {
+        let history = history(vec![data(RxTx::Rx, b"x x x")]);
+        let mut result = search(
+            &history,
+            &SearchQuery::parse(SearchKind::Text, "x").unwrap(),
+        );
+        result.select_last();
+        assert_eq!(result.current_index(), 2);
+        result.select_next();
+        assert_eq!(result.current_index(), 0);
+        result.select_previous();
+        assert_eq!(result.current_index(), 2);
+    }
+}
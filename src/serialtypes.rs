@@ -1,3 +1,5 @@
+use crate::DisplayMode;
+
 pub const BAUD_RATES: [u32; 8] = [9600, 19200, 38400, 57600, 115200, 230400, 460800, 921600];
 
 pub const STOP_BITS: [u8; 2] = [1, 2];
@@ -48,5 +50,45 @@ pub fn control_char_to_string(c: u8) -> String {
         0x1F => "US",
         _ => " ",
     };
-    return format!("<{}>", chr);
+    format!("<{}>", chr)
+}
+
+/// Formats a single byte according to the display mode.
+pub fn format_byte_for_display(byte: u8, display_mode: DisplayMode) -> String {
+    let printable = (byte as char).is_ascii() && !(byte as char).is_control();
+    match display_mode {
+        DisplayMode::Hex => format!("{:02X}", byte),
+        // replace control bytes by their name:
+        DisplayMode::Ascii if (byte as char).is_control() => control_char_to_string(byte),
+        DisplayMode::Ascii => format!("{}", byte as char),
+        DisplayMode::Decimal => byte.to_string(),
+        // all bytes, that are printable characters are printed as such, otherwise hex
+        DisplayMode::MixedHex if printable => format!("{}", byte as char),
+        DisplayMode::MixedHex => format!("{:02X}", byte),
+        DisplayMode::MixedDec if printable => format!("{}", byte as char),
+        DisplayMode::MixedDec => byte.to_string(),
+    }
+}
+
+/// The separator between two formatted bytes.
+pub fn display_separator(display_mode: DisplayMode) -> &'static str {
+    match display_mode {
+        DisplayMode::Ascii => "",
+        _ => " ",
+    }
+}
+
+/// Formats the given bytes according to the display mode, i.e. the way they are
+/// shown in the history.
+pub fn format_data_for_display(data: &[u8], display_mode: DisplayMode) -> String {
+    let mut formatted = data
+        .iter()
+        .map(|x| format_byte_for_display(*x, display_mode))
+        .collect::<Vec<String>>()
+        .join(display_separator(display_mode));
+    // hex bytes are always followed by a space, even the last one:
+    if display_mode == DisplayMode::Hex && !data.is_empty() {
+        formatted.push(' ');
+    }
+    formatted
 }
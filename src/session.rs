@@ -0,0 +1,123 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use chrono::Local;
+use serde::Deserialize;
+use snafu::{prelude::*, Whatever};
+
+use crate::{portthread::SerialStateMessage, settings_mode::SettingsMode};
+
+/// Version of the session file format. Bump this, whenever a change breaks
+/// reading older session files.
+pub const SESSION_VERSION: u32 = 1;
+
+/// A saved session, i.e. the settings that were active and the complete history,
+/// including error, start and stop markers.
+#[derive(Debug, Deserialize)]
+pub struct Session {
+    pub version: u32,
+    pub settings: SettingsMode,
+    pub history: Vec<SerialStateMessage>,
+}
+
+/// Writes a session. The history is written entry by entry, so that large histories
+/// don't have to be held in memory as a whole.
+pub fn write_session<W: Write>(
+    writer: &mut W,
+    settings: &SettingsMode,
+    messages: impl Iterator<Item = SerialStateMessage>,
+) -> std::io::Result<()> {
+    writeln!(writer, "{{")?;
+    writeln!(writer, "  \"version\": {},", SESSION_VERSION)?;
+    writeln!(writer, "  \"settings\": {},", serde_json::to_string(settings)?)?;
+    writeln!(writer, "  \"history\": [")?;
+    for (index, msg) in messages.enumerate() {
+        if index > 0 {
+            writeln!(writer, ",")?;
+        }
+        write!(writer, "    {}", serde_json::to_string(&msg)?)?;
+    }
+    writeln!(writer, "\n  ]")?;
+    writeln!(writer, "}}")?;
+    writer.flush()
+}
+
+/// Returns a file name for a session file, based on the current time.
+pub fn default_session_path() -> PathBuf {
+    PathBuf::from(format!(
+        "klemme_session_{}.json",
+        Local::now().format("%Y%m%d_%H%M%S")
+    ))
+}
+
+pub fn save_session(
+    path: &Path,
+    settings: &SettingsMode,
+    messages: impl Iterator<Item = SerialStateMessage>,
+) -> Result<(), Whatever> {
+    let file = File::create(path)
+        .with_whatever_context(|_| format!("Failed to create {}", path.display()))?;
+    write_session(&mut BufWriter::new(file), settings, messages)
+        .with_whatever_context(|_| format!("Failed to write {}", path.display()))
+}
+
+pub fn load_session(path: &Path) -> Result<Session, Whatever> {
+    let file =
+        File::open(path).with_whatever_context(|_| format!("Failed to open {}", path.display()))?;
+    let session: Session = serde_json::from_reader(BufReader::new(file))
+        .with_whatever_context(|_| format!("{} is not a valid session file", path.display()))?;
+    if session.version > SESSION_VERSION {
+        whatever!(
+            "{} was written by a newer version of klemme (session version {})",
+            path.display(),
+            session.version
+        );
+    }
+    Ok(session)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        portthread::{HistoryEntry, RxTx},
+        DisplayMode,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_session_round_trip() {
+        let settings: SettingsMode = serde_json::from_value(serde_json::json!({
+            "port": "ttyUSB0",
+            "baud": 115200,
+            "stopbits": 1,
+            "parity": "None",
+            "databits": 8,
+            "display_mode": "Ascii",
+        }))
+        .unwrap();
+        let messages = vec![
+            SerialStateMessage::Started,
+            SerialStateMessage::DataEvent(HistoryEntry {
+                rx_tx: RxTx::Tx,
+                data: vec![0x01, 0x02],
+                ..Default::default()
+            }),
+            SerialStateMessage::ErrorEvent("Failed to write to port".to_string()),
+            SerialStateMessage::Stopped,
+        ];
+
+        let mut path = std::env::temp_dir();
+        path.push(format!("klemme_session_test_{}.json", std::process::id()));
+        save_session(&path, &settings, messages.clone().into_iter()).unwrap();
+        let session = load_session(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(session.version, SESSION_VERSION);
+        assert_eq!(session.history, messages);
+        assert_eq!(session.settings.get_display_mode(), DisplayMode::Ascii);
+    }
+}
@@ -5,19 +5,35 @@ use ratatui::{
     style::{Style, Stylize},
     symbols::border,
     text::Line,
-    widgets::{Block, Paragraph},
+    widgets::{Block, Paragraph, Wrap},
 };
 
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
 use snafu::{prelude::*, Whatever};
 
 use crate::{
+    capturelog::{CaptureLogSettings, LOG_FORMATS},
+    checksum::{RxValidation, CHECKSUMS, CHECKSUM_STARTS, CHECKSUM_TRAILERS},
+    highlight::HighlightRule,
     mode::ApplicationMode,
     portthread::{PortError, SerialContext},
     serialtypes::{BAUD_RATES, DATABITS, PARITY, STOP_BITS},
+    template::StructTemplate,
+    timing::{DeltaSettings, TimestampSettings, TIMESTAMP_STYLES, TIME_BASES},
     DisplayMode, DISPLAY_MODES,
 };
 
+/// Reads a setting, that is replaced by its default if it is invalid, e.g. a CRC
+/// with a width of 0 edited into the file. The other settings are kept.
+fn invalid_as_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned + Default,
+{
+    let value = serde_json::Value::deserialize(deserializer)?;
+    Ok(T::deserialize(value).unwrap_or_default())
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SettingsMode {
     port: String,
@@ -26,8 +42,24 @@ pub struct SettingsMode {
     parity: String,
     databits: u8,
     display_mode: DisplayMode,
+    #[serde(default)]
+    capture_log: CaptureLogSettings,
+    #[serde(default)]
+    highlight_rules: Vec<HighlightRule>,
+    #[serde(default)]
+    timestamps: TimestampSettings,
+    #[serde(default, deserialize_with = "invalid_as_default")]
+    rx_validation: RxValidation,
+    #[serde(default)]
+    templates: Vec<StructTemplate>,
+    #[serde(default)]
+    delta: DeltaSettings,
     #[serde(skip_serializing, default)]
     active: bool,
+    /// Set, if the settings were loaded from a session file. Such settings
+    /// are never written back to the .klemme file.
+    #[serde(skip)]
+    session_file: Option<String>,
 }
 
 impl ApplicationMode for SettingsMode {
@@ -39,13 +71,24 @@ impl ApplicationMode for SettingsMode {
             KeyCode::Char('a') => self.rotate_parity(),
             KeyCode::Char('d') => self.rotate_databits(),
             KeyCode::Char('m') => self.rotate_display_mode(),
+            KeyCode::Char('l') => self.rotate_log_format(),
+            KeyCode::Char('t') => self.rotate_timestamp_style(),
+            KeyCode::Char('u') => self.timestamps.utc = !self.timestamps.utc,
+            KeyCode::Char('r') => self.rotate_time_base(),
+            KeyCode::Char('v') => self.rotate_rx_checksum(),
+            KeyCode::Char('e') => self.rx_validation.big_endian = !self.rx_validation.big_endian,
+            KeyCode::Char('o') => self.rotate_rx_checksum_start(),
+            KeyCode::Char('f') => self.rotate_rx_checksum_after(),
             _ => {}
         }
         self.try_write_config_file();
     }
 
     fn render(&self, area: ratatui::prelude::Rect, buf: &mut ratatui::Frame) {
-        let top = "Settings"; //, active mode: ".to_string() + &self.mode.to_string();
+        let top = match &self.session_file {
+            Some(file) => format!("Settings (Session: {})", file),
+            None => "Settings".to_string(),
+        };
 
         let highlight_color = if self.active {
             ratatui::style::Color::Red
@@ -58,7 +101,7 @@ impl ApplicationMode for SettingsMode {
             .border_set(border::THICK)
             .border_style(Style::default().fg(highlight_color));
 
-        let opts = Paragraph::new(Line::from(vec![
+        let mut line = Line::from(vec![
             "P".fg(highlight_color),
             format!("ort:{} ", self.port).fg(ratatui::style::Color::Gray),
             "B".fg(highlight_color),
@@ -73,9 +116,40 @@ impl ApplicationMode for SettingsMode {
             "Display".fg(ratatui::style::Color::Gray),
             "M".fg(highlight_color),
             format!("ode:{} ", self.display_mode).fg(ratatui::style::Color::Gray),
-        ]));
+            "L".fg(highlight_color),
+            format!("og(F6):{} ", self.capture_log_state()).fg(ratatui::style::Color::Gray),
+            "T".fg(highlight_color),
+            format!("ime:{} ", self.timestamps.style).fg(ratatui::style::Color::Gray),
+            "U".fg(highlight_color),
+            format!("TC:{} ", if self.timestamps.utc { "On" } else { "Off" })
+                .fg(ratatui::style::Color::Gray),
+            "R".fg(highlight_color),
+            format!("elative:{} ", self.timestamps.base).fg(ratatui::style::Color::Gray),
+            "V".fg(highlight_color),
+            format!("alidate:{} ", self.rx_validation_state()).fg(ratatui::style::Color::Gray),
+        ]);
+        if self.rx_validation.checksum.is_some() {
+            line.extend([
+                "E".fg(highlight_color),
+                format!(
+                    "ndian:{} ",
+                    if self.rx_validation.big_endian {
+                        "Big"
+                    } else {
+                        "Little"
+                    }
+                )
+                .fg(ratatui::style::Color::Gray),
+                "O".fg(highlight_color),
+                format!("ffset:{} ", self.rx_validation.start).fg(ratatui::style::Color::Gray),
+                "A".fg(ratatui::style::Color::Gray),
+                "f".fg(highlight_color),
+                format!("ter:{} ", self.rx_validation.after).fg(ratatui::style::Color::Gray),
+            ]);
+        }
+        let opts = Paragraph::new(line);
 
-        buf.render_widget(opts.block(block), area);
+        buf.render_widget(opts.wrap(Wrap { trim: true }).block(block), area);
     }
 
     fn set_active_inactive(&mut self, active: bool) {
@@ -87,9 +161,8 @@ impl SettingsMode {
     fn try_load_config_file() -> Result<SettingsMode, Whatever> {
         // if a .klemme file is present in the CWD, try
         // to deserialize settings from it:
-        let path = std::env::current_dir().unwrap();
-        let path = path.to_str().unwrap();
-        if let Ok(file) = std::fs::File::open(path.to_owned() + "/.klemme") {
+        let path = std::env::current_dir().whatever_context("The CWD is not accessible")?;
+        if let Ok(file) = std::fs::File::open(path.join(".klemme")) {
             let reader = std::io::BufReader::new(file);
             let res: Result<SettingsMode, serde_json::Error> = serde_json::from_reader(reader);
 
@@ -102,20 +175,26 @@ impl SettingsMode {
     }
 
     fn try_write_config_file(&self) {
+        if self.session_file.is_some() {
+            return;
+        }
         // if a .klemme file is present in the CWD, try
         // to deserialize settings from it:
-        let path = std::env::current_dir().unwrap();
-        let path = path.to_str().unwrap();
-        let file = std::fs::File::create(path.to_owned() + "/.klemme").unwrap();
+        // the settings are kept in memory only, if the CWD is gone or not writable:
+        let Ok(path) = std::env::current_dir() else {
+            return;
+        };
+        let Ok(file) = std::fs::File::create(path.join(".klemme")) else {
+            return;
+        };
         let writer = std::io::BufWriter::new(file);
         let _ = serde_json::to_writer_pretty(writer, &self);
     }
 
     pub fn new() -> SettingsMode {
-        match SettingsMode::try_load_config_file() {
-            Ok(settings) => return settings,
-            Err(_) => {}
-        };
+        if let Ok(settings) = SettingsMode::try_load_config_file() {
+            return settings;
+        }
 
         let mut res = Self {
             port: "".to_string(),
@@ -124,7 +203,14 @@ impl SettingsMode {
             parity: PARITY[0].to_string(),
             databits: DATABITS[3],
             display_mode: DisplayMode::Hex,
+            capture_log: CaptureLogSettings::default(),
+            highlight_rules: vec![],
+            timestamps: TimestampSettings::default(),
+            rx_validation: RxValidation::default(),
+            templates: vec![],
+            delta: DeltaSettings::default(),
             active: false,
+            session_file: None,
         };
         res.rotate_port();
         res
@@ -134,6 +220,141 @@ impl SettingsMode {
         self.display_mode
     }
 
+    /// Marks the settings as belonging to the given session file.
+    pub fn set_session_file(&mut self, file: &str) {
+        self.session_file = Some(file.to_string());
+    }
+
+    pub fn get_port(&self) -> &str {
+        &self.port
+    }
+
+    pub fn get_capture_log_settings(&self) -> CaptureLogSettings {
+        self.capture_log.clone()
+    }
+
+    pub fn get_highlight_rules(&self) -> Vec<HighlightRule> {
+        self.highlight_rules.clone()
+    }
+
+    /// Replaces the highlight rules and persists them.
+    pub fn set_highlight_rules(&mut self, rules: Vec<HighlightRule>) {
+        self.highlight_rules = rules;
+        self.try_write_config_file();
+    }
+
+    pub fn get_delta_settings(&self) -> DeltaSettings {
+        self.delta
+    }
+
+    /// Replaces the delta settings and persists them.
+    pub fn set_delta_settings(&mut self, delta: DeltaSettings) {
+        self.delta = delta;
+        self.try_write_config_file();
+    }
+
+    pub fn get_templates(&self) -> Vec<StructTemplate> {
+        self.templates.clone()
+    }
+
+    pub fn get_timestamp_settings(&self) -> &TimestampSettings {
+        &self.timestamps
+    }
+
+    pub fn get_rx_validation(&self) -> RxValidation {
+        self.rx_validation
+    }
+
+    fn rx_validation_state(&self) -> String {
+        match self.rx_validation.checksum {
+            Some(checksum) => checksum.to_string(),
+            None => "Off".to_string(),
+        }
+    }
+
+    /// Rotates the checksum received frames are validated with, starting with none.
+    fn rotate_rx_checksum(&mut self) {
+        let selected_idx = CHECKSUMS
+            .iter()
+            .position(|x| Some(*x) == self.rx_validation.checksum);
+        self.rx_validation.checksum = match selected_idx {
+            None => Some(CHECKSUMS[0]),
+            Some(idx) => CHECKSUMS.get(idx + 1).copied(),
+        };
+    }
+
+    fn rotate_rx_checksum_start(&mut self) {
+        let mut selected_idx = CHECKSUM_STARTS
+            .iter()
+            .position(|&x| x == self.rx_validation.start)
+            .unwrap_or(0);
+        selected_idx += 1;
+        selected_idx %= CHECKSUM_STARTS.len();
+        self.rx_validation.start = CHECKSUM_STARTS[selected_idx];
+    }
+
+    fn rotate_rx_checksum_after(&mut self) {
+        let mut selected_idx = CHECKSUM_TRAILERS
+            .iter()
+            .position(|&x| x == self.rx_validation.after)
+            .unwrap_or(0);
+        selected_idx += 1;
+        selected_idx %= CHECKSUM_TRAILERS.len();
+        self.rx_validation.after = CHECKSUM_TRAILERS[selected_idx];
+    }
+
+    fn capture_log_state(&self) -> String {
+        if self.capture_log.enabled {
+            self.capture_log.format.to_string()
+        } else {
+            "Off".to_string()
+        }
+    }
+
+    /// Enables or disables capture logging and persists the new state.
+    pub fn toggle_capture_log(&mut self) {
+        self.capture_log.enabled = !self.capture_log.enabled;
+        self.try_write_config_file();
+    }
+
+    fn rotate_log_format(&mut self) {
+        let mut selected_idx = LOG_FORMATS
+            .iter()
+            .position(|&x| x == self.capture_log.format)
+            .unwrap_or(0);
+        selected_idx += 1;
+        selected_idx %= LOG_FORMATS.len();
+        self.capture_log.format = LOG_FORMATS[selected_idx];
+    }
+
+    fn rotate_timestamp_style(&mut self) {
+        let mut selected_idx = TIMESTAMP_STYLES
+            .iter()
+            .position(|&x| x == self.timestamps.style)
+            .unwrap_or(0);
+        selected_idx += 1;
+        selected_idx %= TIMESTAMP_STYLES.len();
+        self.timestamps.style = TIMESTAMP_STYLES[selected_idx];
+    }
+
+    fn rotate_time_base(&mut self) {
+        let mut selected_idx = TIME_BASES
+            .iter()
+            .position(|&x| x == self.timestamps.base)
+            .unwrap_or(0);
+        selected_idx += 1;
+        selected_idx %= TIME_BASES.len();
+        self.timestamps.base = TIME_BASES[selected_idx];
+    }
+
+    /// Transmission time of one character: start bit, data bits, parity bit
+    /// and stop bits.
+    fn char_time_ns(&self) -> u32 {
+        let parity_bits = if self.parity == "None" { 0 } else { 1 };
+        let bits = 1 + self.databits as u64 + parity_bits + self.stopbits as u64;
+        (bits * 1_000_000_000 / self.baud.max(1) as u64) as u32
+    }
+
     pub fn create_serial_context(&self) -> Result<SerialContext, PortError> {
         let the_port = serial2::SerialPort::open(&self.port, |mut settings: serial2::Settings| {
             let _ = settings.set_baud_rate(self.baud);
@@ -174,22 +395,22 @@ impl SettingsMode {
         });
 
         if let Ok(mut p) = the_port {
-            if let Err(_) = p.set_read_timeout(Duration::from_millis(125)) {
+            if p.set_read_timeout(Duration::from_millis(125)).is_err() {
                 return Err(PortError::BadSettings);
             }
-            if let Err(_) = p.set_write_timeout(Duration::from_millis(2500)) {
+            if p.set_write_timeout(Duration::from_millis(2500)).is_err() {
                 return Err(PortError::BadSettings);
             }
-            if let Err(_) = p.flush() {
+            if p.flush().is_err() {
                 return Err(PortError::FailedToFlush);
             }
-            if let Err(_) = p.discard_buffers() {
+            if p.discard_buffers().is_err() {
                 return Err(PortError::BadSettings);
             }
 
-            return Ok(SerialContext::new(self.port.clone(), p));
+            Ok(SerialContext::new(self.port.clone(), p).with_char_time(self.char_time_ns()))
         } else {
-            return Err(PortError::FailedToOpen);
+            Err(PortError::FailedToOpen)
         }
     }
 
@@ -202,7 +423,7 @@ impl SettingsMode {
         // enumerate comports
         let mut port_found = false;
         if let Ok(ports) = serial2::SerialPort::available_ports() {
-            if ports.len() == 0 {
+            if ports.is_empty() {
                 self.port = "".to_string();
                 return;
             }
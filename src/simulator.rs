@@ -0,0 +1,342 @@
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{BufReader, ErrorKind},
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+use snafu::{prelude::*, Whatever};
+
+use crate::{
+    pattern::BytePattern,
+    portthread::{SerialContext, Transport},
+};
+
+/// How long a read waits at most, if no response is due.
+const IDLE_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// Bytes as given in the rule file, either as hex string or as text.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleBytes {
+    Hex(String),
+    Text(String),
+}
+
+impl RuleBytes {
+    fn to_pattern(&self) -> Result<BytePattern, Whatever> {
+        match self {
+            RuleBytes::Hex(hex) => BytePattern::parse_hex(hex),
+            RuleBytes::Text(text) => Ok(BytePattern::from_bytes(text.as_bytes())),
+        }
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, Whatever> {
+        self.to_pattern()?
+            .to_bytes()
+            .whatever_context("Responses must not contain wildcards")
+    }
+}
+
+/// Answers every TX frame containing `request` with `response`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ResponseRule {
+    pub request: RuleBytes,
+    pub response: RuleBytes,
+    #[serde(default)]
+    pub delay_ms: u64,
+}
+
+/// Sends `message` every `interval_ms`, without being asked.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PeriodicMessage {
+    pub interval_ms: u64,
+    pub message: RuleBytes,
+}
+
+/// The contents of a simulator rule file.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SimulatorConfig {
+    #[serde(default)]
+    pub rules: Vec<ResponseRule>,
+    #[serde(default)]
+    pub periodic: Vec<PeriodicMessage>,
+}
+
+#[derive(Debug)]
+struct CompiledRule {
+    request: BytePattern,
+    response: Vec<u8>,
+    delay: Duration,
+}
+
+#[derive(Debug)]
+struct CompiledPeriodic {
+    interval: Duration,
+    message: Vec<u8>,
+    next: Instant,
+}
+
+impl SimulatorConfig {
+    pub fn load(path: &Path) -> Result<SimulatorConfig, Whatever> {
+        let file = File::open(path)
+            .with_whatever_context(|_| format!("Failed to open {}", path.display()))?;
+        let config: SimulatorConfig = serde_json::from_reader(BufReader::new(file))
+            .with_whatever_context(|_| format!("{} is not a valid rule file", path.display()))?;
+        // make sure all patterns are valid before the simulator is started:
+        config.create_transport()?;
+        Ok(config)
+    }
+
+    pub fn create_transport(&self) -> Result<SimulatorTransport, Whatever> {
+        let now = Instant::now();
+        let rules = self
+            .rules
+            .iter()
+            .map(|rule| {
+                Ok(CompiledRule {
+                    request: rule.request.to_pattern()?,
+                    response: rule.response.to_bytes()?,
+                    delay: Duration::from_millis(rule.delay_ms),
+                })
+            })
+            .collect::<Result<Vec<_>, Whatever>>()?;
+        let periodic = self
+            .periodic
+            .iter()
+            .map(|p| {
+                let interval = Duration::from_millis(p.interval_ms.max(1));
+                Ok(CompiledPeriodic {
+                    interval,
+                    message: p.message.to_bytes()?,
+                    next: now + interval,
+                })
+            })
+            .collect::<Result<Vec<_>, Whatever>>()?;
+
+        Ok(SimulatorTransport {
+            rules,
+            periodic,
+            pending: VecDeque::new(),
+        })
+    }
+
+    /// Creates a context the port thread can be started with.
+    pub fn create_context(&self) -> Result<SerialContext, Whatever> {
+        Ok(SerialContext::with_transport(
+            "Simulator".to_string(),
+            Box::new(self.create_transport()?),
+        ))
+    }
+}
+
+/// A simulated device, that answers according to the rules of a `SimulatorConfig`.
+#[derive(Debug)]
+pub struct SimulatorTransport {
+    rules: Vec<CompiledRule>,
+    periodic: Vec<CompiledPeriodic>,
+    /// Responses that are waiting for their delay to pass, ordered by due time.
+    pending: VecDeque<(Instant, Vec<u8>)>,
+}
+
+impl SimulatorTransport {
+    /// Returns the next message that is due, or the time to wait for it.
+    fn next_due(&mut self, now: Instant) -> Result<Vec<u8>, Duration> {
+        if let Some((due, _)) = self.pending.front() {
+            if *due <= now {
+                return Ok(self.pending.pop_front().unwrap().1);
+            }
+        }
+        for periodic in self.periodic.iter_mut() {
+            if periodic.next <= now {
+                periodic.next = now + periodic.interval;
+                return Ok(periodic.message.clone());
+            }
+        }
+
+        let next = self
+            .pending
+            .front()
+            .map(|(due, _)| *due)
+            .into_iter()
+            .chain(self.periodic.iter().map(|p| p.next))
+            .min();
+        Err(next.map_or(IDLE_TIMEOUT, |n| (n - now).min(IDLE_TIMEOUT)))
+    }
+}
+
+impl Transport for SimulatorTransport {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        match self.next_due(Instant::now()) {
+            Ok(mut data) => {
+                // messages larger than the buffer are continued by the next read:
+                if data.len() > buffer.len() {
+                    let rest = data.split_off(buffer.len());
+                    self.pending.push_front((Instant::now(), rest));
+                }
+                buffer[..data.len()].copy_from_slice(&data);
+                Ok(data.len())
+            }
+            Err(wait) => {
+                thread::sleep(wait);
+                Err(ErrorKind::TimedOut.into())
+            }
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        if let Some(rule) = self.rules.iter().find(|r| r.request.find(data).is_some()) {
+            let due = Instant::now() + rule.delay;
+            // keep the queue ordered, responses with a shorter delay may overtake:
+            let pos = self.pending.partition_point(|(d, _)| *d <= due);
+            self.pending.insert(pos, (due, rule.response.clone()));
+        }
+        Ok(data.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use crate::portthread::{port_background_thread, RxTx, SerialCommand, SerialStateMessage};
+
+    use super::*;
+
+    fn config() -> SimulatorConfig {
+        serde_json::from_value(serde_json::json!({
+            "rules": [
+                { "request": { "text": "AT\r\n" }, "response": { "text": "OK\r\n" } },
+                { "request": { "hex": "01 03 ?? ??" }, "response": { "hex": "01 03 02 00 2A" }, "delay_ms": 30 },
+            ],
+            "periodic": [
+                { "interval_ms": 20, "message": { "hex": "AA 55" } },
+            ],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_rules_answer_requests() {
+        let mut sim = SimulatorConfig {
+            periodic: vec![],
+            ..config()
+        }
+        .create_transport()
+        .unwrap();
+        let now = Instant::now();
+        assert!(sim.next_due(now).is_err());
+
+        sim.write(&[0x01, 0x03, 0x00, 0x10]).unwrap();
+        sim.write(b"AT\r\n").unwrap();
+        // the response without delay overtakes the delayed one:
+        assert_eq!(sim.next_due(Instant::now()), Ok(b"OK\r\n".to_vec()));
+        assert!(sim.next_due(Instant::now()).is_err());
+        assert_eq!(
+            sim.next_due(Instant::now() + Duration::from_millis(30)),
+            Ok(vec![0x01, 0x03, 0x02, 0x00, 0x2A])
+        );
+
+        sim.write(b"unknown").unwrap();
+        assert!(sim.pending.is_empty());
+    }
+
+    #[test]
+    fn test_periodic_messages() {
+        let mut sim = config().create_transport().unwrap();
+        let start = Instant::now();
+        assert!(sim.next_due(start).is_err());
+        let later = start + Duration::from_millis(25);
+        assert_eq!(sim.next_due(later), Ok(vec![0xAA, 0x55]));
+        assert!(sim.next_due(later).is_err());
+    }
+
+    #[test]
+    fn test_wildcard_responses_are_rejected() {
+        let config: SimulatorConfig = serde_json::from_value(serde_json::json!({
+            "rules": [{ "request": { "text": "A" }, "response": { "hex": "??" } }],
+        }))
+        .unwrap();
+        assert!(config.create_transport().is_err());
+    }
+
+    /// Runs the port thread against the simulator and returns all data events
+    /// received within the given time.
+    fn run_port_thread(
+        commands: Vec<SerialCommand>,
+        duration: Duration,
+    ) -> Vec<SerialStateMessage> {
+        let (cmd_tx, cmd_rx) = mpsc::channel();
+        let (state_tx, state_rx) = mpsc::channel();
+        port_background_thread(cmd_rx, state_tx, Default::default());
+        for cmd in commands {
+            cmd_tx.send(cmd).unwrap();
+        }
+
+        let mut messages = vec![];
+        let end = Instant::now() + duration;
+        while let Ok(msg) = state_rx.recv_timeout(end.saturating_duration_since(Instant::now())) {
+            messages.push(msg);
+        }
+        cmd_tx.send(SerialCommand::Stop).unwrap();
+        messages
+    }
+
+    #[test]
+    fn test_port_thread_with_simulator() {
+        let config = SimulatorConfig {
+            periodic: vec![],
+            ..config()
+        };
+        let messages = run_port_thread(
+            vec![
+                SerialCommand::Start(config.create_context().unwrap()),
+                SerialCommand::Send(b"AT\r\n".to_vec()),
+            ],
+            Duration::from_millis(200),
+        );
+
+        let data: Vec<(RxTx, Vec<u8>)> = messages
+            .iter()
+            .filter_map(|m| match m {
+                SerialStateMessage::DataEvent(e) => Some((e.rx_tx.clone(), e.data.clone())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(messages[0], SerialStateMessage::Started);
+        assert_eq!(
+            data,
+            vec![
+                (RxTx::Tx, b"AT\r\n".to_vec()),
+                (RxTx::Rx, b"OK\r\n".to_vec())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_port_thread_splits_large_responses() {
+        let config: SimulatorConfig = serde_json::from_value(serde_json::json!({
+            "rules": [{ "request": { "text": "?" }, "response": { "text": "x".repeat(600) } }],
+        }))
+        .unwrap();
+        let messages = run_port_thread(
+            vec![
+                SerialCommand::Start(config.create_context().unwrap()),
+                SerialCommand::Send(b"?".to_vec()),
+            ],
+            Duration::from_millis(200),
+        );
+
+        let received: usize = messages
+            .iter()
+            .filter_map(|m| match m {
+                SerialStateMessage::DataEvent(e) if e.rx_tx == RxTx::Rx => Some(e.data.len()),
+                _ => None,
+            })
+            .sum();
+        assert_eq!(received, 600);
+    }
+}
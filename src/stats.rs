@@ -0,0 +1,327 @@
+use std::fmt::Display;
+
+use chrono::{DateTime, Local, TimeDelta};
+
+use crate::portthread::{HistoryEntry, RxTx, SerialStateMessage};
+
+/// Number of bars of the histogram.
+pub const HISTOGRAM_BUCKETS: usize = 8;
+
+/// Number of values kept per metric. Count, min, max and mean are exact, the
+/// percentiles and the histogram are estimated from a uniform sample of this
+/// many values, so memory use does not grow with the history.
+const RESERVOIR_SIZE: usize = 10_000;
+
+pub const STATS_METRICS: [StatsMetric; 3] = [
+    StatsMetric::RxGap,
+    StatsMetric::ResponseTime,
+    StatsMetric::FrameSize,
+];
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum StatsMetric {
+    /// Silence between the end of an RX frame and the start of the next one.
+    #[default]
+    RxGap,
+    /// Time between a TX frame and the first RX frame after it.
+    ResponseTime,
+    /// Number of bytes of every frame, both directions.
+    FrameSize,
+}
+
+impl Display for StatsMetric {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StatsMetric::RxGap => write!(f, "RX gaps"),
+            StatsMetric::ResponseTime => write!(f, "TX->RX response times"),
+            StatsMetric::FrameSize => write!(f, "Frame sizes"),
+        }
+    }
+}
+
+impl StatsMetric {
+    /// Times are collected in microseconds, sizes in bytes.
+    pub fn is_time(&self) -> bool {
+        *self != StatsMetric::FrameSize
+    }
+}
+
+/// Running aggregates of the values of a metric and a uniform sample of them
+/// (reservoir sampling, algorithm R).
+#[derive(Debug, Default)]
+struct Reservoir {
+    count: usize,
+    min: i64,
+    max: i64,
+    sum: f64,
+    samples: Vec<i64>,
+    /// State of the xorshift generator choosing the replaced samples.
+    random: u64,
+}
+
+impl Reservoir {
+    fn add(&mut self, value: i64) {
+        if self.count == 0 {
+            (self.min, self.max) = (value, value);
+        }
+        self.count += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value as f64;
+        if self.samples.len() < RESERVOIR_SIZE {
+            self.samples.push(value);
+            return;
+        }
+        let slot = (self.next_random() % self.count as u64) as usize;
+        if slot < RESERVOIR_SIZE {
+            self.samples[slot] = value;
+        }
+    }
+
+    fn next_random(&mut self) -> u64 {
+        // any non zero seed works, the sample only has to be spread evenly:
+        if self.random == 0 {
+            self.random = 0x9E37_79B9_7F4A_7C15;
+        }
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 7;
+        self.random ^= self.random << 17;
+        self.random
+    }
+
+    fn summarize(&self) -> Option<Summary> {
+        let mut summary = Summary::new(self.samples.clone())?;
+        if self.count > self.samples.len() {
+            // the buckets cover the range of all values, their counts are
+            // scaled from the sample to the number of values:
+            let scale = self.count as f64 / self.samples.len() as f64;
+            summary.histogram = histogram(&self.samples, self.min, self.max, scale);
+        }
+        summary.count = self.count;
+        summary.min = self.min;
+        summary.max = self.max;
+        summary.mean = self.sum / self.count as f64;
+        Some(summary)
+    }
+}
+
+/// Collects the values of all metrics from the messages of the history, in order.
+#[derive(Debug, Default)]
+pub struct TimingStats {
+    rx_gaps: Reservoir,
+    response_times: Reservoir,
+    frame_sizes: Reservoir,
+    /// Time the last byte of the previous RX frame arrived.
+    last_rx_end: Option<DateTime<Local>>,
+    /// Time of a TX frame that has not been answered yet.
+    pending_tx: Option<DateTime<Local>>,
+    changed: bool,
+}
+
+/// The time the first byte of the entry arrived.
+fn start_time(entry: &HistoryEntry) -> DateTime<Local> {
+    entry.timestamp + entry.byte_time(0).unwrap_or_default()
+}
+
+/// The time the last byte of the entry arrived.
+fn end_time(entry: &HistoryEntry) -> DateTime<Local> {
+    let last = entry.data.len().saturating_sub(1);
+    entry.timestamp + entry.byte_time(last).unwrap_or_default()
+}
+
+impl TimingStats {
+    pub fn add(&mut self, msg: &SerialStateMessage) {
+        let entry = match msg {
+            SerialStateMessage::DataEvent(entry) => entry,
+            // gaps across a restart of the port mean nothing:
+            SerialStateMessage::Started | SerialStateMessage::Stopped => {
+                self.last_rx_end = None;
+                self.pending_tx = None;
+                return;
+            }
+            _ => return,
+        };
+        self.changed = true;
+        self.frame_sizes.add(entry.data.len() as i64);
+        match entry.rx_tx {
+            RxTx::Rx => {
+                let start = start_time(entry);
+                if let Some(last) = self.last_rx_end {
+                    self.rx_gaps.add(micros(start - last));
+                }
+                if let Some(tx) = self.pending_tx.take() {
+                    self.response_times.add(micros(start - tx));
+                }
+                self.last_rx_end = Some(end_time(entry));
+            }
+            RxTx::Tx => self.pending_tx = Some(entry.timestamp),
+        }
+    }
+
+    /// Returns true, if values were added since the last call.
+    pub fn take_changed(&mut self) -> bool {
+        std::mem::take(&mut self.changed)
+    }
+
+    pub fn summarize(&self, metric: StatsMetric) -> Option<Summary> {
+        match metric {
+            StatsMetric::RxGap => self.rx_gaps.summarize(),
+            StatsMetric::ResponseTime => self.response_times.summarize(),
+            StatsMetric::FrameSize => self.frame_sizes.summarize(),
+        }
+    }
+}
+
+fn micros(delta: TimeDelta) -> i64 {
+    delta.num_microseconds().unwrap_or(i64::MAX)
+}
+
+/// Distribution of the values of a metric.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Summary {
+    pub count: usize,
+    pub min: i64,
+    pub max: i64,
+    pub mean: f64,
+    pub p50: i64,
+    pub p90: i64,
+    pub p99: i64,
+    /// Lower bound of every bucket and the number of values in it. The buckets
+    /// are of equal width and cover min..=max.
+    pub histogram: Vec<(i64, u64)>,
+}
+
+impl Summary {
+    fn new(mut values: Vec<i64>) -> Option<Summary> {
+        if values.is_empty() {
+            return None;
+        }
+        values.sort_unstable();
+        let count = values.len();
+        let min = values[0];
+        let max = values[count - 1];
+        // nearest rank percentile:
+        let percentile = |p: usize| values[(count * p).div_ceil(100).clamp(1, count) - 1];
+
+        let histogram = histogram(&values, min, max, 1.0);
+
+        Some(Summary {
+            count,
+            min,
+            max,
+            mean: values.iter().map(|v| *v as f64).sum::<f64>() / count as f64,
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            histogram,
+        })
+    }
+}
+
+/// Counts the values in buckets of equal width covering min..=max, the counts
+/// are multiplied by `scale`.
+fn histogram(values: &[i64], min: i64, max: i64, scale: f64) -> Vec<(i64, u64)> {
+    let width = ((max - min) / HISTOGRAM_BUCKETS as i64 + 1).max(1);
+    let mut counts = [0u64; HISTOGRAM_BUCKETS];
+    for value in values {
+        let bucket = ((value - min) / width) as usize;
+        counts[bucket.min(HISTOGRAM_BUCKETS - 1)] += 1;
+    }
+    (0..HISTOGRAM_BUCKETS)
+        .map(|bucket| {
+            let count = (counts[bucket] as f64 * scale).round() as u64;
+            (min + bucket as i64 * width, count)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::portthread::ChunkTiming;
+
+    use super::*;
+
+    fn data(start: DateTime<Local>, ms: i64, rx_tx: RxTx, len: usize) -> SerialStateMessage {
+        SerialStateMessage::DataEvent(HistoryEntry {
+            timestamp: start + TimeDelta::milliseconds(ms),
+            rx_tx,
+            data: vec![0; len],
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_collect_gaps_responses_and_sizes() {
+        let start = Local::now();
+        let mut stats = TimingStats::default();
+        for msg in [
+            data(start, 0, RxTx::Tx, 2),
+            data(start, 10, RxTx::Rx, 4),
+            data(start, 15, RxTx::Rx, 4),
+            SerialStateMessage::Started,
+            data(start, 100, RxTx::Rx, 1),
+        ] {
+            stats.add(&msg);
+        }
+        assert!(stats.take_changed());
+        assert!(!stats.take_changed());
+
+        let gaps = stats.summarize(StatsMetric::RxGap).unwrap();
+        assert_eq!((gaps.count, gaps.min, gaps.max), (1, 5000, 5000));
+        let responses = stats.summarize(StatsMetric::ResponseTime).unwrap();
+        assert_eq!(responses.max, 10_000);
+        let sizes = stats.summarize(StatsMetric::FrameSize).unwrap();
+        assert_eq!((sizes.count, sizes.min, sizes.max), (4, 1, 4));
+        assert_eq!(sizes.mean, 2.75);
+    }
+
+    #[test]
+    fn test_gaps_use_the_arrival_of_the_first_byte() {
+        let start = Local::now();
+        let mut stats = TimingStats::default();
+        stats.add(&data(start, 0, RxTx::Rx, 1));
+        // the first chunk of the entry was read 2ms after its timestamp:
+        stats.add(&SerialStateMessage::DataEvent(HistoryEntry {
+            timestamp: start + TimeDelta::milliseconds(10),
+            data: vec![0; 4],
+            chunks: vec![ChunkTiming {
+                offset: 0,
+                micros: 2000,
+            }],
+            ..Default::default()
+        }));
+        let gaps = stats.summarize(StatsMetric::RxGap).unwrap();
+        assert_eq!(gaps.max, 12_000);
+    }
+
+    #[test]
+    fn test_reservoir_is_bounded() {
+        let mut reservoir = Reservoir::default();
+        for value in 0..(RESERVOIR_SIZE as i64 * 3) {
+            reservoir.add(value);
+        }
+        assert_eq!(reservoir.samples.len(), RESERVOIR_SIZE);
+        let summary = reservoir.summarize().unwrap();
+        assert_eq!(summary.count, RESERVOIR_SIZE * 3);
+        assert_eq!(
+            (summary.min, summary.max),
+            (0, RESERVOIR_SIZE as i64 * 3 - 1)
+        );
+        assert_eq!(summary.mean, (RESERVOIR_SIZE * 3 - 1) as f64 / 2.0);
+        // the median of the sample is close to the real one:
+        let median = RESERVOIR_SIZE as i64 * 3 / 2;
+        assert!((summary.p50 - median).abs() < median / 10);
+        let total = summary.histogram.iter().map(|(_, n)| n).sum::<u64>();
+        assert!(total.abs_diff(summary.count as u64) < HISTOGRAM_BUCKETS as u64);
+    }
+
+    #[test]
+    fn test_percentiles_and_histogram() {
+        let summary = Summary::new((1..=100).rev().collect()).unwrap();
+        assert_eq!((summary.p50, summary.p90, summary.p99), (50, 90, 99));
+        assert_eq!(summary.histogram.len(), HISTOGRAM_BUCKETS);
+        assert_eq!(summary.histogram[0], (1, 13));
+        assert_eq!(summary.histogram.iter().map(|(_, n)| n).sum::<u64>(), 100);
+        assert_eq!(Summary::new(vec![]), None);
+    }
+}
@@ -0,0 +1,448 @@
+use std::{collections::BTreeMap, ops::Range};
+
+use serde::{Deserialize, Serialize};
+use snafu::{prelude::*, Whatever};
+
+use crate::{
+    decode::{bcd, f16_to_f32, ordered, Endianness},
+    pattern::BytePattern,
+};
+
+/// Type of a template field, the numeric types are those of the analyzer.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldType {
+    U8,
+    I8,
+    U16,
+    I16,
+    U24,
+    I24,
+    U32,
+    I32,
+    U64,
+    I64,
+    F16,
+    F32,
+    F64,
+    /// Packed BCD of `len` bytes.
+    Bcd,
+    /// Text of `len` bytes, non printable bytes are shown as `.`.
+    Ascii,
+    /// `len` bytes shown as hex.
+    Bytes,
+}
+
+impl FieldType {
+    /// Number of bytes of the type, `None` if it is given by the `len` of the field.
+    pub(crate) fn size(&self) -> Option<usize> {
+        match self {
+            FieldType::U8 | FieldType::I8 => Some(1),
+            FieldType::U16 | FieldType::I16 | FieldType::F16 => Some(2),
+            FieldType::U24 | FieldType::I24 => Some(3),
+            FieldType::U32 | FieldType::I32 | FieldType::F32 => Some(4),
+            FieldType::U64 | FieldType::I64 | FieldType::F64 => Some(8),
+            FieldType::Bcd | FieldType::Ascii | FieldType::Bytes => None,
+        }
+    }
+
+    pub(crate) fn is_integer(&self) -> bool {
+        !matches!(
+            self,
+            FieldType::F16 | FieldType::F32 | FieldType::F64 | FieldType::Ascii | FieldType::Bytes
+        )
+    }
+}
+
+/// Some bits of an integer field, e.g. a flag of a status word.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BitField {
+    pub name: String,
+    /// The lowest bit, 0 is the least significant bit of the value.
+    pub bit: u32,
+    #[serde(default = "default_width")]
+    pub width: u32,
+    /// Names of the values of the bits.
+    #[serde(default)]
+    pub values: BTreeMap<u64, String>,
+}
+
+fn default_width() -> u32 {
+    1
+}
+
+/// A field of a template. Scaled values are `raw * scale + add`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TemplateField {
+    pub name: String,
+    /// Position of the field, relative to the start of the decoded bytes.
+    pub offset: usize,
+    #[serde(rename = "type")]
+    pub kind: FieldType,
+    /// Length of `bcd`, `ascii` and `bytes` fields.
+    #[serde(default)]
+    pub len: Option<usize>,
+    /// Overrides the endianness of the template.
+    #[serde(default)]
+    pub endianness: Option<Endianness>,
+    #[serde(default)]
+    pub scale: Option<f64>,
+    #[serde(default)]
+    pub add: Option<f64>,
+    #[serde(default)]
+    pub unit: Option<String>,
+    /// Names of the raw values of integer fields, e.g. the states of a state machine.
+    #[serde(default)]
+    pub values: BTreeMap<i64, String>,
+    #[serde(default)]
+    pub bits: Vec<BitField>,
+}
+
+/// The layout of a frame, as defined in the .klemme file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StructTemplate {
+    pub name: String,
+    /// Hex pattern, e.g. `AA ?? 01`. Frames starting with it are decoded with the
+    /// template automatically.
+    #[serde(default)]
+    pub header: Option<String>,
+    #[serde(default)]
+    pub endianness: Endianness,
+    pub fields: Vec<TemplateField>,
+}
+
+/// A decoded field or bit field, i.e. a row of the template panel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldValue {
+    pub name: String,
+    /// The bytes of the field, or the bits of a bit field.
+    pub position: String,
+    pub raw: String,
+    pub value: String,
+}
+
+/// A value read from the bytes of a field.
+pub(crate) enum Value {
+    Integer(i128),
+    /// The value and its text, as the value is widened from f16 or f32.
+    Float(f64, String),
+    Text(String),
+}
+
+impl TemplateField {
+    fn size(&self) -> usize {
+        self.kind.size().unwrap_or(self.len.unwrap_or(0))
+    }
+
+    /// The bytes of the field, `None` if the end does not fit into a usize.
+    fn range(&self) -> Option<Range<usize>> {
+        Some(self.offset..self.offset.checked_add(self.size())?)
+    }
+
+    fn read(&self, bytes: &[u8], endianness: Endianness) -> Option<Value> {
+        read_value(self.kind, bytes, self.endianness.unwrap_or(endianness))
+    }
+
+    fn format(&self, value: &Value) -> String {
+        format_value(
+            value,
+            self.scale,
+            self.add,
+            self.unit.as_deref(),
+            &self.values,
+        )
+    }
+}
+
+impl StructTemplate {
+    fn header_pattern(&self) -> Result<Option<BytePattern>, Whatever> {
+        self.header
+            .as_deref()
+            .map(BytePattern::parse_hex)
+            .transpose()
+    }
+
+    /// Checks the template for errors, that would only show when decoding.
+    pub fn check(&self) -> Result<(), Whatever> {
+        self.header_pattern()
+            .with_whatever_context(|_| format!("Invalid header of template '{}'", self.name))?;
+        for field in &self.fields {
+            ensure_whatever!(
+                field.kind.size().is_some() || field.len.is_some(),
+                "Field '{}' of template '{}' needs a len",
+                field.name,
+                self.name
+            );
+            ensure_whatever!(
+                field.range().is_some(),
+                "Field '{}' of template '{}' is too long",
+                field.name,
+                self.name
+            );
+            ensure_whatever!(
+                field.bits.is_empty() || field.kind.is_integer(),
+                "Field '{}' of template '{}' is not an integer, it can't have bits",
+                field.name,
+                self.name
+            );
+            for bits in &field.bits {
+                ensure_whatever!(
+                    bits.width > 0
+                        && bits
+                            .bit
+                            .checked_add(bits.width)
+                            .is_some_and(|end| end <= 64),
+                    "Bits '{}' of template '{}' exceed 64 bits",
+                    bits.name,
+                    self.name
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of bytes covered by the fields.
+    pub fn len(&self) -> usize {
+        self.fields
+            .iter()
+            .filter_map(|f| f.range())
+            .map(|range| range.end)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Returns true, if the data starts with the header of the template.
+    pub fn matches(&self, data: &[u8]) -> bool {
+        match self.header_pattern() {
+            Ok(Some(header)) => header.matches_at(data, 0),
+            _ => false,
+        }
+    }
+
+    /// Decodes the fields from the bytes. Fields beyond the end of the bytes are
+    /// shown as `-`.
+    pub fn decode(&self, bytes: &[u8]) -> Vec<FieldValue> {
+        let mut rows = vec![];
+        for field in &self.fields {
+            // a field too long for a usize can't be within the bytes either:
+            let range = field.range().unwrap_or(field.offset..field.offset);
+            let data = field.range().and_then(|range| bytes.get(range));
+            let value = data.and_then(|data| field.read(data, self.endianness));
+            rows.push(FieldValue {
+                name: field.name.clone(),
+                position: if range.len() > 1 {
+                    format!("{}-{}", range.start, range.end - 1)
+                } else {
+                    range.start.to_string()
+                },
+                raw: data.map(hex).unwrap_or_default(),
+                value: value.as_ref().map_or("-".to_string(), |v| field.format(v)),
+            });
+
+            let Some(Value::Integer(raw)) = value else {
+                continue;
+            };
+            for bits in &field.bits {
+                let mask = u64::MAX >> (64 - bits.width);
+                let value = (raw as u64 >> bits.bit) & mask;
+                rows.push(FieldValue {
+                    name: format!("  {}", bits.name),
+                    position: if bits.width > 1 {
+                        format!("b{}-{}", bits.bit, bits.bit + bits.width - 1)
+                    } else {
+                        format!("b{}", bits.bit)
+                    },
+                    raw: String::new(),
+                    value: match bits.values.get(&value) {
+                        Some(name) => format!("{} ({})", name, value),
+                        None => value.to_string(),
+                    },
+                });
+            }
+        }
+        rows
+    }
+}
+
+/// Reads a value of the given type from the start of the bytes.
+pub(crate) fn read_value(kind: FieldType, bytes: &[u8], endianness: Endianness) -> Option<Value> {
+    let value = match kind {
+        FieldType::U8 => Value::Integer(*bytes.first()? as i128),
+        FieldType::I8 => Value::Integer(*bytes.first()? as i8 as i128),
+        FieldType::U16 => Value::Integer(u16::from_be_bytes(ordered(bytes, endianness)?) as i128),
+        FieldType::I16 => Value::Integer(i16::from_be_bytes(ordered(bytes, endianness)?) as i128),
+        FieldType::U24 | FieldType::I24 => {
+            let [a, b, c] = ordered::<3>(bytes, endianness)?;
+            let unsigned = u32::from_be_bytes([0, a, b, c]);
+            if kind == FieldType::U24 {
+                Value::Integer(unsigned as i128)
+            } else {
+                // sign extend from bit 23:
+                Value::Integer((((unsigned << 8) as i32) >> 8) as i128)
+            }
+        }
+        FieldType::U32 => Value::Integer(u32::from_be_bytes(ordered(bytes, endianness)?) as i128),
+        FieldType::I32 => Value::Integer(i32::from_be_bytes(ordered(bytes, endianness)?) as i128),
+        FieldType::U64 => Value::Integer(u64::from_be_bytes(ordered(bytes, endianness)?) as i128),
+        FieldType::I64 => Value::Integer(i64::from_be_bytes(ordered(bytes, endianness)?) as i128),
+        FieldType::F16 => {
+            let value = f16_to_f32(u16::from_be_bytes(ordered(bytes, endianness)?));
+            Value::Float(value as f64, value.to_string())
+        }
+        FieldType::F32 => {
+            let value = f32::from_be_bytes(ordered(bytes, endianness)?);
+            Value::Float(value as f64, value.to_string())
+        }
+        FieldType::F64 => {
+            let value = f64::from_be_bytes(ordered(bytes, endianness)?);
+            Value::Float(value, value.to_string())
+        }
+        FieldType::Bcd => Value::Integer(bcd(bytes)? as i128),
+        FieldType::Ascii => Value::Text(
+            bytes
+                .iter()
+                .map(|b| {
+                    if (0x20..0x7f).contains(b) {
+                        *b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect(),
+        ),
+        FieldType::Bytes => Value::Text(hex(bytes)),
+    };
+    Some(value)
+}
+
+/// Formats a value, integers with a name in `values` are shown by name. Numbers
+/// are shown scaled, as `raw * scale + add`, followed by the unit.
+pub(crate) fn format_value(
+    value: &Value,
+    scale: Option<f64>,
+    add: Option<f64>,
+    unit: Option<&str>,
+    values: &BTreeMap<i64, String>,
+) -> String {
+    let scaled = |raw: f64| {
+        if scale.is_none() && add.is_none() {
+            return None;
+        }
+        let value = raw * scale.unwrap_or(1.0) + add.unwrap_or(0.0);
+        // round away the noise of the float arithmetic, e.g. 0.1 * 3:
+        let text = format!("{:.6}", value);
+        Some(text.trim_end_matches('0').trim_end_matches('.').to_string())
+    };
+    let text = match value {
+        Value::Integer(raw) => {
+            if let Some(name) = i64::try_from(*raw).ok().and_then(|raw| values.get(&raw)) {
+                return format!("{} ({})", name, raw);
+            }
+            scaled(*raw as f64).unwrap_or(raw.to_string())
+        }
+        Value::Float(value, text) => scaled(*value).unwrap_or(text.clone()),
+        Value::Text(text) => return text.clone(),
+    };
+    match unit {
+        Some(unit) => format!("{} {}", text, unit),
+        None => text,
+    }
+}
+
+/// Returns the first template, whose header the data starts with.
+pub fn find_template<'a>(
+    templates: &'a [StructTemplate],
+    data: &[u8],
+) -> Option<&'a StructTemplate> {
+    templates.iter().find(|t| t.matches(data))
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn telemetry() -> StructTemplate {
+        serde_json::from_value(serde_json::json!({
+            "name": "Telemetry",
+            "header": "AA 01",
+            "fields": [
+                { "name": "Temperature", "offset": 2, "type": "i16", "scale": 0.1, "unit": "°C" },
+                { "name": "State", "offset": 4, "type": "u8", "values": { "0": "Idle", "1": "Running" } },
+                { "name": "Status", "offset": 5, "type": "u16", "endianness": "big", "bits": [
+                    { "name": "Alarm", "bit": 0 },
+                    { "name": "Mode", "bit": 4, "width": 3, "values": { "2": "Auto" } }
+                ] },
+                { "name": "Serial", "offset": 7, "type": "ascii", "len": 4 }
+            ]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_decode_template() {
+        let template = telemetry();
+        assert!(template.check().is_ok());
+        assert_eq!(template.len(), 11);
+
+        let frame = [0xAA, 0x01, 0xEB, 0x00, 0x01, 0x00, 0x21, b'K', b'1', 0x00];
+        let values: Vec<(String, String)> = template
+            .decode(&frame)
+            .into_iter()
+            .map(|row| (row.name, row.value))
+            .collect();
+        let expected = [
+            ("Temperature", "23.5 °C"),
+            ("State", "Running (1)"),
+            ("Status", "33"),
+            ("  Alarm", "1"),
+            ("  Mode", "Auto (2)"),
+            // the frame is one byte short:
+            ("Serial", "-"),
+        ];
+        let expected: Vec<(String, String)> = expected
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        assert_eq!(values, expected);
+        assert_eq!(template.decode(&frame)[2].raw, "00 21");
+        assert_eq!(template.decode(&frame)[4].position, "b4-6");
+    }
+
+    #[test]
+    fn test_find_and_check_templates() {
+        let mut other = telemetry();
+        other.name = "Other".to_string();
+        other.header = Some("AA ??".to_string());
+        let templates = [telemetry(), other];
+        assert_eq!(
+            find_template(&templates, &[0xAA, 0x01, 0x00]).map(|t| t.name.as_str()),
+            Some("Telemetry")
+        );
+        assert_eq!(
+            find_template(&templates, &[0xAA, 0x02]).map(|t| t.name.as_str()),
+            Some("Other")
+        );
+        assert!(find_template(&templates, &[0xAA]).is_none());
+
+        let mut template = telemetry();
+        template.fields[3].len = None;
+        assert!(template.check().is_err());
+        let mut template = telemetry();
+        template.header = Some("AA 0".to_string());
+        assert!(template.check().is_err());
+
+        let mut template = telemetry();
+        template.fields[3].offset = usize::MAX - 1;
+        assert!(template.check().is_err());
+        assert_eq!(template.len(), 7);
+        assert_eq!(template.decode(&[0xAA; 16])[5].value, "-");
+    }
+}
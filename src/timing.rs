@@ -0,0 +1,464 @@
+use std::fmt::Display;
+
+use chrono::{
+    format::{Item, StrftimeItems},
+    DateTime, Local, TimeDelta, Utc,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{history::History, portthread::SerialStateMessage};
+
+/// How far back the reference entry of a delta is searched. Older entries are
+/// read from disk, so the search is bounded to keep rendering fast.
+const MAX_DELTA_LOOKBACK: usize = 1024;
+
+pub const DELTA_REFERENCES: [DeltaReference; 3] = [
+    DeltaReference::Previous,
+    DeltaReference::OppositeDirection,
+    DeltaReference::SessionStart,
+];
+
+/// The entry the time delta of an entry is computed against.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum DeltaReference {
+    /// The previous entry.
+    #[default]
+    Previous,
+    /// The previous entry of the other direction, i.e. the latency between
+    /// request and response.
+    OppositeDirection,
+    /// The first entry after the last start marker, i.e. since the port was opened.
+    SessionStart,
+}
+
+impl Display for DeltaReference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeltaReference::Previous => write!(f, "prev"),
+            DeltaReference::OppositeDirection => write!(f, "other direction"),
+            DeltaReference::SessionStart => write!(f, "start"),
+        }
+    }
+}
+
+pub const DELTA_UNITS: [DeltaUnit; 3] = [
+    DeltaUnit::Microseconds,
+    DeltaUnit::Milliseconds,
+    DeltaUnit::Seconds,
+];
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum DeltaUnit {
+    Microseconds,
+    #[default]
+    Milliseconds,
+    Seconds,
+}
+
+impl Display for DeltaUnit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeltaUnit::Microseconds => write!(f, "µs"),
+            DeltaUnit::Milliseconds => write!(f, "ms"),
+            DeltaUnit::Seconds => write!(f, "s"),
+        }
+    }
+}
+
+impl DeltaUnit {
+    /// Formats a delta in this unit, e.g. `+12.345ms`.
+    pub fn format(&self, delta: TimeDelta) -> String {
+        let micros = delta.num_microseconds().unwrap_or(i64::MAX);
+        let sign = if micros < 0 { "-" } else { "+" };
+        let micros = micros.unsigned_abs();
+        match self {
+            DeltaUnit::Microseconds => format!("{}{}{}", sign, micros, self),
+            DeltaUnit::Milliseconds => {
+                format!("{}{}.{:03}{}", sign, micros / 1000, micros % 1000, self)
+            }
+            DeltaUnit::Seconds => {
+                format!(
+                    "{}{}.{:06}{}",
+                    sign,
+                    micros / 1_000_000,
+                    micros % 1_000_000,
+                    self
+                )
+            }
+        }
+    }
+}
+
+/// How the time delta of an entry is shown, persisted in the .klemme file.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct DeltaSettings {
+    #[serde(default)]
+    pub reference: DeltaReference,
+    #[serde(default)]
+    pub unit: DeltaUnit,
+}
+
+/// Returns the timestamp of the entry the delta of the entry at `index` is
+/// computed against, if there is one. `session_start` is the index of the
+/// first entry of the session the entry belongs to.
+pub fn reference_timestamp(
+    history: &History,
+    index: usize,
+    reference: DeltaReference,
+    session_start: usize,
+) -> Option<DateTime<Local>> {
+    if reference == DeltaReference::SessionStart {
+        return (session_start..=index)
+            .take(MAX_DELTA_LOOKBACK)
+            .find_map(|i| history.get(i).and_then(|msg| msg.timestamp()));
+    }
+
+    let direction = match history.get(index)? {
+        SerialStateMessage::DataEvent(entry) => Some(entry.rx_tx),
+        _ => None,
+    };
+    (index.saturating_sub(MAX_DELTA_LOOKBACK)..index)
+        .rev()
+        .filter_map(|i| history.get(i))
+        .find_map(|msg| match (reference, &msg, &direction) {
+            (DeltaReference::OppositeDirection, SerialStateMessage::DataEvent(entry), Some(d)) => {
+                (entry.rx_tx != *d).then_some(entry.timestamp)
+            }
+            (DeltaReference::OppositeDirection, _, _) => None,
+            _ => msg.timestamp(),
+        })
+}
+
+pub const TIMESTAMP_STYLES: [TimestampStyle; 5] = [
+    TimestampStyle::Default,
+    TimestampStyle::Time,
+    TimestampStyle::Micros,
+    TimestampStyle::Iso8601,
+    TimestampStyle::Custom,
+];
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum TimestampStyle {
+    /// Time of day on screen, date and time in files and RFC 3339 in JSON.
+    #[default]
+    Default,
+    /// Time of day with milliseconds everywhere.
+    Time,
+    /// Date and time with microseconds.
+    Micros,
+    /// ISO 8601 with microseconds and UTC offset.
+    Iso8601,
+    /// The strftime format given in `custom_format`.
+    Custom,
+}
+
+impl Display for TimestampStyle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TimestampStyle::Default => write!(f, "Default"),
+            TimestampStyle::Time => write!(f, "Time"),
+            TimestampStyle::Micros => write!(f, "Micros"),
+            TimestampStyle::Iso8601 => write!(f, "ISO8601"),
+            TimestampStyle::Custom => write!(f, "Custom"),
+        }
+    }
+}
+
+pub const TIME_BASES: [TimeBase; 3] = [
+    TimeBase::WallClock,
+    TimeBase::SessionStart,
+    TimeBase::Bookmark,
+];
+
+/// What timestamps are relative to.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum TimeBase {
+    #[default]
+    WallClock,
+    /// Time since the first entry after the last start marker.
+    SessionStart,
+    /// Time since the last annotation.
+    Bookmark,
+}
+
+impl Display for TimeBase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TimeBase::WallClock => write!(f, "Clock"),
+            TimeBase::SessionStart => write!(f, "Session"),
+            TimeBase::Bookmark => write!(f, "Bookmark"),
+        }
+    }
+}
+
+/// Where a timestamp is shown, the default style differs between them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimestampTarget {
+    Screen,
+    File,
+    Json,
+}
+
+/// Timestamp settings as stored in the .klemme file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TimestampSettings {
+    pub style: TimestampStyle,
+    /// chrono strftime format, used by `TimestampStyle::Custom`.
+    pub custom_format: String,
+    pub utc: bool,
+    pub base: TimeBase,
+}
+
+impl Default for TimestampSettings {
+    fn default() -> Self {
+        Self {
+            style: TimestampStyle::Default,
+            custom_format: "%Y-%m-%d %H:%M:%S%.3f".to_string(),
+            utc: false,
+            base: TimeBase::WallClock,
+        }
+    }
+}
+
+impl TimestampSettings {
+    fn strftime_format(&self, target: TimestampTarget) -> &str {
+        match (self.style, target) {
+            (TimestampStyle::Default, TimestampTarget::Screen) => "%H:%M:%S%.3f",
+            (TimestampStyle::Default, TimestampTarget::File) => "%Y-%m-%d %H:%M:%S%.3f",
+            (TimestampStyle::Default, TimestampTarget::Json) => "%Y-%m-%dT%H:%M:%S%.f%:z",
+            (TimestampStyle::Time, _) => "%H:%M:%S%.3f",
+            (TimestampStyle::Micros, _) => "%Y-%m-%d %H:%M:%S%.6f",
+            (TimestampStyle::Iso8601, _) => "%Y-%m-%dT%H:%M:%S%.6f%:z",
+            // an invalid format would make chrono panic while formatting:
+            (TimestampStyle::Custom, _)
+                if StrftimeItems::new(&self.custom_format).any(|i| i == Item::Error) =>
+            {
+                "%Y-%m-%d %H:%M:%S%.3f"
+            }
+            (TimestampStyle::Custom, _) => &self.custom_format,
+        }
+    }
+
+    /// Formats a timestamp. If a relative time base is configured, `base_time`
+    /// is the time it refers to; without one the wall clock time is shown.
+    pub fn format(
+        &self,
+        timestamp: DateTime<Local>,
+        base_time: Option<DateTime<Local>>,
+        target: TimestampTarget,
+    ) -> String {
+        if let (TimeBase::SessionStart | TimeBase::Bookmark, Some(base_time)) =
+            (self.base, base_time)
+        {
+            let micros = matches!(self.style, TimestampStyle::Micros | TimestampStyle::Iso8601);
+            return format_relative(timestamp - base_time, micros);
+        }
+
+        let format = self.strftime_format(target);
+        if self.utc {
+            timestamp.with_timezone(&Utc).format(format).to_string()
+        } else {
+            timestamp.format(format).to_string()
+        }
+    }
+}
+
+/// Formats a time relative to a time base, e.g. `+0:01:02.345`.
+fn format_relative(delta: TimeDelta, micros: bool) -> String {
+    let total = delta.num_microseconds().unwrap_or(i64::MAX);
+    let sign = if total < 0 { "-" } else { "+" };
+    let total = total.unsigned_abs();
+    let seconds = total / 1_000_000;
+    let time = format!(
+        "{}{}:{:02}:{:02}",
+        sign,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    );
+    if micros {
+        format!("{}.{:06}", time, total % 1_000_000)
+    } else {
+        format!("{}.{:03}", time, total % 1_000_000 / 1000)
+    }
+}
+
+/// Formats the timestamps of messages that are processed in order, e.g. while
+/// exporting or logging, keeping track of the times the relative time bases
+/// refer to.
+#[derive(Debug, Default, Clone)]
+pub struct TimestampFormatter {
+    settings: TimestampSettings,
+    session_start: Option<DateTime<Local>>,
+    awaiting_session_start: bool,
+    bookmark: Option<DateTime<Local>>,
+}
+
+impl TimestampFormatter {
+    pub fn new(settings: TimestampSettings) -> Self {
+        Self {
+            settings,
+            ..Default::default()
+        }
+    }
+
+    pub fn set_settings(&mut self, settings: &TimestampSettings) {
+        if *settings != self.settings {
+            self.settings = settings.clone();
+        }
+    }
+
+    /// Has to be called for every message, before its timestamp is formatted.
+    pub fn observe(&mut self, msg: &SerialStateMessage) {
+        if *msg == SerialStateMessage::Started {
+            self.awaiting_session_start = true;
+        }
+        if let Some(timestamp) = msg.timestamp() {
+            if self.awaiting_session_start || self.session_start.is_none() {
+                self.session_start = Some(timestamp);
+                self.awaiting_session_start = false;
+            }
+        }
+        if let SerialStateMessage::Annotation(note) = msg {
+            self.bookmark = Some(note.timestamp);
+        }
+    }
+
+    pub fn format(&self, timestamp: DateTime<Local>, target: TimestampTarget) -> String {
+        let base_time = match self.settings.base {
+            TimeBase::WallClock => None,
+            TimeBase::SessionStart => self.session_start,
+            TimeBase::Bookmark => self.bookmark,
+        };
+        self.settings.format(timestamp, base_time, target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::portthread::{HistoryEntry, RxTx};
+
+    use super::*;
+
+    #[test]
+    fn test_format_delta() {
+        let delta = TimeDelta::microseconds(1_234_567);
+        assert_eq!(DeltaUnit::Microseconds.format(delta), "+1234567µs");
+        assert_eq!(DeltaUnit::Milliseconds.format(delta), "+1234.567ms");
+        assert_eq!(DeltaUnit::Seconds.format(-delta), "-1.234567s");
+    }
+
+    #[test]
+    fn test_delta_settings_round_trip() {
+        let delta = DeltaSettings {
+            reference: DeltaReference::OppositeDirection,
+            unit: DeltaUnit::Microseconds,
+        };
+        let json = serde_json::to_string(&delta).unwrap();
+        assert_eq!(serde_json::from_str::<DeltaSettings>(&json).unwrap(), delta);
+        assert_eq!(
+            serde_json::from_str::<DeltaSettings>("{}").unwrap(),
+            DeltaSettings::default()
+        );
+    }
+
+    #[test]
+    fn test_reference_timestamps() {
+        let start = Local::now();
+        let at = |ms: i64| start + TimeDelta::milliseconds(ms);
+        let mut history = History::default();
+        for (ms, rx_tx) in [(0, RxTx::Tx), (10, RxTx::Rx), (15, RxTx::Rx)] {
+            history.push(SerialStateMessage::DataEvent(HistoryEntry {
+                timestamp: at(ms),
+                rx_tx,
+                data: vec![0],
+                ..Default::default()
+            }));
+        }
+        history.push(SerialStateMessage::Started);
+
+        let reference = |index, reference| reference_timestamp(&history, index, reference, 0);
+        assert_eq!(reference(2, DeltaReference::Previous), Some(at(10)));
+        assert_eq!(reference(2, DeltaReference::OppositeDirection), Some(at(0)));
+        assert_eq!(reference(0, DeltaReference::OppositeDirection), None);
+        assert_eq!(reference(2, DeltaReference::SessionStart), Some(at(0)));
+        assert_eq!(reference(0, DeltaReference::Previous), None);
+    }
+
+    #[test]
+    fn test_timestamp_styles() {
+        let timestamp = DateTime::parse_from_rfc3339("2024-01-02T03:04:05.678901+00:00")
+            .unwrap()
+            .with_timezone(&Local);
+        let mut settings = TimestampSettings {
+            utc: true,
+            ..Default::default()
+        };
+        let format =
+            |settings: &TimestampSettings, target| settings.format(timestamp, None, target);
+        assert_eq!(format(&settings, TimestampTarget::Screen), "03:04:05.678");
+        assert_eq!(
+            format(&settings, TimestampTarget::File),
+            "2024-01-02 03:04:05.678"
+        );
+        assert_eq!(
+            format(&settings, TimestampTarget::Json),
+            "2024-01-02T03:04:05.678901+00:00"
+        );
+        settings.style = TimestampStyle::Iso8601;
+        assert_eq!(
+            format(&settings, TimestampTarget::Screen),
+            "2024-01-02T03:04:05.678901+00:00"
+        );
+        settings.style = TimestampStyle::Custom;
+        settings.custom_format = "%d.%m. %H:%M".to_string();
+        assert_eq!(format(&settings, TimestampTarget::Screen), "02.01. 03:04");
+        settings.custom_format = "%Q".to_string();
+        assert_eq!(
+            format(&settings, TimestampTarget::Screen),
+            "2024-01-02 03:04:05.678"
+        );
+    }
+
+    #[test]
+    fn test_relative_time_bases() {
+        let start = Local::now();
+        let at = |ms: i64| start + TimeDelta::milliseconds(ms);
+        let data = |ms: i64| {
+            SerialStateMessage::DataEvent(HistoryEntry {
+                timestamp: at(ms),
+                rx_tx: RxTx::Rx,
+                data: vec![0],
+                ..Default::default()
+            })
+        };
+        let mut formatter = TimestampFormatter::new(TimestampSettings {
+            base: TimeBase::SessionStart,
+            ..Default::default()
+        });
+        for msg in [data(0), SerialStateMessage::Started, data(1000)] {
+            formatter.observe(&msg);
+        }
+        assert_eq!(
+            formatter.format(at(62_500), TimestampTarget::File),
+            "+0:01:01.500"
+        );
+
+        formatter.set_settings(&TimestampSettings {
+            base: TimeBase::Bookmark,
+            style: TimestampStyle::Micros,
+            ..Default::default()
+        });
+        formatter.observe(&SerialStateMessage::Annotation(
+            crate::portthread::Annotation {
+                timestamp: at(2000),
+                text: "reset".to_string(),
+            },
+        ));
+        assert_eq!(
+            formatter.format(at(1999), TimestampTarget::File),
+            "-0:00:00.001000"
+        );
+    }
+}
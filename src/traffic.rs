@@ -0,0 +1,164 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::portthread::RxTx;
+
+/// Length of the window the current data rate is measured over.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// The traffic counters, shared between the port thread that updates them and
+/// the status bar that shows them.
+pub type SharedTrafficStats = Arc<Mutex<TrafficStats>>;
+
+/// Counters of the traffic since the port was opened or the counters were reset.
+#[derive(Debug, Default, Clone)]
+pub struct TrafficStats {
+    /// Name of the open port, `None` while the port thread is stopped.
+    pub port: Option<String>,
+    pub connected_since: Option<Instant>,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub rx_frames: u64,
+    pub tx_frames: u64,
+    pub errors: u64,
+    /// Bytes per second in both directions, measured over the last full window.
+    pub current_rate: u64,
+    pub peak_rate: u64,
+    window_start: Option<Instant>,
+    window_bytes: u64,
+}
+
+impl TrafficStats {
+    pub fn connected(&mut self, port: &str, now: Instant) {
+        self.port = Some(port.to_string());
+        self.connected_since = Some(now);
+        self.window_start = Some(now);
+        self.window_bytes = 0;
+    }
+
+    pub fn disconnected(&mut self) {
+        self.port = None;
+        self.connected_since = None;
+        self.window_start = None;
+        self.current_rate = 0;
+    }
+
+    pub fn add_bytes(&mut self, rx_tx: RxTx, bytes: usize) {
+        match rx_tx {
+            RxTx::Rx => self.rx_bytes += bytes as u64,
+            RxTx::Tx => self.tx_bytes += bytes as u64,
+        }
+        self.window_bytes += bytes as u64;
+    }
+
+    pub fn add_frame(&mut self, rx_tx: RxTx) {
+        match rx_tx {
+            RxTx::Rx => self.rx_frames += 1,
+            RxTx::Tx => self.tx_frames += 1,
+        }
+    }
+
+    pub fn add_error(&mut self) {
+        self.errors += 1;
+    }
+
+    /// Updates the data rate, once the current window is over.
+    pub fn tick(&mut self, now: Instant) {
+        let Some(start) = self.window_start else {
+            return;
+        };
+        let elapsed = now.duration_since(start);
+        if elapsed < RATE_WINDOW {
+            return;
+        }
+        self.current_rate = (self.window_bytes as u128 * 1000 / elapsed.as_millis()) as u64;
+        self.peak_rate = self.peak_rate.max(self.current_rate);
+        self.window_start = Some(now);
+        self.window_bytes = 0;
+    }
+
+    /// Resets all counters. The connection state is kept, but the duration
+    /// starts over.
+    pub fn reset(&mut self, now: Instant) {
+        let port = self.port.take();
+        *self = TrafficStats::default();
+        if let Some(port) = port {
+            self.connected(&port, now);
+        }
+    }
+
+    pub fn duration(&self, now: Instant) -> Option<Duration> {
+        self.connected_since.map(|since| now.duration_since(since))
+    }
+}
+
+/// Formats a number of bytes with a binary prefix, e.g. `1.5KiB`.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{}B", bytes)
+    } else {
+        format!("{:.1}{}", value, UNITS[unit])
+    }
+}
+
+/// Formats a duration as `H:MM:SS`.
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    format!(
+        "{}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_and_reset() {
+        let start = Instant::now();
+        let mut stats = TrafficStats::default();
+        stats.connected("COM1", start);
+        stats.add_bytes(RxTx::Rx, 1500);
+        stats.add_bytes(RxTx::Tx, 500);
+        stats.add_frame(RxTx::Rx);
+        stats.tick(start + Duration::from_millis(500));
+        assert_eq!(stats.current_rate, 0);
+        stats.tick(start + Duration::from_secs(2));
+        assert_eq!((stats.current_rate, stats.peak_rate), (1000, 1000));
+        stats.tick(start + Duration::from_secs(3));
+        assert_eq!((stats.current_rate, stats.peak_rate), (0, 1000));
+
+        stats.reset(start + Duration::from_secs(4));
+        assert_eq!(
+            (stats.rx_bytes, stats.rx_frames, stats.peak_rate),
+            (0, 0, 0)
+        );
+        assert_eq!(stats.port.as_deref(), Some("COM1"));
+        assert_eq!(
+            stats.duration(start + Duration::from_secs(5)),
+            Some(Duration::from_secs(1))
+        );
+        stats.disconnected();
+        assert_eq!(stats.duration(start), None);
+    }
+
+    #[test]
+    fn test_format_bytes_and_duration() {
+        assert_eq!(format_bytes(1023), "1023B");
+        assert_eq!(format_bytes(1536), "1.5KiB");
+        assert_eq!(format_bytes(3 * 1024 * 1024), "3.0MiB");
+        assert_eq!(format_duration(Duration::from_secs(3723)), "1:02:03");
+    }
+}